@@ -0,0 +1,44 @@
+use std::io;
+use std::os::unix::io::{ AsRawFd, RawFd };
+
+use evdev_rs::{ Device, GrabMode, InputEvent as InInputEvent, ReadFlag };
+
+use evdev::InputEvent as OutInputEvent;
+
+use crate::uinput::OutputDevice;
+
+// Where the read loop gets its input. Reads never block: an empty source
+// returns WouldBlock until its fd is readable again, a source that went
+// away ENODEV.
+pub trait EventSource {
+    fn fd(&self) -> RawFd;
+
+    fn next_event(&mut self) -> io::Result<InInputEvent>;
+
+    fn grab(&mut self, mode: GrabMode) -> io::Result<()>;
+}
+
+// Where the writers put whole frames.
+pub trait OutputSink: Send + 'static {
+    fn write(&mut self, frame: &[OutInputEvent]) -> io::Result<()>;
+}
+
+impl EventSource for Device {
+    fn fd(&self) -> RawFd {
+        return self.file().as_raw_fd();
+    }
+
+    fn next_event(&mut self) -> io::Result<InInputEvent> {
+        return Device::next_event(self, ReadFlag::NORMAL).map(|val| val.1);
+    }
+
+    fn grab(&mut self, mode: GrabMode) -> io::Result<()> {
+        return Device::grab(self, mode);
+    }
+}
+
+impl OutputSink for OutputDevice {
+    fn write(&mut self, frame: &[OutInputEvent]) -> io::Result<()> {
+        return OutputDevice::write(self, frame);
+    }
+}
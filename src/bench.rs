@@ -0,0 +1,73 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant, SystemTime };
+
+use evdev_rs::TimeVal;
+
+use evdev::Key;
+
+use crate::{ key_input, run_sources, syn_input, uinput, Capabilities, DeviceInfo, EventLoop, Handler, Latency, MockSink, MockSource, NhkError, Options, Stats };
+
+const KEYS: [Key; 8] = [
+    Key::KEY_A, Key::KEY_S, Key::KEY_D, Key::KEY_F,
+    Key::KEY_J, Key::KEY_K, Key::KEY_L, Key::KEY_SEMICOLON,
+];
+
+// Types the keys on the source in real time, each stamped with when it was
+// due, and closes it at the end. Falling behind the rate shows up as read
+// latency, the way a busy loop would in front of a real device.
+fn feed(source: MockSource, rate: u32, count: usize, done: Arc<AtomicBool>) {
+    let interval = Duration::from_secs(1) / rate.max(1);
+    let start = Instant::now();
+    let wall = SystemTime::now();
+
+    for i in 0..count {
+        if done.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let offset = interval * i as u32;
+        thread::sleep((start + offset).saturating_duration_since(Instant::now()));
+
+        let time = TimeVal::try_from(wall + offset).unwrap_or_else(|_| TimeVal::new(0, 0));
+        let key = KEYS[(i / 2) % KEYS.len()];
+        source.extend(vec![key_input(&time, key.code(), (i % 2 == 0) as i32), syn_input(&time)]);
+    }
+
+    source.close();
+}
+
+// Types `count` key events at `rate` per second through a handler in the
+// real read loop for `event_loop`, from a mock source on another thread.
+// The output goes to a mock sink, or with `uinput` to a real virtual device.
+pub fn bench<H: Handler>(handler: H, rate: u32, count: usize, uinput: bool, event_loop: EventLoop) -> Result<Stats, NhkError> {
+    let latency = Latency::new();
+    let options = Options { event_loop, latency: Some(latency.clone()), ..Options::default() };
+    let device = match uinput {
+        true => Some(uinput::create(Capabilities::Default, &[])?),
+        false => None,
+    };
+
+    let source = MockSource::new().map_err(NhkError::Read)?;
+    let info = DeviceInfo { path: "bench".to_string(), name: "NHK bench".to_string(), ..DeviceInfo::default() };
+    let done = Arc::new(AtomicBool::new(false));
+    let feeder = {
+        let (source, done) = (source.clone(), done.clone());
+        thread::spawn(move || feed(source, rate, count, done))
+    };
+
+    let result = match device {
+        Some(device) => run_sources(vec![(info, source)], vec![device], options, handler),
+        None => run_sources(vec![(info, source)], vec![MockSink::new()], options, handler),
+    };
+    done.store(true, Ordering::Relaxed);
+    feeder.join().expect("panic!");
+
+    // The source going away is how a full run ends.
+    return match result {
+        Ok(()) | Err(NhkError::DeviceRemoved(_)) => Ok(latency.snapshot()),
+        Err(e) => Err(e),
+    };
+}
@@ -0,0 +1,394 @@
+use std::time::Duration;
+
+use evdev_rs::InputEvent as InInputEvent;
+
+use evdev::Key;
+
+use crate::{ key_code, key_input, syn_input, Context, Control, DeviceInfo, Handler, Reply, TimerId };
+use crate::tap_hold::age;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Combo {
+    pub keys: Vec<Key>,
+    pub key: Key,
+}
+
+impl Combo {
+    fn contains(&self, code: u16) -> bool {
+        return self.keys.iter().any(|key| key.code() == code);
+    }
+
+    fn covers(&self, codes: &[u16]) -> bool {
+        return codes.iter().all(|&code| self.contains(code));
+    }
+}
+
+struct Pending {
+    pressed: Vec<u16>,
+    buffer: Vec<InInputEvent>,
+    timer: Option<TimerId>,
+}
+
+struct Active {
+    keys: Vec<u16>,
+    key: Key,
+    released: bool,
+}
+
+// Turns keys pressed together within the term into a different key. Presses
+// that could still become a combo are held back with everything after
+// them, and replayed in order with their own timestamps once they can't.
+// A combo is released with the first of its keys.
+pub struct Combos<H> {
+    inner: H,
+    combos: Vec<Combo>,
+    term: Duration,
+    pending: Option<Pending>,
+    active: Vec<Active>,
+}
+
+impl<H: Handler> Combos<H> {
+    pub fn new(inner: H) -> Combos<H> {
+        return Combos {
+            inner,
+            combos: Vec::new(),
+            term: Duration::from_millis(50),
+            pending: None,
+            active: Vec::new(),
+        };
+    }
+
+    pub fn term(mut self, term: Duration) -> Combos<H> {
+        self.term = term;
+        return self;
+    }
+
+    pub fn combo(mut self, keys: &[Key], key: Key) -> Combos<H> {
+        let mut unique: Vec<Key> = Vec::new();
+        for &key in keys {
+            if !unique.contains(&key) {
+                unique.push(key);
+            }
+        }
+
+        if unique.len() >= 2 {
+            self.combos.push(Combo { keys: unique, key });
+        }
+        return self;
+    }
+
+    fn exact(&self, codes: &[u16]) -> Option<&Combo> {
+        return self.combos.iter().find(|combo| combo.keys.len() == codes.len() && combo.covers(codes));
+    }
+
+    fn candidates(&self, codes: &[u16]) -> usize {
+        return self.combos.iter().filter(|combo| combo.covers(codes)).count();
+    }
+
+    fn dispatch(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        let code = key_code(&ev);
+
+        if let (Some(pending), Some(code)) = (&self.pending, code) {
+            let mut pressed = pending.pressed.clone();
+            pressed.push(code);
+
+            if ev.value == 1 && self.candidates(&pressed) > 0 {
+                // Done early when no longer combo can match anymore.
+                let done = self.exact(&pressed).is_some() && self.candidates(&pressed) == 1;
+                if let Some(pending) = &mut self.pending {
+                    pending.pressed = pressed;
+                    pending.buffer.push(ev);
+                }
+
+                return match done {
+                    true => self.resolve(ctx),
+                    false => Control::Continue,
+                };
+            }
+
+            if ev.value == 1 || (ev.value == 0 && pending.pressed.contains(&code)) {
+                return match self.resolve(ctx) {
+                    Control::Continue => self.dispatch(ev, ctx),
+                    control => control,
+                };
+            }
+        }
+
+        if let Some(pending) = &mut self.pending {
+            pending.buffer.push(ev);
+            return Control::Continue;
+        }
+
+        let code = match code {
+            Some(code) => code,
+            None => return self.inner.on_event(ev, ctx),
+        };
+
+        if let Some(index) = self.active.iter().position(|active| active.keys.contains(&code)) {
+            if ev.value != 1 {
+                return self.release(index, code, ev, ctx);
+            }
+        }
+
+        if ev.value == 1 && self.combos.iter().any(|combo| combo.contains(code)) {
+            // Replayed presses may already be older than the term.
+            let remaining = self.term.saturating_sub(age(&ev.time, ctx.wall_clock()));
+            if remaining.as_millis() > 0 {
+                let timer = Some(ctx.call_after(remaining));
+                self.pending = Some(Pending { pressed: vec![code], buffer: vec![ev], timer });
+                return Control::Continue;
+            }
+        }
+
+        return self.inner.on_event(ev, ctx);
+    }
+
+    fn release(&mut self, index: usize, code: u16, ev: InInputEvent, ctx: &mut Context) -> Control {
+        let active = &mut self.active[index];
+        let key = active.key;
+
+        if ev.value != 0 {
+            return match active.released {
+                false => self.inner.on_event(key_input(&ev.time, key.code(), ev.value), ctx),
+                true => Control::Continue,
+            };
+        }
+
+        let released = active.released;
+        active.released = true;
+        active.keys.retain(|&held| held != code);
+        if active.keys.is_empty() {
+            self.active.remove(index);
+        }
+
+        return match released {
+            false => self.inner.on_event(key_input(&ev.time, key.code(), 0), ctx),
+            true => Control::Continue,
+        };
+    }
+
+    // Fires the longest combo made up of the first keys pressed, and
+    // replays the rest. Without one, the first press goes through as it is.
+    fn resolve(&mut self, ctx: &mut Context) -> Control {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Control::Continue,
+        };
+        if let Some(timer) = pending.timer {
+            ctx.cancel(timer);
+        }
+
+        let matched = (2..=pending.pressed.len()).rev()
+            .find_map(|len| self.exact(&pending.pressed[..len]).map(|combo| (len, combo.key)));
+
+        let mut buffer = pending.buffer.into_iter();
+        let control = match matched {
+            Some((len, key)) => {
+                let members = &pending.pressed[..len];
+                let mut rest = Vec::new();
+                let mut swallowed = 0;
+                let mut time = None;
+
+                for ev in buffer {
+                    match key_code(&ev) {
+                        Some(code) if swallowed < len && ev.value == 1 && members.contains(&code) => {
+                            swallowed += 1;
+                            time = Some(ev.time);
+                        },
+                        _ => rest.push(ev),
+                    }
+                }
+                buffer = rest.into_iter();
+
+                self.active.push(Active { keys: members.to_vec(), key, released: false });
+                match time {
+                    Some(time) => match self.inner.on_event(key_input(&time, key.code(), 1), ctx) {
+                        Control::Continue => self.inner.on_event(syn_input(&time), ctx),
+                        control => control,
+                    },
+                    None => Control::Continue,
+                }
+            },
+            None => match buffer.next() {
+                Some(first) => self.inner.on_event(first, ctx),
+                None => Control::Continue,
+            },
+        };
+
+        if control != Control::Continue {
+            return control;
+        }
+
+        for ev in buffer {
+            let control = self.dispatch(ev, ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+}
+
+impl<H: Handler> Handler for Combos<H> {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return self.dispatch(ev, ctx);
+    }
+
+    fn on_start(&mut self, ctx: &mut Context) {
+        self.inner.on_start(ctx);
+    }
+
+    fn on_stop(&mut self, ctx: &mut Context) {
+        self.inner.on_stop(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) -> Control {
+        return self.inner.on_tick(ctx);
+    }
+
+    fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+        return match self.pending.as_ref().is_some_and(|pending| pending.timer == Some(timer)) {
+            true => {
+                if let Some(pending) = &mut self.pending {
+                    pending.timer = None;
+                }
+                self.resolve(ctx)
+            },
+            false => self.inner.on_timer(timer, ctx),
+        };
+    }
+
+    fn on_device_added(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_added(info, ctx);
+    }
+
+    fn on_device_removed(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_removed(info, ctx);
+    }
+
+    fn on_command(&mut self, command: &[&str], ctx: &mut Context) -> Option<Reply> {
+        return self.inner.on_command(command, ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ Mock, Passthrough };
+
+    #[test]
+    fn combo() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_D, Key::KEY_F], Key::KEY_TAB);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_D);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.press(Key::KEY_F);
+        assert_eq!(mock.keys(), vec![(Key::KEY_TAB, 1)]);
+
+        // Released with the first key, the second does nothing.
+        mock.release(Key::KEY_F);
+        mock.release(Key::KEY_D);
+        assert_eq!(mock.keys(), vec![(Key::KEY_TAB, 0)]);
+    }
+
+    #[test]
+    fn longest_match() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_J, Key::KEY_K], Key::KEY_ESC)
+            .combo(&[Key::KEY_J, Key::KEY_K, Key::KEY_L], Key::KEY_ENTER);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_J);
+        mock.press(Key::KEY_K);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.press(Key::KEY_L);
+        assert_eq!(mock.keys(), vec![(Key::KEY_ENTER, 1)]);
+    }
+
+    #[test]
+    fn shorter_match_on_timeout() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_J, Key::KEY_K], Key::KEY_ESC)
+            .combo(&[Key::KEY_J, Key::KEY_K, Key::KEY_L], Key::KEY_ENTER);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_J);
+        mock.press(Key::KEY_K);
+        mock.advance(Duration::from_millis(50));
+        assert_eq!(mock.keys(), vec![(Key::KEY_ESC, 1)]);
+
+        mock.release(Key::KEY_K);
+        mock.release(Key::KEY_J);
+        assert_eq!(mock.keys(), vec![(Key::KEY_ESC, 0)]);
+    }
+
+    #[test]
+    fn shorter_match_then_other_key() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_J, Key::KEY_K], Key::KEY_ESC)
+            .combo(&[Key::KEY_J, Key::KEY_K, Key::KEY_L], Key::KEY_ENTER);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_J);
+        mock.press(Key::KEY_K);
+        mock.press(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_ESC, 1), (Key::KEY_A, 1)]);
+    }
+
+    #[test]
+    fn timeout_flush() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_J, Key::KEY_K], Key::KEY_ESC);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_J);
+        mock.advance(Duration::from_millis(49));
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.advance(Duration::from_millis(1));
+        assert_eq!(mock.keys(), vec![(Key::KEY_J, 1)]);
+
+        // Too late to join a combo now.
+        mock.press(Key::KEY_K);
+        mock.advance(Duration::from_millis(50));
+        mock.release(Key::KEY_J);
+        assert_eq!(mock.keys(), vec![(Key::KEY_K, 1), (Key::KEY_J, 0)]);
+    }
+
+    #[test]
+    fn order_of_other_keys() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_J, Key::KEY_K], Key::KEY_ESC);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_J);
+        mock.tap(Key::KEY_A);
+        mock.release(Key::KEY_J);
+        assert_eq!(mock.keys(), vec![(Key::KEY_J, 1), (Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_J, 0)]);
+    }
+
+    #[test]
+    fn release_before_combo() {
+        let combos = Combos::new(Passthrough)
+            .term(Duration::from_millis(50))
+            .combo(&[Key::KEY_D, Key::KEY_F], Key::KEY_TAB);
+        let mut mock = Mock::new(combos);
+
+        mock.press(Key::KEY_D);
+        mock.release(Key::KEY_D);
+        mock.press(Key::KEY_F);
+        mock.advance(Duration::from_millis(50));
+        assert_eq!(mock.keys(), vec![(Key::KEY_D, 1), (Key::KEY_D, 0), (Key::KEY_F, 1)]);
+    }
+}
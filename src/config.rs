@@ -0,0 +1,1083 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::time::Duration;
+
+use evdev_rs::enums::EV_KEY;
+
+use evdev::Key;
+
+use crate::{ default_socket_path, run_reloading, Action, Capabilities, Combo, Combos, ConfigError, DeviceSelector, EscapeChord, EventLoop, Keymap, Layer, Layers, Leader, LeaderFallback, Macro, NhkError, Options, Output, Passthrough, Player, Trace, Unicode };
+use crate::tap_hold::{ TapHoldConfig, TapHoldMode };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Symbol(char),
+    Newline,
+    Eof,
+}
+
+fn is_word_char(c: char) -> bool {
+    return c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.' || c == '+';
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, Pos)>, (Pos, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut pos = Pos { line: 1, column: 1 };
+
+    while let Some(&c) = chars.peek() {
+        let start = pos;
+        match c {
+            '\n' => {
+                chars.next();
+                pos = Pos { line: pos.line + 1, column: 1 };
+                tokens.push((Token::Newline, start));
+                continue;
+            },
+            ' ' | '\t' | '\r' => {
+                chars.next();
+            },
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                    pos.column += 1;
+                }
+                continue;
+            },
+            '"' => {
+                chars.next();
+                pos.column += 1;
+
+                let mut value = String::new();
+                loop {
+                    let c = match chars.next() {
+                        Some('\n') | None => return Err((start, "unterminated string".to_string())),
+                        Some(c) => c,
+                    };
+                    pos.column += 1;
+
+                    match c {
+                        '"' => break,
+                        '\\' => {
+                            let escaped = match chars.next() {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('\\') => '\\',
+                                Some('"') => '"',
+                                _ => return Err((Pos { line: pos.line, column: pos.column - 1 }, "invalid escape sequence".to_string())),
+                            };
+                            pos.column += 1;
+                            value.push(escaped);
+                        },
+                        c => value.push(c),
+                    }
+                }
+
+                tokens.push((Token::Str(value), start));
+                continue;
+            },
+            '=' | '(' | ')' | ',' | '{' | '}' => {
+                chars.next();
+                tokens.push((Token::Symbol(c), start));
+            },
+            c if is_word_char(c) => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !is_word_char(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                    pos.column += 1;
+                }
+
+                tokens.push((Token::Word(word), start));
+                continue;
+            },
+            c => return Err((start, format!("unexpected character '{}'", c))),
+        }
+
+        pos.column += 1;
+    }
+
+    tokens.push((Token::Eof, pos));
+    return Ok(tokens);
+}
+
+#[derive(Clone, Debug)]
+struct Expr {
+    name: String,
+    pos: Pos,
+    args: Vec<Arg>,
+}
+
+#[derive(Clone, Debug)]
+enum Arg {
+    Positional(Expr),
+    Named(String, Pos, Expr),
+}
+
+// A macro step: a string is text to type, anything else reads like an
+// action, e.g. "delay(100ms)" or "ctrl+shift+t".
+#[derive(Clone, Debug)]
+enum StepExpr {
+    Text(String, Pos),
+    Call(String, Pos, Vec<StepExpr>),
+}
+
+struct Mapping {
+    layer: usize,
+    key: String,
+    key_pos: Pos,
+    action: Expr,
+}
+
+struct Sequence {
+    keys: Vec<(String, Pos)>,
+    pos: Pos,
+    action: Expr,
+}
+
+struct Parser {
+    file: String,
+    tokens: Vec<(Token, Pos)>,
+    index: usize,
+    selectors: Vec<DeviceSelector>,
+    settings: Vec<(String, Pos, String, Pos)>,
+    layers: Vec<(String, Pos)>,
+    mappings: Vec<Mapping>,
+    combos: Vec<(String, Pos, String, Pos)>,
+    macros: Vec<(String, Pos, Vec<StepExpr>)>,
+    sequences: Vec<Sequence>,
+}
+
+fn describe(token: &Token) -> String {
+    return match token {
+        Token::Word(word) => format!("\"{}\"", word),
+        Token::Str(_) => "string".to_string(),
+        Token::Symbol(c) => format!("'{}'", c),
+        Token::Newline => "end of line".to_string(),
+        Token::Eof => "end of file".to_string(),
+    };
+}
+
+impl Parser {
+    fn error(&self, pos: Pos, message: String) -> ConfigError {
+        return ConfigError::Parse { file: self.file.clone(), line: pos.line, column: pos.column, message };
+    }
+
+    fn peek(&self) -> &(Token, Pos) {
+        return &self.tokens[self.index.min(self.tokens.len() - 1)];
+    }
+
+    fn peek_second(&self) -> &(Token, Pos) {
+        return &self.tokens[(self.index + 1).min(self.tokens.len() - 1)];
+    }
+
+    fn next(&mut self) -> (Token, Pos) {
+        let token = self.peek().clone();
+        if self.index < self.tokens.len() {
+            self.index += 1;
+        }
+        return token;
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ConfigError> {
+        let (token, pos) = self.peek();
+        return Err(self.error(*pos, format!("expected {}, found {}", expected, describe(token))));
+    }
+
+    fn symbol(&mut self, c: char) -> Result<Pos, ConfigError> {
+        return match self.peek().clone() {
+            (Token::Symbol(s), pos) if s == c => {
+                self.next();
+                Ok(pos)
+            },
+            _ => self.unexpected(&format!("'{}'", c)),
+        };
+    }
+
+    fn word(&mut self, expected: &str) -> Result<(String, Pos), ConfigError> {
+        return match self.peek().clone() {
+            (Token::Word(word), pos) => {
+                self.next();
+                Ok((word, pos))
+            },
+            _ => self.unexpected(expected),
+        };
+    }
+
+    fn string(&mut self, expected: &str) -> Result<(String, Pos), ConfigError> {
+        return match self.peek().clone() {
+            (Token::Str(value), pos) => {
+                self.next();
+                Ok((value, pos))
+            },
+            _ => self.unexpected(expected),
+        };
+    }
+
+    fn end_of_statement(&mut self) -> Result<(), ConfigError> {
+        return match self.peek().0 {
+            Token::Newline => {
+                self.next();
+                Ok(())
+            },
+            Token::Eof => Ok(()),
+            _ => self.unexpected("end of line"),
+        };
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek().0 == Token::Newline {
+            self.next();
+        }
+    }
+
+    fn parse(&mut self) -> Result<(), ConfigError> {
+        loop {
+            self.skip_newlines();
+
+            let (token, pos) = self.peek().clone();
+            let word = match token {
+                Token::Eof => return Ok(()),
+                Token::Word(word) => word,
+                token => return Err(self.error(pos, format!("expected a statement, found {}", describe(&token)))),
+            };
+
+            // A keyword is only a statement where one can start, so a key
+            // with the same name can still be mapped, e.g. "macro = f13".
+            match word.as_str() {
+                _ if self.peek_second().0 == Token::Symbol('=') => self.parse_mapping(0)?,
+                "device" => {
+                    self.next();
+                    let (value, pos) = self.string("a device selector string")?;
+                    let selector = DeviceSelector::from_str(&value).map_err(|e| self.error(pos, e))?;
+                    self.selectors.push(selector);
+                },
+                "set" => {
+                    self.next();
+                    let (name, name_pos) = self.word("a setting name")?;
+                    let (value, value_pos) = match self.peek().0 {
+                        Token::Str(_) => self.string("a value")?,
+                        _ => self.word("a value")?,
+                    };
+                    self.settings.push((name, name_pos, value, value_pos));
+                },
+                "layer" => {
+                    self.next();
+                    self.parse_layer()?;
+                },
+                "combo" => {
+                    self.next();
+                    let (keys, keys_pos) = self.word("keys joined with '+'")?;
+                    self.symbol('=')?;
+                    let (key, key_pos) = self.word("a key name")?;
+                    self.combos.push((keys, keys_pos, key, key_pos));
+                },
+                "leader" => {
+                    self.next();
+                    let mut keys = vec![self.word("a key name")?];
+                    while let Token::Word(_) = self.peek().0 {
+                        keys.push(self.word("a key name")?);
+                    }
+                    self.symbol('=')?;
+                    let action = self.parse_expr()?;
+                    self.sequences.push(Sequence { keys, pos, action });
+                },
+                "macro" => {
+                    self.next();
+                    let (name, name_pos) = self.word("a macro name")?;
+                    self.symbol('=')?;
+                    let steps = self.parse_steps(None)?;
+                    self.macros.push((name, name_pos, steps));
+                },
+                _ => self.parse_mapping(0)?,
+            }
+
+            self.end_of_statement()?;
+        }
+    }
+
+    fn layer_id(&mut self, name: &str, pos: Pos) -> usize {
+        if let Some(id) = self.layers.iter().position(|(layer, _)| layer == name) {
+            return id;
+        }
+
+        self.layers.push((name.to_string(), pos));
+        return self.layers.len() - 1;
+    }
+
+    fn parse_layer(&mut self) -> Result<(), ConfigError> {
+        let (name, pos) = self.word("a layer name")?;
+        let layer = self.layer_id(&name, pos);
+
+        self.symbol('{')?;
+        loop {
+            self.skip_newlines();
+            match self.peek().0 {
+                Token::Symbol('}') => {
+                    self.next();
+                    return Ok(());
+                },
+                Token::Eof => return self.unexpected("'}'"),
+                _ => {
+                    self.parse_mapping(layer)?;
+                    if self.peek().0 != Token::Symbol('}') {
+                        self.end_of_statement()?;
+                    }
+                },
+            }
+        }
+    }
+
+    fn parse_mapping(&mut self, layer: usize) -> Result<(), ConfigError> {
+        let (key, key_pos) = self.word("a key name")?;
+        self.symbol('=')?;
+        let action = self.parse_expr()?;
+
+        self.mappings.push(Mapping { layer, key, key_pos, action });
+        return Ok(());
+    }
+
+    // Steps are separated by commas, a line may end after one. Inside
+    // parentheses the list ends at the closing one.
+    fn parse_steps(&mut self, close: Option<char>) -> Result<Vec<StepExpr>, ConfigError> {
+        let mut steps = Vec::new();
+
+        loop {
+            if close.is_some_and(|c| self.peek().0 == Token::Symbol(c)) {
+                self.next();
+                return Ok(steps);
+            }
+
+            let step = match self.peek().clone() {
+                (Token::Str(text), pos) => {
+                    self.next();
+                    StepExpr::Text(text, pos)
+                },
+                _ => {
+                    let (name, pos) = self.word("a macro step")?;
+                    let mut args = Vec::new();
+                    if self.peek().0 == Token::Symbol('(') {
+                        self.next();
+                        args = self.parse_steps(Some(')'))?;
+                    }
+                    StepExpr::Call(name, pos, args)
+                },
+            };
+            steps.push(step);
+
+            match self.peek().0 {
+                Token::Symbol(',') => {
+                    self.next();
+                    self.skip_newlines();
+                },
+                Token::Symbol(c) if close == Some(c) => {},
+                _ if close.is_none() => return Ok(steps),
+                _ => return self.unexpected("',' or ')'"),
+            }
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr, ConfigError> {
+        let (name, pos) = self.word("an action")?;
+        let mut args = Vec::new();
+
+        if self.peek().0 == Token::Symbol('(') {
+            self.next();
+            loop {
+                if self.peek().0 == Token::Symbol(')') {
+                    self.next();
+                    break;
+                }
+
+                let expr = self.parse_expr()?;
+                if self.peek().0 == Token::Symbol('=') && expr.args.is_empty() {
+                    self.next();
+                    args.push(Arg::Named(expr.name, expr.pos, self.parse_expr()?));
+                } else {
+                    args.push(Arg::Positional(expr));
+                }
+
+                match self.peek().0 {
+                    Token::Symbol(',') => {
+                        self.next();
+                    },
+                    Token::Symbol(')') => {},
+                    _ => return self.unexpected("',' or ')'"),
+                }
+            }
+        }
+
+        return Ok(Expr { name, pos, args });
+    }
+}
+
+pub fn parse_key(name: &str) -> Option<Key> {
+    let name = name.to_ascii_lowercase();
+    let name = match name.as_str() {
+        "caps" => "capslock",
+        "ctrl" | "lctrl" => "leftctrl",
+        "rctrl" => "rightctrl",
+        "shift" | "lshift" => "leftshift",
+        "rshift" => "rightshift",
+        "alt" | "lalt" => "leftalt",
+        "ralt" | "altgr" => "rightalt",
+        "meta" | "lmeta" | "super" => "leftmeta",
+        "rmeta" => "rightmeta",
+        "ret" | "return" => "enter",
+        "bspc" => "backspace",
+        "del" => "delete",
+        "ins" => "insert",
+        "pgup" => "pageup",
+        "pgdn" => "pagedown",
+        name => name,
+    };
+
+    let name = name.to_ascii_uppercase();
+    let code = match name.starts_with("KEY_") || name.starts_with("BTN_") {
+        true => EV_KEY::from_str(&name),
+        false => EV_KEY::from_str(&format!("KEY_{}", name)),
+    };
+
+    return code.ok().map(|code| Key::new(code as u16));
+}
+
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, scale) = match value {
+        value if value.ends_with("ms") => (&value[..value.len() - 2], 1),
+        value if value.ends_with('s') => (&value[..value.len() - 1], 1000),
+        value => (value, 1),
+    };
+
+    return number.parse::<u64>().ok().and_then(|number| number.checked_mul(scale)).map(Duration::from_millis);
+}
+
+fn parse_mode(value: &str) -> Option<TapHoldMode> {
+    return match value {
+        "tap-preferred" => Some(TapHoldMode::TapPreferred),
+        "hold-on-other-key-press" => Some(TapHoldMode::HoldOnOtherKeyPress),
+        "permissive-hold" => Some(TapHoldMode::PermissiveHold),
+        _ => None,
+    };
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub selectors: Vec<DeviceSelector>,
+    pub options: Options,
+    layers: Vec<(String, Layer)>,
+    combos: Vec<Combo>,
+    combo_term: Option<Duration>,
+    macro_interrupt: Option<Key>,
+    keymap: Keymap,
+    leader_keys: Vec<Key>,
+    sequences: Vec<(Vec<Key>, Macro)>,
+    leader_timeout: Option<Duration>,
+    leader_fallback: LeaderFallback,
+    sticky_timeout: Option<Duration>,
+    path: Option<PathBuf>,
+}
+
+struct Compiler<'a> {
+    parser: &'a Parser,
+    tap_hold: TapHoldConfig,
+    keymap: &'a Keymap,
+    macros: Vec<(String, Macro)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn layer(&self, expr: &Expr) -> Result<usize, ConfigError> {
+        if !expr.args.is_empty() {
+            return Err(self.parser.error(expr.pos, format!("expected a layer name, found \"{}(...)\"", expr.name)));
+        }
+
+        return self.parser.layers.iter()
+            .position(|(name, _)| *name == expr.name)
+            .ok_or_else(|| self.parser.error(expr.pos, format!("unknown layer \"{}\"", expr.name)));
+    }
+
+    fn positional<'e>(&self, expr: &'e Expr, count: usize) -> Result<Vec<&'e Expr>, ConfigError> {
+        let args: Vec<&Expr> = expr.args.iter()
+            .filter_map(|arg| match arg {
+                Arg::Positional(expr) => Some(expr),
+                Arg::Named(..) => None,
+            })
+            .collect();
+
+        if args.len() != count {
+            return Err(self.parser.error(expr.pos, format!("\"{}\" takes {} argument(s), found {}", expr.name, count, args.len())));
+        }
+
+        return Ok(args);
+    }
+
+    fn action(&self, expr: &Expr) -> Result<Action, ConfigError> {
+        let layer_action = |action: fn(usize) -> Action| -> Result<Action, ConfigError> {
+            let args = self.positional(expr, 1)?;
+            return Ok(action(self.layer(args[0])?));
+        };
+
+        match expr.name.as_str() {
+            "momentary" => return layer_action(Action::Momentary),
+            "toggle" => return layer_action(Action::Toggle),
+            "one-shot" => return layer_action(Action::OneShot),
+            "default" => return layer_action(Action::SetDefault),
+            "tap-hold" => return self.tap_hold(expr),
+            "sticky" => {
+                let args = self.positional(expr, 1)?;
+                if !args[0].args.is_empty() {
+                    return Err(self.parser.error(args[0].pos, format!("expected a key, found \"{}(...)\"", args[0].name)));
+                }
+                return parse_key(&args[0].name)
+                    .map(Action::Sticky)
+                    .ok_or_else(|| self.parser.error(args[0].pos, format!("unknown key \"{}\"", args[0].name)));
+            },
+            // Without arguments it's the key of the same name.
+            "macro" if !expr.args.is_empty() => {
+                let args = self.positional(expr, 1)?;
+                return self.macros.iter()
+                    .find(|(name, _)| *name == args[0].name)
+                    .map(|(_, m)| Action::Macro(m.clone()))
+                    .ok_or_else(|| self.parser.error(args[0].pos, format!("unknown macro \"{}\"", args[0].name)));
+            },
+            _ => {},
+        }
+
+        if !expr.args.is_empty() {
+            return Err(self.parser.error(expr.pos, format!("unknown action \"{}\"", expr.name)));
+        }
+
+        return match expr.name.as_str() {
+            "_" => Ok(Action::Transparent),
+            "none" => Ok(Action::NoOp),
+            // Only a mapping outside any layer can be the leader key.
+            "leader" => Err(self.parser.error(expr.pos, "\"leader\" can only be mapped to a key outside a layer".to_string())),
+            "caps-word" => Ok(Action::CapsWord),
+            name => parse_key(name)
+                .map(Action::Key)
+                .ok_or_else(|| self.parser.error(expr.pos, format!("unknown key \"{}\"", name))),
+        };
+    }
+
+    fn tap_hold(&self, expr: &Expr) -> Result<Action, ConfigError> {
+        let mut config = self.tap_hold;
+        let mut positional = Vec::new();
+
+        for arg in &expr.args {
+            match arg {
+                Arg::Positional(arg) if arg.name == "retro-tap" && arg.args.is_empty() => config.retro_tap = true,
+                Arg::Positional(arg) => positional.push(arg),
+                Arg::Named(name, pos, value) => {
+                    let invalid = || self.parser.error(value.pos, format!("invalid value for \"{}\": \"{}\"", name, value.name));
+                    match name.as_str() {
+                        "term" => config.tapping_term = parse_duration(&value.name).ok_or_else(invalid)?,
+                        "mode" => config.mode = parse_mode(&value.name).ok_or_else(invalid)?,
+                        _ => return Err(self.parser.error(*pos, format!("unknown tap-hold option \"{}\"", name))),
+                    }
+                },
+            }
+        }
+
+        if positional.len() != 2 {
+            return Err(self.parser.error(expr.pos, format!("\"tap-hold\" takes a tap and a hold action, found {} argument(s)", positional.len())));
+        }
+
+        return Ok(Action::TapHold(Box::new(self.action(positional[0])?), Box::new(self.action(positional[1])?), config));
+    }
+
+    fn key(&self, name: &str, pos: Pos) -> Result<Key, ConfigError> {
+        return parse_key(name).ok_or_else(|| self.parser.error(pos, format!("unknown key \"{}\"", name)));
+    }
+
+    fn steps(&self, steps: &[StepExpr], mut m: Macro) -> Result<Macro, ConfigError> {
+        for step in steps {
+            let (name, pos, args) = match step {
+                StepExpr::Text(text, pos) => {
+                    if let Some(c) = text.chars().find(|&c| !self.keymap.can_type(c)) {
+                        return Err(self.parser.error(*pos, format!("can't type {:?}", c)));
+                    }
+                    m = m.text(text);
+                    continue;
+                },
+                StepExpr::Call(name, pos, args) => (name.as_str(), *pos, args),
+            };
+
+            let single = |what: &str| -> Result<(&str, Pos), ConfigError> {
+                return match args.as_slice() {
+                    [StepExpr::Call(arg, pos, args)] if args.is_empty() => Ok((arg.as_str(), *pos)),
+                    _ => Err(self.parser.error(pos, format!("\"{}\" takes {}", name, what))),
+                };
+            };
+
+            m = match name {
+                "press" | "release" | "tap" => {
+                    let (key, key_pos) = single("a key")?;
+                    let key = self.key(key, key_pos)?;
+                    match name {
+                        "press" => m.press(key),
+                        "release" => m.release(key),
+                        _ => m.tap(key),
+                    }
+                },
+                "delay" => {
+                    let (value, value_pos) = single("a duration")?;
+                    m.delay(parse_duration(value).ok_or_else(|| self.parser.error(value_pos, format!("invalid duration \"{}\"", value)))?)
+                },
+                "repeat" => {
+                    let count = match args.first() {
+                        Some(StepExpr::Call(count, count_pos, _)) => count.parse().map_err(|_e| self.parser.error(*count_pos, format!("invalid repeat count \"{}\"", count)))?,
+                        _ => return Err(self.parser.error(pos, "\"repeat\" takes a count and steps".to_string())),
+                    };
+                    m.repeat(count, self.steps(&args[1..], Macro::new())?)
+                },
+                _ if !args.is_empty() => return Err(self.parser.error(pos, format!("unknown macro step \"{}\"", name))),
+                chord => {
+                    let keys: Result<Vec<Key>, ConfigError> = chord.split('+').map(|key| self.key(key, pos)).collect();
+                    match keys?.as_slice() {
+                        [key] => m.tap(*key),
+                        keys => m.chord(keys),
+                    }
+                },
+            };
+        }
+
+        return Ok(m);
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let file = path.display().to_string();
+
+        let source = fs::read_to_string(path).map_err(|e| ConfigError::Read(file.clone(), e))?;
+        let mut config = Config::parse(&file, &source)?;
+        config.path = Some(path.to_path_buf());
+
+        return Ok(config);
+    }
+
+    pub fn parse(file: &str, source: &str) -> Result<Config, ConfigError> {
+        let tokens = tokenize(source).map_err(|(pos, message)| {
+            ConfigError::Parse { file: file.to_string(), line: pos.line, column: pos.column, message }
+        })?;
+
+        let mut parser = Parser {
+            file: file.to_string(),
+            tokens,
+            index: 0,
+            selectors: Vec::new(),
+            settings: Vec::new(),
+            layers: vec![("base".to_string(), Pos { line: 1, column: 1 })],
+            mappings: Vec::new(),
+            combos: Vec::new(),
+            macros: Vec::new(),
+            sequences: Vec::new(),
+        };
+        parser.parse()?;
+
+        if parser.selectors.is_empty() {
+            let pos = parser.peek().1;
+            return Err(parser.error(pos, "no device selected, add a line like: device \"name:My Keyboard\"".to_string()));
+        }
+
+        let mut options = Options::default();
+        let mut tap_hold = TapHoldConfig::default();
+        let mut combo_term = None;
+        let mut macro_interrupt = Some(Key::KEY_ESC);
+        let mut layout = None;
+        let mut variant = "";
+        let mut unicode = Unicode::Off;
+        let mut leader_timeout = None;
+        let mut sticky_timeout = None;
+        let mut leader_fallback = LeaderFallback::Replay;
+        let mut escape_hold = false;
+        for (name, name_pos, value, value_pos) in &parser.settings {
+            let invalid = || parser.error(*value_pos, format!("invalid value for \"{}\": \"{}\"", name, value));
+            match name.as_str() {
+                "capabilities" => options.capabilities = match value.as_str() {
+                    "default" => Capabilities::Default,
+                    "mirror" => Capabilities::Mirror,
+                    _ => return Err(invalid()),
+                },
+                "output" => options.output = match value.as_str() {
+                    "shared" => Output::Shared,
+                    "per-source" => Output::PerSource,
+                    _ => return Err(invalid()),
+                },
+                "hotplug" => options.hotplug = match value.as_str() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(invalid()),
+                },
+                "escape" => options.escape = match value.as_str() {
+                    "off" if escape_hold => return Err(parser.error(*name_pos, "\"escape-hold\" is set but the escape chord is off".to_string())),
+                    "off" => None,
+                    value => {
+                        let keys: Option<Vec<Key>> = value.split('+').map(parse_key).collect();
+                        let hold = options.escape.as_ref().map_or_else(|| EscapeChord::default().hold, |escape| escape.hold);
+                        Some(EscapeChord { keys: keys.ok_or_else(invalid)?, hold })
+                    },
+                },
+                "escape-hold" => {
+                    let hold = parse_duration(value).ok_or_else(invalid)?;
+                    match &mut options.escape {
+                        Some(escape) => escape.hold = hold,
+                        None => return Err(parser.error(*name_pos, "\"escape-hold\" is set but the escape chord is off".to_string())),
+                    }
+                    escape_hold = true;
+                },
+                "raw" => options.raw = match value.as_str() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(invalid()),
+                },
+                "event-loop" => options.event_loop = match value.as_str() {
+                    "threaded" => EventLoop::Threaded,
+                    "single" => EventLoop::Single,
+                    _ => return Err(invalid()),
+                },
+                "trace" => options.trace = match value.as_str() {
+                    "off" => Trace::Off,
+                    "on" => Trace::On,
+                    "redacted" => Trace::Redacted,
+                    _ => return Err(invalid()),
+                },
+                "control" => options.control = match value.as_str() {
+                    "off" => None,
+                    "on" => Some(default_socket_path()),
+                    path => Some(PathBuf::from(path)),
+                },
+                "combo-term" => combo_term = Some(parse_duration(value).ok_or_else(invalid)?),
+                "macro-interrupt" => macro_interrupt = match value.as_str() {
+                    "off" => None,
+                    value => Some(parse_key(value).ok_or_else(invalid)?),
+                },
+                "layout" => layout = Some((value, *value_pos)),
+                "layout-variant" => variant = value,
+                "unicode-input" => unicode = match value.as_str() {
+                    "off" => Unicode::Off,
+                    "ctrl-shift-u" => Unicode::CtrlShiftU,
+                    _ => return Err(invalid()),
+                },
+                "leader-timeout" => leader_timeout = Some(parse_duration(value).ok_or_else(invalid)?),
+                "leader-fallback" => leader_fallback = match value.as_str() {
+                    "replay" => LeaderFallback::Replay,
+                    "discard" => LeaderFallback::Discard,
+                    _ => return Err(invalid()),
+                },
+                "sticky-timeout" => sticky_timeout = Some(parse_duration(value).ok_or_else(invalid)?),
+                "tapping-term" => tap_hold.tapping_term = parse_duration(value).ok_or_else(invalid)?,
+                "tap-hold-mode" => tap_hold.mode = parse_mode(value).ok_or_else(invalid)?,
+                "retro-tap" => tap_hold.retro_tap = match value.as_str() {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(parser.error(*name_pos, format!("unknown setting \"{}\"", name))),
+            }
+        }
+
+        let keymap = match layout {
+            Some((layout, pos)) => Keymap::xkb(layout, variant)
+                .map_err(|e| parser.error(pos, format!("invalid value for \"layout\": {}", e)))?,
+            None => Keymap::us(),
+        };
+        let keymap = keymap.unicode(unicode);
+
+        let mut compiler = Compiler { parser: &parser, tap_hold, keymap: &keymap, macros: Vec::new() };
+        for (name, pos, steps) in &parser.macros {
+            if compiler.macros.iter().any(|(other, _)| other == name) {
+                return Err(parser.error(*pos, format!("macro \"{}\" is already defined", name)));
+            }
+
+            let m = compiler.steps(steps, Macro::new())?;
+            compiler.macros.push((name.clone(), m));
+        }
+
+        let mut layers: Vec<(String, Layer)> = parser.layers.iter().map(|(name, _)| (name.clone(), Layer::named(name))).collect();
+        let mut seen: HashMap<(usize, u16), Pos> = HashMap::new();
+        let mut leader_keys = Vec::new();
+
+        for mapping in &parser.mappings {
+            let key = parse_key(&mapping.key)
+                .ok_or_else(|| parser.error(mapping.key_pos, format!("unknown key \"{}\"", mapping.key)))?;
+
+            if let Some(previous) = seen.insert((mapping.layer, key.code()), mapping.key_pos) {
+                return Err(parser.error(mapping.key_pos, format!("\"{}\" is already mapped at line {}", mapping.key, previous.line)));
+            }
+
+            // The leader wrapper sits in front of the layers and takes the key.
+            if mapping.layer == 0 && mapping.action.name == "leader" && mapping.action.args.is_empty() {
+                leader_keys.push(key);
+                continue;
+            }
+
+            let action = compiler.action(&mapping.action)?;
+            let layer = mem::take(&mut layers[mapping.layer].1);
+            layers[mapping.layer].1 = layer.map(key, action);
+        }
+
+        let mut sequences: Vec<(Vec<Key>, Macro)> = Vec::new();
+        for sequence in &parser.sequences {
+            let keys: Result<Vec<Key>, ConfigError> = sequence.keys.iter()
+                .map(|(name, pos)| parse_key(name).ok_or_else(|| parser.error(*pos, format!("unknown key \"{}\"", name))))
+                .collect();
+            let keys = keys?;
+            if sequences.iter().any(|(other, _)| *other == keys) {
+                return Err(parser.error(sequence.pos, "leader sequence is already defined".to_string()));
+            }
+
+            let action = match compiler.action(&sequence.action)? {
+                Action::Key(key) => Macro::new().tap(key),
+                Action::Macro(m) => m,
+                _ => return Err(parser.error(sequence.action.pos, "a leader sequence can only type a key or play a macro".to_string())),
+            };
+            sequences.push((keys, action));
+        }
+
+        let mut combos: Vec<Combo> = Vec::new();
+        for (keys, keys_pos, key, key_pos) in &parser.combos {
+            let mut codes = Vec::new();
+            for name in keys.split('+') {
+                let key = parse_key(name).ok_or_else(|| parser.error(*keys_pos, format!("unknown key \"{}\"", name)))?;
+                if !codes.contains(&key) {
+                    codes.push(key);
+                }
+            }
+            if codes.len() < 2 {
+                return Err(parser.error(*keys_pos, format!("a combo needs at least two keys, found \"{}\"", keys)));
+            }
+            if combos.iter().any(|combo| combo.keys.len() == codes.len() && codes.iter().all(|key| combo.keys.contains(key))) {
+                return Err(parser.error(*keys_pos, format!("combo \"{}\" is already defined", keys)));
+            }
+
+            let key = parse_key(key).ok_or_else(|| parser.error(*key_pos, format!("unknown key \"{}\"", key)))?;
+            combos.push(Combo { keys: codes, key });
+        }
+
+        return Ok(Config { selectors: parser.selectors, options, layers, combos, combo_term, macro_interrupt, keymap, leader_keys, sequences, leader_timeout, leader_fallback, sticky_timeout, path: None });
+    }
+
+    pub fn layer_names(&self) -> Vec<&str> {
+        return self.layers.iter().map(|(name, _)| name.as_str()).collect();
+    }
+
+    pub fn handler(&self) -> Combos<Leader<Layers<Passthrough>>> {
+        let mut layers = Layers::new(Passthrough)
+            .macro_interrupt(self.macro_interrupt)
+            .keymap(self.keymap.clone());
+        if let Some(timeout) = self.sticky_timeout {
+            layers = layers.sticky_timeout(timeout);
+        }
+        for (_, layer) in &self.layers {
+            layers = layers.layer(layer.clone());
+        }
+
+        let player = Player::new().interrupt(self.macro_interrupt).keymap(self.keymap.clone());
+        let mut leader = Leader::new(layers).fallback(self.leader_fallback).player(player);
+        if let Some(timeout) = self.leader_timeout {
+            leader = leader.timeout(timeout);
+        }
+        for &key in &self.leader_keys {
+            leader = leader.key(key);
+        }
+        for (keys, action) in &self.sequences {
+            leader = leader.sequence(keys, action.clone());
+        }
+
+        let mut handler = Combos::new(leader);
+        if let Some(term) = self.combo_term {
+            handler = handler.term(term);
+        }
+        for combo in &self.combos {
+            handler = handler.combo(&combo.keys, combo.key);
+        }
+
+        return handler;
+    }
+
+    // A reload from the control socket reads the file again. If it no
+    // longer parses, the old configuration keeps running.
+    pub fn run(&self) -> Result<(), NhkError> {
+        let reload = || {
+            let path = self.path.as_ref()?;
+            match Config::load(path) {
+                Ok(mut config) => {
+                    config.options.latency = self.options.latency.clone();
+                    Some((config.selectors.clone(), config.options.clone(), config.handler()))
+                },
+                Err(e) => {
+                    log::error!("reload failed, keeping the running configuration error=\"{}\"", e);
+                    None
+                },
+            }
+        };
+
+        return run_reloading(self.selectors.clone(), self.options.clone(), self.handler(), reload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Mock;
+
+    #[test]
+    fn parse() {
+        let source = "
+            device \"name:Keyboard\"
+            device \"id:046d:c52b\"
+            set output per-source
+            set event-loop single
+            set tapping-term 150ms
+            caps = esc
+            layer nav {
+                h = left
+                j = _
+            }
+            space = tap-hold(space, momentary(nav), mode=permissive-hold)
+        ";
+        let config = Config::parse("t.conf", source).unwrap();
+        assert_eq!(config.selectors.len(), 2);
+        assert_eq!(config.options.output, Output::PerSource);
+        assert_eq!(config.options.event_loop, EventLoop::Single);
+
+        let names: Vec<&str> = config.layers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["base", "nav"]);
+
+        let (base, nav) = (&config.layers[0].1, &config.layers[1].1);
+        assert_eq!(base.get(Key::KEY_CAPSLOCK), Some(&Action::Key(Key::KEY_ESC)));
+        assert_eq!(nav.get(Key::KEY_H), Some(&Action::Key(Key::KEY_LEFT)));
+        assert_eq!(nav.get(Key::KEY_J), Some(&Action::Transparent));
+
+        let config = TapHoldConfig { tapping_term: Duration::from_millis(150), mode: TapHoldMode::PermissiveHold, retro_tap: false };
+        let tap_hold = Action::TapHold(Box::new(Action::Key(Key::KEY_SPACE)), Box::new(Action::Momentary(1)), config);
+        assert_eq!(base.get(Key::KEY_SPACE), Some(&tap_hold));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |source: &str| Config::parse("t.conf", source).err().map(|e| e.to_string());
+
+        assert_eq!(error("caps = esc\n"), Some("t.conf:2:1: no device selected, add a line like: device \"name:My Keyboard\"".to_string()));
+        assert_eq!(error("device \"name:K\"\ncaps = nope\n"), Some("t.conf:2:8: unknown key \"nope\"".to_string()));
+        assert_eq!(error("device \"name:K\"\nset tapping-term soon\n"), Some("t.conf:2:18: invalid value for \"tapping-term\": \"soon\"".to_string()));
+        assert_eq!(error("device \"name:K\"\nset tapping-term 18446744073709551615s\n"), Some("t.conf:2:18: invalid value for \"tapping-term\": \"18446744073709551615s\"".to_string()));
+        assert_eq!(error("device \"name:K\"\nset colour red\n"), Some("t.conf:2:5: unknown setting \"colour\"".to_string()));
+        assert_eq!(error("device \"name:K\"\ncombo j+k = esc\ncombo k+j = tab\n"), Some("t.conf:3:7: combo \"k+j\" is already defined".to_string()));
+        assert_eq!(error("device \"name:K\"\nset escape off\nset escape-hold 2s\n"), Some("t.conf:3:5: \"escape-hold\" is set but the escape chord is off".to_string()));
+        assert_eq!(error("device \"name:K\"\nset escape-hold 2s\nset escape off\n"), Some("t.conf:3:5: \"escape-hold\" is set but the escape chord is off".to_string()));
+        assert_eq!(error("device \"name:K\"\nlayer nav {\n  h = left\n"), Some("t.conf:4:1: expected '}', found end of file".to_string()));
+        assert_eq!(error("device \"name:K\nx = y\n"), Some("t.conf:1:8: unterminated string".to_string()));
+    }
+
+    #[test]
+    fn keywords_as_keys() {
+        let source = "
+            device \"name:Keyboard\"
+            macro = f13
+            macro greet = \"hi\"
+            f1 = macro(greet)
+            f2 = macro
+        ";
+        let config = Config::parse("t.conf", source).unwrap();
+        let base = &config.layers[0].1;
+        assert_eq!(base.get(Key::KEY_MACRO), Some(&Action::Key(Key::KEY_F13)));
+        assert!(matches!(base.get(Key::KEY_F1), Some(Action::Macro(_))));
+        assert_eq!(base.get(Key::KEY_F2), Some(&Action::Key(Key::KEY_MACRO)));
+
+        let config = Config::parse("t.conf", "device \"name:K\"\nset escape ctrl+esc\nset escape-hold 2s\n").unwrap();
+        assert_eq!(config.options.escape.map(|escape| escape.hold), Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("150"), Some(Duration::from_millis(150)));
+        assert_eq!(parse_duration("150ms"), Some(Duration::from_millis(150)));
+        assert_eq!(parse_duration("2s"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("-1ms"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), None);
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_key("caps"), Some(Key::KEY_CAPSLOCK));
+        assert_eq!(parse_key("Ctrl"), Some(Key::KEY_LEFTCTRL));
+        assert_eq!(parse_key("KEY_A"), Some(Key::KEY_A));
+        assert_eq!(parse_key("nope"), None);
+    }
+
+    #[test]
+    fn handler() {
+        let source = "
+            device \"name:Keyboard\"
+            caps = esc
+            combo j+k = enter
+            layer nav {
+                h = left
+            }
+            space = tap-hold(space, momentary(nav), term=150ms)
+        ";
+        let mut mock = Mock::new(Config::parse("t.conf", source).unwrap().handler());
+        mock.tap(Key::KEY_CAPSLOCK);
+        assert_eq!(mock.keys(), vec![(Key::KEY_ESC, 1), (Key::KEY_ESC, 0)]);
+
+        mock.press(Key::KEY_SPACE);
+        mock.advance(Duration::from_millis(150));
+        mock.tap(Key::KEY_H);
+        mock.release(Key::KEY_SPACE);
+        mock.tap(Key::KEY_SPACE);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0)]);
+
+        mock.press(Key::KEY_J);
+        mock.press(Key::KEY_K);
+        assert_eq!(mock.keys(), vec![(Key::KEY_ENTER, 1)]);
+    }
+
+    #[test]
+    fn leader() {
+        let source = "
+            device \"name:Keyboard\"
+            set leader-timeout 500ms
+            ralt = leader
+            g = h
+            macro ok = \"ok\"
+            leader g s = macro(ok)
+            leader g e = end
+        ";
+        let config = Config::parse("t.conf", source).unwrap();
+        assert_eq!(config.leader_keys, vec![Key::KEY_RIGHTALT]);
+        assert_eq!(config.layers[0].1.get(Key::KEY_RIGHTALT), None);
+
+        // The sequences are the keys as typed, in front of the layers.
+        let mut mock = Mock::new(config.handler());
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_G);
+        mock.tap(Key::KEY_S);
+        assert_eq!(mock.keys(), vec![(Key::KEY_O, 1), (Key::KEY_O, 0), (Key::KEY_K, 1), (Key::KEY_K, 0)]);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_G);
+        mock.advance(Duration::from_millis(500));
+        assert_eq!(mock.keys(), vec![(Key::KEY_H, 1), (Key::KEY_H, 0)]);
+
+        let error = |source: &str| Config::parse("t.conf", source).err().map(|e| e.to_string());
+        assert_eq!(error("device \"name:K\"\nlayer nav {\n  ralt = leader\n}\n"), Some("t.conf:3:10: \"leader\" can only be mapped to a key outside a layer".to_string()));
+        assert_eq!(error("device \"name:K\"\nlayer nav {\n}\nleader n = toggle(nav)\n"), Some("t.conf:4:12: a leader sequence can only type a key or play a macro".to_string()));
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::os::unix::net::{ UnixListener, UnixStream };
+use std::path::{ Path, PathBuf };
+
+use nix::unistd::getuid;
+
+// Lines to send back on success, a message on failure.
+pub type Reply = Result<Vec<String>, String>;
+
+// Requests are one line of whitespace separated words. A reply starts with
+// "ok <n>" followed by n lines, or is a single "error: <message>" line.
+pub fn default_socket_path() -> PathBuf {
+    return match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("nhk.sock"),
+        None => PathBuf::from(format!("/tmp/nhk-{}.sock", getuid())),
+    };
+}
+
+pub fn request(path: &Path, command: &str) -> io::Result<Reply> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    let status = status.trim_end();
+
+    if let Some(message) = status.strip_prefix("error: ") {
+        return Ok(Err(message.to_string()));
+    }
+
+    let count: usize = status.strip_prefix("ok ")
+        .and_then(|count| count.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply \"{}\"", status)))?;
+
+    let mut lines = Vec::with_capacity(count);
+    for line in reader.lines().take(count) {
+        lines.push(line?);
+    }
+
+    return Ok(Ok(lines));
+}
+
+// Requests are short, a client sending more without a newline is dropped.
+const MAX_LINE: usize = 4096;
+const MAX_LINES: usize = 64;
+
+struct Client {
+    stream: UnixStream,
+    buf: Vec<u8>,
+}
+
+// The listening side, kept across reloads so clients can stay connected.
+// Clients are served from the read loop, between device events.
+pub(crate) struct ControlSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: HashMap<usize, Client>,
+    next: usize,
+}
+
+impl ControlSocket {
+    pub(crate) fn bind(path: &Path) -> io::Result<ControlSocket> {
+        // A socket nobody answers on is left over from a crash.
+        if path.exists() {
+            match UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::AddrInUse, "another nhk is listening")),
+                Err(_) => fs::remove_file(path)?,
+            }
+        }
+
+        let listener = UnixListener::bind(path)?;
+        // Whoever can connect can type as the user.
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        listener.set_nonblocking(true)?;
+
+        log::info!("control socket listening path={:?}", path);
+        return Ok(ControlSocket { path: path.to_path_buf(), listener, clients: HashMap::new(), next: 0 });
+    }
+
+    pub(crate) fn clients(&self) -> Vec<(usize, RawFd)> {
+        return self.clients.iter().map(|(&id, client)| (id, client.stream.as_raw_fd())).collect();
+    }
+
+    pub(crate) fn accept(&mut self) -> Vec<(usize, RawFd)> {
+        let mut accepted = Vec::new();
+
+        while let Ok((stream, _)) = self.listener.accept() {
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+
+            let id = self.next;
+            self.next += 1;
+            accepted.push((id, stream.as_raw_fd()));
+            self.clients.insert(id, Client { stream, buf: Vec::new() });
+            log::debug!("control client connected client={}", id);
+        }
+
+        return accepted;
+    }
+
+    // The complete lines a client has sent so far, and whether it is gone.
+    pub(crate) fn read(&mut self, id: usize) -> (Vec<String>, bool) {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return (Vec::new(), true),
+        };
+
+        let mut closed = false;
+        let mut lines = Vec::new();
+        let mut chunk = [0u8; 1024];
+        // The rest of a burst stays in the socket for the next wakeup.
+        while lines.len() < MAX_LINES {
+            match client.stream.read(&mut chunk) {
+                Ok(0) => { closed = true; break; },
+                Ok(n) => client.buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_e) => { closed = true; break; },
+            }
+
+            while let Some(end) = client.buf.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = client.buf.drain(..=end).collect();
+                lines.push(String::from_utf8_lossy(&line).trim().to_string());
+            }
+            if client.buf.len() > MAX_LINE {
+                log::warn!("control client sent an overlong line client={}", id);
+                return (lines, true);
+            }
+        }
+
+        return (lines, closed);
+    }
+
+    pub(crate) fn reply(&mut self, id: usize, reply: &Reply) -> io::Result<()> {
+        let client = match self.clients.get_mut(&id) {
+            Some(client) => client,
+            None => return Ok(()),
+        };
+
+        let mut out = String::new();
+        match reply {
+            Ok(lines) => {
+                out.push_str(&format!("ok {}\n", lines.len()));
+                for line in lines {
+                    out.push_str(&line.replace('\n', " "));
+                    out.push('\n');
+                }
+            },
+            Err(message) => out.push_str(&format!("error: {}\n", message.replace('\n', " "))),
+        }
+
+        // Replies are small, a client that can't take one is dropped.
+        return client.stream.write_all(out.as_bytes());
+    }
+
+    pub(crate) fn close(&mut self, id: usize) {
+        if self.clients.remove(&id).is_some() {
+            log::debug!("control client disconnected client={}", id);
+        }
+    }
+}
+
+impl AsRawFd for ControlSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.listener.as_raw_fd();
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::selector::DeviceInfo;
+
+#[derive(Debug)]
+pub enum NhkError {
+    DeviceOpen(String, io::Error),
+    NoMatchingDevice(String, Vec<DeviceInfo>),
+    PermissionDenied(String),
+    Grab(io::Error),
+    Read(io::Error),
+    DeviceRemoved(String),
+    UinputCreate(io::Error),
+    Emit(io::Error),
+    Record(io::Error),
+    Control(String, io::Error),
+}
+
+impl NhkError {
+    pub(crate) fn device_open(path: &str, err: io::Error) -> NhkError {
+        return match err.kind() {
+            io::ErrorKind::PermissionDenied => NhkError::PermissionDenied(path.to_string()),
+            _ => NhkError::DeviceOpen(path.to_string(), err),
+        };
+    }
+
+    pub(crate) fn uinput_create(err: io::Error) -> NhkError {
+        return match err.kind() {
+            io::ErrorKind::PermissionDenied => NhkError::PermissionDenied("/dev/uinput".to_string()),
+            _ => NhkError::UinputCreate(err),
+        };
+    }
+}
+
+pub(crate) fn nix_error(err: nix::Error) -> io::Error {
+    return match err.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(err),
+    };
+}
+
+impl fmt::Display for NhkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NhkError::DeviceOpen(path, err) => write!(f, "failed to open device {}: {}", path, err),
+            NhkError::NoMatchingDevice(selector, candidates) => {
+                write!(f, "no device matches {}", selector)?;
+                match candidates.is_empty() {
+                    true => write!(f, " (no readable input devices found)"),
+                    false => {
+                        write!(f, ", candidates are:")?;
+                        for candidate in candidates {
+                            write!(f, "\n  {}", candidate)?;
+                        }
+                        Ok(())
+                    },
+                }
+            },
+            NhkError::PermissionDenied(path) => write!(f, "permission denied: {}", path),
+            NhkError::Grab(err) => write!(f, "failed to grab device: {}", err),
+            NhkError::Read(err) => write!(f, "failed to read events: {}", err),
+            NhkError::DeviceRemoved(path) => write!(f, "device removed: {}", path),
+            NhkError::UinputCreate(err) => write!(f, "failed to create uinput device: {}", err),
+            NhkError::Emit(err) => write!(f, "failed to emit event: {}", err),
+            NhkError::Record(err) => write!(f, "failed to write recording: {}", err),
+            NhkError::Control(path, err) => write!(f, "control socket {}: {}", path, err),
+        }
+    }
+}
+
+impl Error for NhkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NhkError::DeviceOpen(_, err) => Some(err),
+            NhkError::NoMatchingDevice(_, _) => None,
+            NhkError::PermissionDenied(_) => None,
+            NhkError::Grab(err) => Some(err),
+            NhkError::Read(err) => Some(err),
+            NhkError::DeviceRemoved(_) => None,
+            NhkError::UinputCreate(err) => Some(err),
+            NhkError::Emit(err) => Some(err),
+            NhkError::Record(err) => Some(err),
+            NhkError::Control(_, err) => Some(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, io::Error),
+    Parse { file: String, line: usize, column: usize, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(file, err) => write!(f, "failed to read {}: {}", file, err),
+            ConfigError::Parse { file, line, column, message } => write!(f, "{}:{}:{}: {}", file, line, column, message),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read(_, err) => Some(err),
+            ConfigError::Parse { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordingError {
+    Read(String, io::Error),
+    Parse { file: String, line: usize, message: String },
+}
+
+impl fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordingError::Read(file, err) => write!(f, "failed to read {}: {}", file, err),
+            RecordingError::Parse { file, line, message } => write!(f, "{}:{}: {}", file, line, message),
+        }
+    }
+}
+
+impl Error for RecordingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RecordingError::Read(_, err) => Some(err),
+            RecordingError::Parse { .. } => None,
+        }
+    }
+}
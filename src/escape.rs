@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::process;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{ Duration, Instant };
+
+use evdev::Key;
+
+use nix::unistd::{ close, dup };
+
+use crate::SourceId;
+use crate::error::nix_error;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// KEY_MAX is 0x2ff.
+const KEY_BYTES: usize = 0x2ff / 8 + 1;
+
+nix::ioctl_read_buf!(eviocgkey, b'E', 0x18, u8);
+nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EscapeChord {
+    pub keys: Vec<Key>,
+    pub hold: Duration,
+}
+
+impl Default for EscapeChord {
+    fn default() -> EscapeChord {
+        return EscapeChord {
+            keys: vec![Key::KEY_LEFTCTRL, Key::KEY_RIGHTCTRL, Key::KEY_ESC],
+            hold: Duration::from_secs(2),
+        };
+    }
+}
+
+struct Shared {
+    fds: Mutex<HashMap<SourceId, RawFd>>,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    fn fds(&self) -> MutexGuard<'_, HashMap<SourceId, RawFd>> {
+        return match self.fds.lock() {
+            Ok(fds) => fds,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+}
+
+// Watches the kernel's key state of every grabbed device from its own
+// thread, so the chord works no matter what the read loop or the handler
+// are stuck on. The fds are dups of the grabbed ones and share their grab.
+pub(crate) struct Escape {
+    shared: Arc<Shared>,
+    watchdog: Option<JoinHandle<()>>,
+}
+
+fn key_state(fd: RawFd, state: &mut [u8; KEY_BYTES]) {
+    let mut buf = [0u8; KEY_BYTES];
+    if unsafe { eviocgkey(fd, &mut buf) }.is_err() {
+        return;
+    }
+
+    for (byte, pressed) in state.iter_mut().zip(buf.iter()) {
+        *byte |= pressed;
+    }
+}
+
+fn is_down(state: &[u8; KEY_BYTES], key: Key) -> bool {
+    let code = key.code() as usize;
+    return state[code / 8] & (1 << (code % 8)) != 0;
+}
+
+fn watch(shared: Arc<Shared>, chord: EscapeChord) {
+    let mut since: Option<Instant> = None;
+
+    while !shared.stopped.load(Ordering::Relaxed) {
+        thread::sleep(POLL_INTERVAL);
+
+        let fds = shared.fds();
+
+        let mut state = [0u8; KEY_BYTES];
+        for &fd in fds.values() {
+            key_state(fd, &mut state);
+        }
+
+        if !chord.keys.iter().all(|&key| is_down(&state, key)) {
+            since = None;
+            continue;
+        }
+
+        let since = *since.get_or_insert_with(Instant::now);
+        if since.elapsed() < chord.hold {
+            continue;
+        }
+
+        for &fd in fds.values() {
+            unsafe { eviocgrab(fd, 0) }.ok();
+        }
+
+        eprintln!("escape chord held, devices ungrabbed, exiting");
+        process::exit(1);
+    }
+}
+
+impl Escape {
+    pub(crate) fn new(chord: EscapeChord) -> Escape {
+        let shared = Arc::new(Shared { fds: Mutex::new(HashMap::new()), stopped: AtomicBool::new(false) });
+
+        let watchdog = match chord.keys.is_empty() {
+            true => None,
+            false => {
+                let shared = shared.clone();
+                Some(thread::spawn(move || watch(shared, chord)))
+            },
+        };
+
+        return Escape { shared, watchdog };
+    }
+
+    pub(crate) fn add(&self, source: SourceId, fd: RawFd) -> io::Result<()> {
+        let fd = dup(fd).map_err(nix_error)?;
+        if let Some(old) = self.shared.fds().insert(source, fd) {
+            close(old).ok();
+        }
+
+        return Ok(());
+    }
+
+    pub(crate) fn remove(&self, source: SourceId) {
+        if let Some(fd) = self.shared.fds().remove(&source) {
+            close(fd).ok();
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        for (_, fd) in self.shared.fds().drain() {
+            close(fd).ok();
+        }
+    }
+}
+
+impl Drop for Escape {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.join().ok();
+        }
+
+        self.clear();
+    }
+}
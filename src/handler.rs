@@ -0,0 +1,128 @@
+use std::sync::mpsc::Sender;
+use std::time::{ Duration, Instant, SystemTime };
+
+use evdev_rs::InputEvent as InInputEvent;
+
+use evdev::InputEvent as OutInputEvent;
+
+use crate::{ passthrough_ev, DeviceInfo, Reply, SourceId };
+use crate::timer::{ Action, TimerId, Timers };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    Stop,
+    Reload,
+}
+
+pub struct Context<'a> {
+    source: SourceId,
+    now: Instant,
+    wall: SystemTime,
+    txs: &'a [Sender<OutInputEvent>],
+    timers: &'a mut Timers,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(source: SourceId, now: Instant, wall: SystemTime, txs: &'a [Sender<OutInputEvent>], timers: &'a mut Timers) -> Context<'a> {
+        return Context { source, now, wall, txs, timers };
+    }
+
+    pub fn source(&self) -> SourceId {
+        return self.source;
+    }
+
+    pub fn tx(&self) -> Option<&'a Sender<OutInputEvent>> {
+        return self.tx_for(self.source);
+    }
+
+    // None while there is no output yet, with per-source output and
+    // nothing plugged in.
+    pub fn tx_for(&self, source: SourceId) -> Option<&'a Sender<OutInputEvent>> {
+        return self.txs.get(source).or_else(|| self.txs.last());
+    }
+
+    pub fn now(&self) -> Instant {
+        return self.now;
+    }
+
+    pub fn wall_clock(&self) -> SystemTime {
+        return self.wall;
+    }
+
+    pub fn emit_after(&mut self, delay: Duration, events: Vec<OutInputEvent>) -> TimerId {
+        return self.timers.schedule(self.now + delay, self.source, Action::Emit(events));
+    }
+
+    pub fn call_after(&mut self, delay: Duration) -> TimerId {
+        return self.timers.schedule(self.now + delay, self.source, Action::Call);
+    }
+
+    pub fn cancel(&mut self, timer: TimerId) -> bool {
+        return self.timers.cancel(timer);
+    }
+}
+
+pub trait Handler {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control;
+
+    fn on_start(&mut self, _ctx: &mut Context) {}
+
+    fn on_stop(&mut self, _ctx: &mut Context) {}
+
+    fn on_tick(&mut self, _ctx: &mut Context) -> Control {
+        return Control::Continue;
+    }
+
+    fn on_timer(&mut self, _timer: TimerId, _ctx: &mut Context) -> Control {
+        return Control::Continue;
+    }
+
+    fn on_device_added(&mut self, _info: &DeviceInfo, _ctx: &mut Context) {}
+
+    fn on_device_removed(&mut self, _info: &DeviceInfo, _ctx: &mut Context) {}
+
+    // A control socket request the read loop doesn't handle itself. None
+    // means the command is unknown here; "status" lines are appended to the
+    // loop's own.
+    fn on_command(&mut self, _command: &[&str], _ctx: &mut Context) -> Option<Reply> {
+        return None;
+    }
+}
+
+fn control(stop: bool) -> Control {
+    return match stop {
+        true => Control::Stop,
+        false => Control::Continue,
+    };
+}
+
+impl<F> Handler for F where F: FnMut(InInputEvent, &Sender<OutInputEvent>) -> bool {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return match ctx.tx() {
+            Some(tx) => control(self(ev, tx)),
+            None => Control::Continue,
+        };
+    }
+}
+
+impl Handler for Box<dyn Fn(SourceId, InInputEvent, &Sender<OutInputEvent>) -> bool> {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return match ctx.tx() {
+            Some(tx) => control(self(ctx.source(), ev, tx)),
+            None => Control::Continue,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Passthrough;
+
+impl Handler for Passthrough {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        if let Some(tx) = ctx.tx() {
+            passthrough_ev(ev, tx);
+        }
+        return Control::Continue;
+    }
+}
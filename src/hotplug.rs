@@ -0,0 +1,81 @@
+use std::io;
+use std::os::unix::io::{ AsRawFd, RawFd };
+
+use evdev_rs::{ Device, DeviceWrapper };
+
+use nix::sys::inotify::{ AddWatchFlags, InitFlags, Inotify };
+use nix::unistd::close;
+
+use crate::error::nix_error;
+
+const INPUT_DIR: &str = "/dev/input";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Identity {
+    name: Option<String>,
+    uniq: Option<String>,
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+}
+
+impl Identity {
+    pub(crate) fn of(dev: &Device) -> Identity {
+        return Identity {
+            name: dev.name().map(String::from),
+            uniq: dev.uniq().map(String::from),
+            bustype: dev.bustype(),
+            vendor: dev.vendor_id(),
+            product: dev.product_id(),
+        };
+    }
+}
+
+pub(crate) struct Watcher {
+    inotify: Inotify,
+}
+
+impl Watcher {
+    pub(crate) fn new() -> io::Result<Watcher> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC).map_err(nix_error)?;
+        let watcher = Watcher { inotify };
+
+        // Nodes show up root-owned and only become readable once udev has
+        // fixed their permissions, hence IN_ATTRIB.
+        inotify.add_watch(INPUT_DIR, AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB).map_err(nix_error)?;
+
+        return Ok(watcher);
+    }
+
+    pub(crate) fn changed_nodes(&self) -> Vec<String> {
+        let mut paths = Vec::new();
+
+        while let Ok(events) = self.inotify.read_events() {
+            for event in events {
+                let name = match event.name.as_ref().and_then(|name| name.to_str()) {
+                    Some(name) if name.starts_with("event") => name,
+                    _ => continue,
+                };
+
+                let path = format!("{}/{}", INPUT_DIR, name);
+                if !paths.contains(&path) {
+                    paths.push(path);
+                }
+            }
+        }
+
+        return paths;
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.inotify.as_raw_fd();
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        close(self.inotify.as_raw_fd()).ok();
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::ptr;
+
+use evdev::Key;
+
+use xkbcommon_dl::{ xkb_context_flags, xkb_keymap_compile_flags, xkb_log_level, xkb_rule_names, xkbcommon_option, XkbCommon, XKB_MOD_INVALID };
+
+// What to do with a character the layout has no key for. With CtrlShiftU
+// it is entered as a hex code point, which GTK and IBus understand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unicode {
+    Off,
+    CtrlShiftU,
+}
+
+impl Default for Unicode {
+    fn default() -> Unicode {
+        return Unicode::Off;
+    }
+}
+
+// Which keys, modifiers first, type each character on a keyboard layout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    chars: HashMap<char, Vec<Key>>,
+    unicode: Unicode,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        return Keymap::us();
+    }
+}
+
+const US: &[(char, Key, bool)] = &[
+    ('0', Key::KEY_0, false), ('1', Key::KEY_1, false), ('2', Key::KEY_2, false), ('3', Key::KEY_3, false), ('4', Key::KEY_4, false),
+    ('5', Key::KEY_5, false), ('6', Key::KEY_6, false), ('7', Key::KEY_7, false), ('8', Key::KEY_8, false), ('9', Key::KEY_9, false),
+    ('!', Key::KEY_1, true), ('@', Key::KEY_2, true), ('#', Key::KEY_3, true), ('$', Key::KEY_4, true), ('%', Key::KEY_5, true),
+    ('^', Key::KEY_6, true), ('&', Key::KEY_7, true), ('*', Key::KEY_8, true), ('(', Key::KEY_9, true), (')', Key::KEY_0, true),
+    (' ', Key::KEY_SPACE, false), ('\n', Key::KEY_ENTER, false), ('\t', Key::KEY_TAB, false),
+    ('-', Key::KEY_MINUS, false), ('_', Key::KEY_MINUS, true), ('=', Key::KEY_EQUAL, false), ('+', Key::KEY_EQUAL, true),
+    ('[', Key::KEY_LEFTBRACE, false), ('{', Key::KEY_LEFTBRACE, true), (']', Key::KEY_RIGHTBRACE, false), ('}', Key::KEY_RIGHTBRACE, true),
+    ('\\', Key::KEY_BACKSLASH, false), ('|', Key::KEY_BACKSLASH, true), (';', Key::KEY_SEMICOLON, false), (':', Key::KEY_SEMICOLON, true),
+    ('\'', Key::KEY_APOSTROPHE, false), ('"', Key::KEY_APOSTROPHE, true), ('`', Key::KEY_GRAVE, false), ('~', Key::KEY_GRAVE, true),
+    (',', Key::KEY_COMMA, false), ('<', Key::KEY_COMMA, true), ('.', Key::KEY_DOT, false), ('>', Key::KEY_DOT, true),
+    ('/', Key::KEY_SLASH, false), ('?', Key::KEY_SLASH, true),
+];
+
+// Modifier combinations tried in order, fewest first. AltGr is assumed to
+// be the right Alt key, as it is on the layouts that have one.
+const LEVELS: &[&[(&str, Key)]] = &[
+    &[],
+    &[("Shift", Key::KEY_LEFTSHIFT)],
+    &[("Mod5", Key::KEY_RIGHTALT)],
+    &[("Shift", Key::KEY_LEFTSHIFT), ("Mod5", Key::KEY_RIGHTALT)],
+];
+
+impl Keymap {
+    // Built in, so typing works without libxkbcommon.
+    pub fn us() -> Keymap {
+        let mut chars = HashMap::new();
+
+        for c in ('a'..='z').chain('A'..='Z') {
+            if let Some(key) = crate::parse_key(&c.to_string()) {
+                chars.insert(c, match c.is_ascii_uppercase() {
+                    true => vec![Key::KEY_LEFTSHIFT, key],
+                    false => vec![key],
+                });
+            }
+        }
+        for &(c, key, shift) in US {
+            chars.insert(c, match shift {
+                true => vec![Key::KEY_LEFTSHIFT, key],
+                false => vec![key],
+            });
+        }
+
+        return Keymap { chars, unicode: Unicode::Off };
+    }
+
+    // Compiles an XKB layout, e.g. "de" or "br" with variant "abnt2", with
+    // libxkbcommon loaded at runtime.
+    pub fn xkb(layout: &str, variant: &str) -> Result<Keymap, String> {
+        return Keymap::compile(xkbcommon_option(), layout, variant);
+    }
+
+    fn compile(xkb: Option<&XkbCommon>, layout: &str, variant: &str) -> Result<Keymap, String> {
+        let xkb = xkb.ok_or_else(|| "libxkbcommon could not be loaded".to_string())?;
+
+        let strings: Result<Vec<CString>, _> = ["evdev", "pc105", layout, variant].iter().map(|s| CString::new(*s)).collect();
+        let strings = strings.map_err(|_e| "invalid layout name".to_string())?;
+        let names = xkb_rule_names {
+            rules: strings[0].as_ptr(),
+            model: strings[1].as_ptr(),
+            layout: strings[2].as_ptr(),
+            variant: strings[3].as_ptr(),
+            options: ptr::null(),
+        };
+
+        let mut chars = HashMap::new();
+        unsafe {
+            let context = (xkb.xkb_context_new)(xkb_context_flags::XKB_CONTEXT_NO_ENVIRONMENT_NAMES);
+            if context.is_null() {
+                return Err("can't create an xkb context".to_string());
+            }
+            // Failures are reported through the error returned instead.
+            (xkb.xkb_context_set_log_level)(context, xkb_log_level::XKB_LOG_LEVEL_CRITICAL);
+            let keymap = (xkb.xkb_keymap_new_from_names)(context, &names, xkb_keymap_compile_flags::XKB_KEYMAP_COMPILE_NO_FLAGS);
+            if keymap.is_null() {
+                (xkb.xkb_context_unref)(context);
+                return Err(format!("can't compile layout \"{}\" variant \"{}\"", layout, variant));
+            }
+            let state = (xkb.xkb_state_new)(keymap);
+
+            let (min, max) = ((xkb.xkb_keymap_min_keycode)(keymap), (xkb.xkb_keymap_max_keycode)(keymap));
+            for level in LEVELS {
+                let mut mask = 0;
+                for (name, _) in level.iter() {
+                    let name = CString::new(*name).unwrap_or_default();
+                    let index = (xkb.xkb_keymap_mod_get_index)(keymap, name.as_ptr());
+                    if index != XKB_MOD_INVALID {
+                        mask |= 1 << index;
+                    }
+                }
+                (xkb.xkb_state_update_mask)(state, mask, 0, 0, 0, 0, 0);
+
+                // XKB keycodes are evdev codes offset by 8. Codes past 255
+                // aren't on a regular keyboard, and may not be on ours.
+                for keycode in min.max(8)..=max.min(255 + 8) {
+                    let c = match char::from_u32((xkb.xkb_state_key_get_utf32)(state, keycode)) {
+                        Some('\r') => '\n',
+                        Some(c) if c == '\n' || c == '\t' || !c.is_control() => c,
+                        _ => continue,
+                    };
+
+                    let mut keys: Vec<Key> = level.iter().map(|&(_, key)| key).collect();
+                    keys.push(Key::new((keycode - 8) as u16));
+                    chars.entry(c).or_insert(keys);
+                }
+            }
+
+            (xkb.xkb_state_unref)(state);
+            (xkb.xkb_keymap_unref)(keymap);
+            (xkb.xkb_context_unref)(context);
+        }
+
+        if chars.is_empty() {
+            return Err(format!("layout \"{}\" has no keys", layout));
+        }
+        return Ok(Keymap { chars, unicode: Unicode::Off });
+    }
+
+    pub fn unicode(mut self, unicode: Unicode) -> Keymap {
+        self.unicode = unicode;
+        return self;
+    }
+
+    pub fn keys(&self, c: char) -> Option<&[Key]> {
+        return self.chars.get(&c).map(|keys| keys.as_slice());
+    }
+
+    pub fn can_type(&self, c: char) -> bool {
+        return self.chars.contains_key(&c) || self.unicode != Unicode::Off;
+    }
+
+    // The key events that type a character: modifiers down, the key tapped,
+    // modifiers up. None if neither the layout nor the fallback can.
+    pub fn events(&self, c: char) -> Option<Vec<(Key, i32)>> {
+        if let Some(keys) = self.keys(c) {
+            return Some(chord(keys));
+        }
+
+        return match self.unicode {
+            Unicode::Off => None,
+            Unicode::CtrlShiftU => {
+                let mut events = chord(&[Key::KEY_LEFTCTRL, Key::KEY_LEFTSHIFT, Key::KEY_U]);
+                for digit in format!("{:x}", c as u32).chars() {
+                    events.extend(chord(self.keys(digit)?));
+                }
+                events.extend(chord(self.keys(' ')?));
+                Some(events)
+            },
+        };
+    }
+}
+
+fn chord(keys: &[Key]) -> Vec<(Key, i32)> {
+    let mut events: Vec<(Key, i32)> = keys.iter().map(|&key| (key, 1)).collect();
+    events.extend(keys.iter().rev().map(|&key| (key, 0)));
+    return events;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn us() {
+        let keymap = Keymap::us();
+        assert_eq!(keymap.keys('a'), Some(&[Key::KEY_A][..]));
+        assert_eq!(keymap.keys('A'), Some(&[Key::KEY_LEFTSHIFT, Key::KEY_A][..]));
+        assert_eq!(keymap.keys('/'), Some(&[Key::KEY_SLASH][..]));
+        assert_eq!(keymap.keys('?'), Some(&[Key::KEY_LEFTSHIFT, Key::KEY_SLASH][..]));
+        assert_eq!(keymap.keys('\n'), Some(&[Key::KEY_ENTER][..]));
+        assert_eq!(keymap.keys('€'), None);
+
+        assert_eq!(keymap.events('"'), Some(vec![
+            (Key::KEY_LEFTSHIFT, 1), (Key::KEY_APOSTROPHE, 1), (Key::KEY_APOSTROPHE, 0), (Key::KEY_LEFTSHIFT, 0),
+        ]));
+    }
+
+    #[test]
+    fn unicode_fallback() {
+        assert!(!Keymap::us().can_type('€'));
+        assert_eq!(Keymap::us().events('€'), None);
+
+        // U+20AC, as Ctrl+Shift+U 2 0 a c and a space to end it.
+        let keymap = Keymap::us().unicode(Unicode::CtrlShiftU);
+        assert!(keymap.can_type('€'));
+        let tap = |key: Key| vec![(key, 1), (key, 0)];
+        let mut events = vec![
+            (Key::KEY_LEFTCTRL, 1), (Key::KEY_LEFTSHIFT, 1), (Key::KEY_U, 1),
+            (Key::KEY_U, 0), (Key::KEY_LEFTSHIFT, 0), (Key::KEY_LEFTCTRL, 0),
+        ];
+        for key in [Key::KEY_2, Key::KEY_0, Key::KEY_A, Key::KEY_C, Key::KEY_SPACE] {
+            events.extend(tap(key));
+        }
+        assert_eq!(keymap.events('€'), Some(events));
+
+        // Characters the layout has are still typed directly.
+        assert_eq!(keymap.events('a'), Some(tap(Key::KEY_A)));
+    }
+
+    #[test]
+    fn without_xkbcommon() {
+        assert_eq!(Keymap::compile(None, "de", ""), Err("libxkbcommon could not be loaded".to_string()));
+    }
+
+    #[test]
+    fn xkb_layouts() {
+        if xkbcommon_option().is_none() {
+            eprintln!("libxkbcommon is not available, skipping");
+            return;
+        }
+
+        let de = Keymap::xkb("de", "").unwrap();
+        assert_eq!(de.keys('ß'), Some(&[Key::KEY_MINUS][..]));
+        assert_eq!(de.keys('z'), Some(&[Key::KEY_Y][..]));
+        assert_eq!(de.keys('Ü'), Some(&[Key::KEY_LEFTSHIFT, Key::KEY_LEFTBRACE][..]));
+        assert_eq!(de.keys('@'), Some(&[Key::KEY_RIGHTALT, Key::KEY_Q][..]));
+
+        let abnt2 = Keymap::xkb("br", "abnt2").unwrap();
+        assert_eq!(abnt2.keys('ç'), Some(&[Key::KEY_SEMICOLON][..]));
+        assert_eq!(abnt2.keys('Ç'), Some(&[Key::KEY_LEFTSHIFT, Key::KEY_SEMICOLON][..]));
+
+        assert_eq!(Keymap::xkb("nope", "").err(), Some("can't compile layout \"nope\" variant \"\"".to_string()));
+    }
+}
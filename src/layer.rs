@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{ Duration, SystemTime };
+
+use evdev_rs::{ InputEvent as InInputEvent, TimeVal };
+
+use evdev::Key;
+
+use crate::{ key_code, key_input, syn_input, Context, Control, DeviceInfo, Handler, Keymap, Macro, Player, Reply, TimerId };
+use crate::sticky::Modifiers;
+use crate::tap_hold::{ Decision, Resolved, Resolver, TapHoldConfig };
+
+pub type LayerId = usize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Key(Key),
+    Transparent,
+    NoOp,
+    Momentary(LayerId),
+    Toggle(LayerId),
+    OneShot(LayerId),
+    SetDefault(LayerId),
+    TapHold(Box<Action>, Box<Action>, TapHoldConfig),
+    Macro(Macro),
+    Sticky(Key),
+    CapsWord,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Layer {
+    name: Option<String>,
+    keys: HashMap<u16, Action>,
+}
+
+impl Layer {
+    pub fn new() -> Layer {
+        return Layer::default();
+    }
+
+    pub fn named(name: &str) -> Layer {
+        return Layer { name: Some(name.to_string()), ..Layer::default() };
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        return self.name.as_deref();
+    }
+
+    pub fn map(mut self, key: Key, action: Action) -> Layer {
+        self.keys.insert(key.code(), action);
+        return self;
+    }
+
+    pub fn get(&self, key: Key) -> Option<&Action> {
+        return self.keys.get(&key.code());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OneShot {
+    Held(LayerId, bool),
+    Armed(LayerId),
+}
+
+// A stack of keymaps in front of the wrapped handler. The highest active
+// layer that doesn't map a key as transparent decides what the key does;
+// keys no layer maps reach the inner handler unchanged.
+pub struct Layers<H> {
+    inner: H,
+    layers: Vec<Layer>,
+    default: LayerId,
+    momentary: Vec<usize>,
+    toggled: Vec<bool>,
+    oneshot: Option<OneShot>,
+    pressed: HashMap<u16, Action>,
+    resolver: Resolver<(Action, Action)>,
+    player: Player,
+    modifiers: Modifiers,
+}
+
+impl<H: Handler> Layers<H> {
+    pub fn new(inner: H) -> Layers<H> {
+        return Layers {
+            inner,
+            layers: Vec::new(),
+            default: 0,
+            momentary: Vec::new(),
+            toggled: Vec::new(),
+            oneshot: None,
+            pressed: HashMap::new(),
+            resolver: Resolver::default(),
+            player: Player::new(),
+            modifiers: Modifiers::default(),
+        };
+    }
+
+    pub fn layer(mut self, layer: Layer) -> Layers<H> {
+        self.layers.push(layer);
+        self.momentary.push(0);
+        self.toggled.push(false);
+        return self;
+    }
+
+    // The key that stops a playing macro, Esc unless changed.
+    pub fn macro_interrupt(mut self, key: Option<Key>) -> Layers<H> {
+        self.player = self.player.interrupt(key);
+        return self;
+    }
+
+    // How macros type text, US unless changed.
+    pub fn keymap(mut self, keymap: Keymap) -> Layers<H> {
+        self.player = self.player.keymap(keymap);
+        return self;
+    }
+
+    // How long a tapped sticky modifier waits for the next key.
+    pub fn sticky_timeout(mut self, timeout: Duration) -> Layers<H> {
+        self.modifiers.set_timeout(timeout);
+        return self;
+    }
+
+    pub fn default_layer(&self) -> LayerId {
+        return self.default;
+    }
+
+    pub fn set_default_layer(&mut self, layer: LayerId) {
+        if layer < self.layers.len() {
+            self.default = layer;
+        }
+    }
+
+    pub fn toggle_layer(&mut self, layer: LayerId) {
+        if let Some(toggled) = self.toggled.get_mut(layer) {
+            *toggled = !*toggled;
+        }
+    }
+
+    pub fn is_active(&self, layer: LayerId) -> bool {
+        if layer >= self.layers.len() {
+            return false;
+        }
+
+        let oneshot = match self.oneshot {
+            Some(OneShot::Held(id, _)) | Some(OneShot::Armed(id)) => id == layer,
+            None => false,
+        };
+
+        return layer == self.default || self.momentary[layer] > 0 || self.toggled[layer] || oneshot;
+    }
+
+    pub fn active_layers(&self) -> Vec<LayerId> {
+        return (0..self.layers.len()).filter(|&layer| self.is_active(layer)).collect();
+    }
+
+    // By name, or by index for layers without one.
+    pub fn find_layer(&self, name: &str) -> Option<LayerId> {
+        return self.layers.iter().position(|layer| layer.name() == Some(name))
+            .or_else(|| name.parse().ok().filter(|&id| id < self.layers.len()));
+    }
+
+    fn layer_name(&self, layer: LayerId) -> String {
+        return match self.layers[layer].name() {
+            Some(name) => name.to_string(),
+            None => layer.to_string(),
+        };
+    }
+
+    fn lookup(&self, code: u16) -> Action {
+        for layer in (0..self.layers.len()).rev() {
+            if !self.is_active(layer) {
+                continue;
+            }
+
+            match self.layers[layer].keys.get(&code) {
+                None | Some(Action::Transparent) => continue,
+                Some(action) => return action.clone(),
+            }
+        }
+
+        return Action::Key(Key::new(code));
+    }
+
+    fn dispatch(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        if let Some(code) = key_code(&ev).filter(|&code| ev.value == 1 && self.player.interrupts(code)) {
+            // The interrupting key does nothing else, its release included.
+            self.pressed.insert(code, Action::NoOp);
+            return self.stop_macro(&ev.time, ctx);
+        }
+
+        if self.resolver.is_pending() {
+            return match self.resolver.feed(ev, ctx) {
+                Some(resolved) => self.apply(resolved, ctx),
+                None => Control::Continue,
+            };
+        }
+
+        let code = match key_code(&ev) {
+            Some(code) => code,
+            None => return self.inner.on_event(ev, ctx),
+        };
+
+        // Releases and repeats go to whatever the press resolved to, even if
+        // the layers have changed since.
+        if let Some(action) = self.pressed.get(&code) {
+            return match (ev.value, action.clone()) {
+                (0, action) => {
+                    self.pressed.remove(&code);
+                    self.release(&ev.time, &action, ctx)
+                },
+                (_, Action::Key(key)) => self.inner.on_event(key_input(&ev.time, key.code(), ev.value), ctx),
+                _ => Control::Continue,
+            };
+        }
+
+        if ev.value != 1 {
+            return self.inner.on_event(ev, ctx);
+        }
+
+        let action = self.lookup(code);
+
+        match self.oneshot {
+            Some(OneShot::Held(layer, false)) if !matches!(action, Action::OneShot(_)) => {
+                self.oneshot = Some(OneShot::Held(layer, true));
+            },
+            Some(OneShot::Armed(_)) => {
+                self.oneshot = None;
+            },
+            _ => {},
+        }
+
+        if let Action::TapHold(tap, hold, config) = action {
+            return match self.resolver.start(&ev, (*tap, *hold), config, ctx) {
+                Some(resolved) => self.apply(resolved, ctx),
+                None => Control::Continue,
+            };
+        }
+
+        self.pressed.insert(code, action.clone());
+        return self.press(&ev.time, &action, ctx);
+    }
+
+    fn press(&mut self, time: &TimeVal, action: &Action, ctx: &mut Context) -> Control {
+        match *action {
+            Action::Key(key) => {
+                let before = self.modifiers.before_key(key);
+                let control = match self.send(time, &before, ctx) {
+                    Control::Continue => self.inner.on_event(key_input(time, key.code(), 1), ctx),
+                    control => control,
+                };
+
+                let after = self.modifiers.after_key(key, ctx);
+                if control != Control::Continue || after.is_empty() {
+                    return control;
+                }
+                return match self.inner.on_event(syn_input(time), ctx) {
+                    Control::Continue => self.send(time, &after, ctx),
+                    control => control,
+                };
+            },
+            Action::Sticky(key) => {
+                let events = self.modifiers.press(key, ctx);
+                return self.send(time, &events, ctx);
+            },
+            Action::CapsWord => {
+                let events = self.modifiers.toggle_caps_word();
+                return self.send(time, &events, ctx);
+            },
+            Action::Momentary(layer) => {
+                if let Some(count) = self.momentary.get_mut(layer) {
+                    *count += 1;
+                }
+            },
+            Action::Toggle(layer) => self.toggle_layer(layer),
+            Action::OneShot(layer) => self.oneshot = Some(OneShot::Held(layer, false)),
+            Action::SetDefault(layer) => self.set_default_layer(layer),
+            Action::Macro(ref m) => {
+                self.player.queue(m);
+                return self.play(ctx);
+            },
+            Action::Transparent | Action::NoOp | Action::TapHold(..) => {},
+        }
+
+        return Control::Continue;
+    }
+
+    fn release(&mut self, time: &TimeVal, action: &Action, ctx: &mut Context) -> Control {
+        match *action {
+            Action::Key(key) => return self.inner.on_event(key_input(time, key.code(), 0), ctx),
+            Action::Sticky(key) => {
+                let events = self.modifiers.release(key, ctx);
+                return self.send(time, &events, ctx);
+            },
+            Action::Momentary(layer) => {
+                if let Some(count) = self.momentary.get_mut(layer) {
+                    *count = count.saturating_sub(1);
+                }
+            },
+            Action::OneShot(layer) => {
+                self.oneshot = match self.oneshot {
+                    Some(OneShot::Held(id, false)) if id == layer => Some(OneShot::Armed(layer)),
+                    Some(OneShot::Held(id, true)) if id == layer => None,
+                    oneshot => oneshot,
+                };
+            },
+            _ => {},
+        }
+
+        return Control::Continue;
+    }
+
+    fn send(&mut self, time: &TimeVal, events: &[(u16, i32)], ctx: &mut Context) -> Control {
+        for &(code, value) in events {
+            let control = self.inner.on_event(key_input(time, code, value), ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+
+    fn play(&mut self, ctx: &mut Context) -> Control {
+        let time = TimeVal::try_from(ctx.wall_clock()).unwrap_or_else(|_| TimeVal::new(0, 0));
+
+        while let Some((code, value)) = self.player.next(ctx) {
+            let control = match self.inner.on_event(key_input(&time, code, value), ctx) {
+                Control::Continue => self.inner.on_event(syn_input(&time), ctx),
+                control => control,
+            };
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+
+    fn stop_macro(&mut self, time: &TimeVal, ctx: &mut Context) -> Control {
+        let held = self.player.stop(ctx);
+        if held.is_empty() {
+            return Control::Continue;
+        }
+
+        for code in held {
+            let control = self.inner.on_event(key_input(time, code, 0), ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return self.inner.on_event(syn_input(time), ctx);
+    }
+
+    fn tap(&mut self, time: &TimeVal, action: &Action, ctx: &mut Context) -> Control {
+        let control = match self.press(time, action, ctx) {
+            Control::Continue => self.inner.on_event(syn_input(time), ctx),
+            control => control,
+        };
+        if control != Control::Continue {
+            return control;
+        }
+
+        return match self.release(time, action, ctx) {
+            Control::Continue => self.inner.on_event(syn_input(time), ctx),
+            control => control,
+        };
+    }
+
+    fn apply(&mut self, resolved: Resolved<(Action, Action)>, ctx: &mut Context) -> Control {
+        let (tap, hold) = resolved.payload;
+
+        let control = match resolved.decision {
+            Decision::Tap => self.tap(&resolved.time, &tap, ctx),
+            Decision::Hold => {
+                self.pressed.insert(resolved.code, hold.clone());
+                match self.press(&resolved.time, &hold, ctx) {
+                    Control::Continue => self.inner.on_event(syn_input(&resolved.time), ctx),
+                    control => control,
+                }
+            },
+        };
+
+        if control != Control::Continue {
+            return control;
+        }
+
+        for ev in resolved.buffer {
+            let control = self.dispatch(ev, ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+}
+
+impl<H: Handler> Handler for Layers<H> {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return self.dispatch(ev, ctx);
+    }
+
+    fn on_start(&mut self, ctx: &mut Context) {
+        self.inner.on_start(ctx);
+    }
+
+    fn on_stop(&mut self, ctx: &mut Context) {
+        let time = TimeVal::try_from(SystemTime::now()).unwrap_or_else(|_| TimeVal::new(0, 0));
+        self.stop_macro(&time, ctx);
+        let events = self.modifiers.clear(ctx);
+        if self.send(&time, &events, ctx) == Control::Continue && !events.is_empty() {
+            self.inner.on_event(syn_input(&time), ctx);
+        }
+        self.inner.on_stop(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) -> Control {
+        return self.inner.on_tick(ctx);
+    }
+
+    fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+        if self.player.timeout(timer) {
+            return self.play(ctx);
+        }
+
+        if let Some(events) = self.modifiers.timeout(timer) {
+            let time = TimeVal::try_from(ctx.wall_clock()).unwrap_or_else(|_| TimeVal::new(0, 0));
+            return match self.send(&time, &events, ctx) {
+                Control::Continue => self.inner.on_event(syn_input(&time), ctx),
+                control => control,
+            };
+        }
+
+        return match self.resolver.timeout(timer) {
+            Some(resolved) => self.apply(resolved, ctx),
+            None => self.inner.on_timer(timer, ctx),
+        };
+    }
+
+    fn on_device_added(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_added(info, ctx);
+    }
+
+    fn on_device_removed(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_removed(info, ctx);
+    }
+
+    fn on_command(&mut self, command: &[&str], ctx: &mut Context) -> Option<Reply> {
+        let find = |name: &str| self.find_layer(name).ok_or_else(|| format!("unknown layer \"{}\"", name));
+
+        return match command {
+            ["layer", name] => Some(find(name).map(|layer| {
+                self.set_default_layer(layer);
+                Vec::new()
+            })),
+            ["toggle", name] => Some(find(name).map(|layer| {
+                self.toggle_layer(layer);
+                Vec::new()
+            })),
+            ["status"] => {
+                let active: Vec<String> = self.active_layers().into_iter().map(|layer| self.layer_name(layer)).collect();
+                let mut lines = vec![
+                    format!("layer={}", self.layer_name(self.default)),
+                    format!("active={}", active.join(",")),
+                ];
+                lines.extend(self.modifiers.status());
+                if let Some(Ok(inner)) = self.inner.on_command(command, ctx) {
+                    lines.extend(inner);
+                }
+                Some(Ok(lines))
+            },
+            _ => self.inner.on_command(command, ctx),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::{ Duration, Instant, SystemTime };
+
+    use super::*;
+    use crate::{ Mock, Passthrough };
+    use crate::timer::Timers;
+
+    #[test]
+    fn momentary() {
+        let (tx, rx) = mpsc::channel();
+        let txs = [tx];
+        let mut timers = Timers::default();
+        let mut layers = Layers::new(Passthrough)
+            .layer(Layer::new().map(Key::KEY_CAPSLOCK, Action::Momentary(1)))
+            .layer(Layer::new().map(Key::KEY_H, Action::Key(Key::KEY_LEFT)).map(Key::KEY_J, Action::Transparent));
+
+        let time = TimeVal::new(0, 0);
+        let keys = [
+            (Key::KEY_CAPSLOCK, 1), (Key::KEY_H, 1), (Key::KEY_H, 0), (Key::KEY_J, 1), (Key::KEY_J, 0),
+            // Released after the layer went away, still as what it was pressed as.
+            (Key::KEY_H, 1), (Key::KEY_CAPSLOCK, 0), (Key::KEY_H, 0),
+            (Key::KEY_H, 1), (Key::KEY_H, 0),
+        ];
+        for (key, value) in keys {
+            layers.on_event(key_input(&time, key.code(), value), &mut Context::new(0, Instant::now(), SystemTime::now(), &txs, &mut timers));
+        }
+
+        let output: Vec<(Key, i32)> = rx.try_iter().map(|ev| (Key::new(ev.code()), ev.value())).collect();
+        assert_eq!(output, vec![
+            (Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_J, 1), (Key::KEY_J, 0),
+            (Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0),
+            (Key::KEY_H, 1), (Key::KEY_H, 0),
+        ]);
+    }
+
+    #[test]
+    fn toggle() {
+        let (tx, rx) = mpsc::channel();
+        let txs = [tx];
+        let mut timers = Timers::default();
+        let mut layers = Layers::new(Passthrough)
+            .layer(Layer::new().map(Key::KEY_F1, Action::Toggle(1)))
+            .layer(Layer::new().map(Key::KEY_H, Action::Key(Key::KEY_LEFT)));
+
+        let time = TimeVal::new(0, 0);
+        let keys = [
+            (Key::KEY_F1, 1), (Key::KEY_F1, 0), (Key::KEY_H, 1), (Key::KEY_H, 0),
+            (Key::KEY_F1, 1), (Key::KEY_F1, 0), (Key::KEY_H, 1), (Key::KEY_H, 0),
+        ];
+        for (key, value) in keys {
+            layers.on_event(key_input(&time, key.code(), value), &mut Context::new(0, Instant::now(), SystemTime::now(), &txs, &mut timers));
+        }
+
+        let output: Vec<(Key, i32)> = rx.try_iter().map(|ev| (Key::new(ev.code()), ev.value())).collect();
+        assert_eq!(output, vec![(Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_H, 1), (Key::KEY_H, 0)]);
+
+        layers.toggle_layer(1);
+        assert_eq!(layers.active_layers(), vec![0, 1]);
+        layers.toggle_layer(5);
+        assert_eq!(layers.active_layers(), vec![0, 1]);
+    }
+
+    #[test]
+    fn one_shot() {
+        let (tx, rx) = mpsc::channel();
+        let txs = [tx];
+        let mut timers = Timers::default();
+        let mut layers = Layers::new(Passthrough)
+            .layer(Layer::new().map(Key::KEY_F2, Action::OneShot(1)))
+            .layer(Layer::new().map(Key::KEY_H, Action::Key(Key::KEY_LEFT)));
+
+        let time = TimeVal::new(0, 0);
+        let keys = [
+            // Tapped, it lasts for one key.
+            (Key::KEY_F2, 1), (Key::KEY_F2, 0), (Key::KEY_H, 1), (Key::KEY_H, 0), (Key::KEY_H, 1), (Key::KEY_H, 0),
+            // Held, for as long as it is down.
+            (Key::KEY_F2, 1), (Key::KEY_H, 1), (Key::KEY_H, 0), (Key::KEY_H, 1), (Key::KEY_H, 0), (Key::KEY_F2, 0),
+            (Key::KEY_H, 1), (Key::KEY_H, 0),
+        ];
+        for (key, value) in keys {
+            layers.on_event(key_input(&time, key.code(), value), &mut Context::new(0, Instant::now(), SystemTime::now(), &txs, &mut timers));
+        }
+
+        let output: Vec<(Key, i32)> = rx.try_iter().map(|ev| (Key::new(ev.code()), ev.value())).collect();
+        assert_eq!(output, vec![
+            (Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_H, 1), (Key::KEY_H, 0),
+            (Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0),
+            (Key::KEY_H, 1), (Key::KEY_H, 0),
+        ]);
+    }
+
+    #[test]
+    fn default_layer() {
+        let (tx, rx) = mpsc::channel();
+        let txs = [tx];
+        let mut timers = Timers::default();
+        let mut layers = Layers::new(Passthrough)
+            .layer(Layer::new().map(Key::KEY_F3, Action::SetDefault(1)))
+            .layer(Layer::new().map(Key::KEY_H, Action::Key(Key::KEY_LEFT)).map(Key::KEY_F4, Action::SetDefault(0)));
+
+        let time = TimeVal::new(0, 0);
+        let keys = [
+            (Key::KEY_F3, 1), (Key::KEY_F3, 0), (Key::KEY_H, 1), (Key::KEY_H, 0),
+            // Nothing below the default layer is looked at.
+            (Key::KEY_F3, 1), (Key::KEY_F3, 0),
+            (Key::KEY_F4, 1), (Key::KEY_F4, 0), (Key::KEY_H, 1), (Key::KEY_H, 0),
+        ];
+        for (key, value) in keys {
+            layers.on_event(key_input(&time, key.code(), value), &mut Context::new(0, Instant::now(), SystemTime::now(), &txs, &mut timers));
+        }
+
+        let output: Vec<(Key, i32)> = rx.try_iter().map(|ev| (Key::new(ev.code()), ev.value())).collect();
+        assert_eq!(output, vec![(Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_F3, 1), (Key::KEY_F3, 0), (Key::KEY_H, 1), (Key::KEY_H, 0)]);
+        assert_eq!(layers.default_layer(), 0);
+
+        layers.set_default_layer(1);
+        layers.set_default_layer(2);
+        assert_eq!(layers.active_layers(), vec![1]);
+    }
+
+    #[test]
+    fn tap_hold() {
+        let hold = Action::TapHold(Box::new(Action::Key(Key::KEY_SPACE)), Box::new(Action::Momentary(1)), TapHoldConfig::default());
+        let mut mock = Mock::new(Layers::new(Passthrough)
+            .layer(Layer::new().map(Key::KEY_SPACE, hold))
+            .layer(Layer::new().map(Key::KEY_H, Action::Key(Key::KEY_LEFT))));
+
+        mock.tap(Key::KEY_SPACE);
+        assert_eq!(mock.keys(), vec![(Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0)]);
+
+        mock.press(Key::KEY_SPACE);
+        mock.advance(Duration::from_millis(200));
+        assert!(mock.handler().is_active(1));
+        mock.tap(Key::KEY_H);
+        mock.release(Key::KEY_SPACE);
+        mock.tap(Key::KEY_H);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFT, 1), (Key::KEY_LEFT, 0), (Key::KEY_H, 1), (Key::KEY_H, 0)]);
+    }
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use evdev_rs::InputEvent as InInputEvent;
+
+use evdev::Key;
+
+use crate::{ key_code, syn_input, Context, Control, DeviceInfo, Handler, Macro, Player, Reply, TimerId };
+
+// What happens to the keys typed after the leader when they don't make up
+// a sequence: typed as if there had been no leader, or dropped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaderFallback {
+    Replay,
+    Discard,
+}
+
+#[derive(Default)]
+struct Node {
+    action: Option<Macro>,
+    children: HashMap<u16, Node>,
+}
+
+fn find<'a>(root: &'a Node, keys: &[Key]) -> Option<&'a Node> {
+    let mut node = root;
+    for key in keys {
+        node = node.children.get(&key.code())?;
+    }
+
+    return Some(node);
+}
+
+// Called with the keys typed so far while a sequence is pending, and with
+// None once it has matched or failed.
+pub type LeaderCallback = Box<dyn FnMut(Option<&[Key]>)>;
+
+// A sequence being typed: its keys so far, and every key event since the
+// leader, for a replay.
+struct Leading {
+    keys: Vec<Key>,
+    buffer: Vec<InInputEvent>,
+    timer: TimerId,
+}
+
+impl Leading {
+    // Sequence keys that are still down.
+    fn held(&self) -> Vec<u16> {
+        let mut held = Vec::new();
+        for ev in &self.buffer {
+            let code = ev.as_raw().code;
+            match ev.value {
+                0 => held.retain(|&key| key != code),
+                1 => held.push(code),
+                _ => {},
+            }
+        }
+
+        return held;
+    }
+}
+
+// Key sequences typed after a leader key, each bound to a macro that plays
+// when it matches. A sequence that is also the start of a longer one fires
+// when the timeout passes or a key that doesn't continue it is pressed.
+// The keys are the ones typed, before the inner handler maps them.
+pub struct Leader<H> {
+    inner: H,
+    keys: Vec<u16>,
+    root: Node,
+    timeout: Duration,
+    fallback: LeaderFallback,
+    callback: Option<LeaderCallback>,
+    player: Player,
+    leading: Option<Leading>,
+    // Keys whose releases mustn't go through on their own.
+    swallowed: Vec<u16>,
+}
+
+impl<H: Handler> Leader<H> {
+    pub fn new(inner: H) -> Leader<H> {
+        return Leader {
+            inner,
+            keys: Vec::new(),
+            root: Node::default(),
+            timeout: Duration::from_secs(1),
+            fallback: LeaderFallback::Replay,
+            callback: None,
+            player: Player::new(),
+            leading: None,
+            swallowed: Vec::new(),
+        };
+    }
+
+    // A key that starts a sequence. It never reaches the inner handler.
+    pub fn key(mut self, key: Key) -> Leader<H> {
+        if !self.keys.contains(&key.code()) {
+            self.keys.push(key.code());
+        }
+        return self;
+    }
+
+    pub fn sequence(mut self, keys: &[Key], action: Macro) -> Leader<H> {
+        let mut node = &mut self.root;
+        for key in keys {
+            node = node.children.entry(key.code()).or_default();
+        }
+
+        node.action = Some(action);
+        return self;
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Leader<H> {
+        self.timeout = timeout;
+        return self;
+    }
+
+    pub fn fallback(mut self, fallback: LeaderFallback) -> Leader<H> {
+        self.fallback = fallback;
+        return self;
+    }
+
+    pub fn on_pending(mut self, callback: impl FnMut(Option<&[Key]>) + 'static) -> Leader<H> {
+        self.callback = Some(Box::new(callback));
+        return self;
+    }
+
+    // What plays the macros, for another interrupt key or keymap.
+    pub fn player(mut self, player: Player) -> Leader<H> {
+        self.player = player;
+        return self;
+    }
+
+    fn notify(&mut self, keys: Option<&[Key]>) {
+        match keys {
+            Some(keys) => log::debug!("leader pending keys={:?}", keys),
+            None => log::debug!("leader done"),
+        }
+
+        if let Some(callback) = &mut self.callback {
+            callback(keys);
+        }
+    }
+
+    fn dispatch(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        let code = match key_code(&ev) {
+            Some(code) => code,
+            None => return self.inner.on_event(ev, ctx),
+        };
+
+        if ev.value == 1 && self.player.interrupts(code) {
+            // The interrupting key does nothing else, its release included.
+            self.player.cancel(ctx);
+            self.swallowed.push(code);
+            return Control::Continue;
+        }
+        if ev.value != 1 && self.swallowed.contains(&code) {
+            if ev.value == 0 {
+                self.swallowed.retain(|&swallowed| swallowed != code);
+            }
+            return Control::Continue;
+        }
+
+        if let Some(leading) = &mut self.leading {
+            // Keys that were down before the leader are let through.
+            if ev.value == 1 {
+                return self.lead(code, ev, ctx);
+            }
+            if leading.held().contains(&code) {
+                leading.buffer.push(ev);
+                return Control::Continue;
+            }
+        }
+
+        if ev.value == 1 && self.keys.contains(&code) {
+            let timer = ctx.call_after(self.timeout);
+            self.leading = Some(Leading { keys: Vec::new(), buffer: Vec::new(), timer });
+            self.swallowed.push(code);
+            self.notify(Some(&[]));
+            return Control::Continue;
+        }
+
+        return self.inner.on_event(ev, ctx);
+    }
+
+    fn lead(&mut self, code: u16, ev: InInputEvent, ctx: &mut Context) -> Control {
+        let leading = match &mut self.leading {
+            Some(leading) => leading,
+            None => return Control::Continue,
+        };
+
+        let mut keys = leading.keys.clone();
+        keys.push(Key::new(code));
+
+        let (action, more) = match find(&self.root, &keys) {
+            Some(node) => (node.action.clone(), !node.children.is_empty()),
+            None => {
+                // A shorter sequence still fires, the key then counts as typed
+                // after it.
+                let action = find(&self.root, &leading.keys).and_then(|node| node.action.clone());
+                return match action {
+                    Some(action) => match self.end(Some(action), ctx) {
+                        Control::Continue => self.dispatch(ev, ctx),
+                        control => control,
+                    },
+                    None => {
+                        leading.buffer.push(ev);
+                        self.end(None, ctx)
+                    },
+                };
+            },
+        };
+
+        leading.keys = keys.clone();
+        leading.buffer.push(ev);
+        if more {
+            ctx.cancel(leading.timer);
+            leading.timer = ctx.call_after(self.timeout);
+            self.notify(Some(&keys));
+            return Control::Continue;
+        }
+
+        return self.end(action, ctx);
+    }
+
+    // Plays the macro the sequence matched, or falls back when there is
+    // none.
+    fn end(&mut self, action: Option<Macro>, ctx: &mut Context) -> Control {
+        let leading = match self.leading.take() {
+            Some(leading) => leading,
+            None => return Control::Continue,
+        };
+        ctx.cancel(leading.timer);
+        self.notify(None);
+
+        if action.is_some() || self.fallback == LeaderFallback::Discard {
+            self.swallowed.extend(leading.held());
+        }
+
+        if let Some(action) = action {
+            self.player.play(&action, ctx);
+            return Control::Continue;
+        }
+        if self.fallback == LeaderFallback::Discard {
+            return Control::Continue;
+        }
+
+        for ev in leading.buffer {
+            let time = ev.time;
+            let control = match self.dispatch(ev, ctx) {
+                Control::Continue => self.dispatch(syn_input(&time), ctx),
+                control => control,
+            };
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+}
+
+impl<H: Handler> Handler for Leader<H> {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return self.dispatch(ev, ctx);
+    }
+
+    fn on_start(&mut self, ctx: &mut Context) {
+        self.inner.on_start(ctx);
+    }
+
+    fn on_stop(&mut self, ctx: &mut Context) {
+        self.player.cancel(ctx);
+        self.inner.on_stop(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) -> Control {
+        return self.inner.on_tick(ctx);
+    }
+
+    fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+        if self.player.on_timer(timer, ctx) {
+            return Control::Continue;
+        }
+
+        if let Some(leading) = self.leading.as_ref().filter(|leading| leading.timer == timer) {
+            let action = find(&self.root, &leading.keys).and_then(|node| node.action.clone());
+            return self.end(action, ctx);
+        }
+
+        return self.inner.on_timer(timer, ctx);
+    }
+
+    fn on_device_added(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_added(info, ctx);
+    }
+
+    fn on_device_removed(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_removed(info, ctx);
+    }
+
+    fn on_command(&mut self, command: &[&str], ctx: &mut Context) -> Option<Reply> {
+        let inner = self.inner.on_command(command, ctx);
+
+        return match (command, &self.leading) {
+            (["status"], Some(leading)) => {
+                let keys: Vec<String> = leading.keys.iter().map(|key| format!("{:?}", key)).collect();
+                let mut lines = match inner {
+                    Some(Ok(lines)) => lines,
+                    _ => Vec::new(),
+                };
+                lines.push(format!("leader={}", keys.join(",")));
+                Some(Ok(lines))
+            },
+            _ => inner,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{ Action, Layer, Layers, Mock, Passthrough };
+
+    #[test]
+    fn match_sequence() {
+        let pending = Rc::new(RefCell::new(Vec::new()));
+        let seen = pending.clone();
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .on_pending(move |keys| seen.borrow_mut().push(keys.map(|keys| keys.to_vec())))
+            .sequence(&[Key::KEY_F, Key::KEY_S], Macro::new().tap(Key::KEY_F12));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_F);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.tap(Key::KEY_S);
+        assert_eq!(mock.keys(), vec![(Key::KEY_F12, 1), (Key::KEY_F12, 0)]);
+        assert_eq!(*pending.borrow(), vec![Some(vec![]), Some(vec![Key::KEY_F]), None]);
+    }
+
+    #[test]
+    fn prefix_on_timeout() {
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .timeout(Duration::from_millis(500))
+            .sequence(&[Key::KEY_G], Macro::new().tap(Key::KEY_HOME))
+            .sequence(&[Key::KEY_G, Key::KEY_E], Macro::new().tap(Key::KEY_END));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_G);
+        mock.advance(Duration::from_millis(499));
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.advance(Duration::from_millis(1));
+        assert_eq!(mock.keys(), vec![(Key::KEY_HOME, 1), (Key::KEY_HOME, 0)]);
+    }
+
+    #[test]
+    fn prefix_then_other_key() {
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .sequence(&[Key::KEY_G], Macro::new().tap(Key::KEY_HOME))
+            .sequence(&[Key::KEY_G, Key::KEY_E], Macro::new().tap(Key::KEY_END));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_G);
+        mock.tap(Key::KEY_X);
+        assert_eq!(mock.keys(), vec![(Key::KEY_HOME, 1), (Key::KEY_HOME, 0), (Key::KEY_X, 1), (Key::KEY_X, 0)]);
+    }
+
+    #[test]
+    fn replay_fallback() {
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .sequence(&[Key::KEY_F, Key::KEY_S], Macro::new().tap(Key::KEY_F12));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_F);
+        mock.tap(Key::KEY_X);
+        assert_eq!(mock.keys(), vec![(Key::KEY_F, 1), (Key::KEY_F, 0), (Key::KEY_X, 1), (Key::KEY_X, 0)]);
+    }
+
+    #[test]
+    fn replay_on_timeout() {
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .timeout(Duration::from_millis(500))
+            .sequence(&[Key::KEY_F, Key::KEY_S], Macro::new().tap(Key::KEY_F12));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.press(Key::KEY_F);
+        mock.advance(Duration::from_millis(500));
+        assert_eq!(mock.keys(), vec![(Key::KEY_F, 1)]);
+
+        mock.release(Key::KEY_F);
+        assert_eq!(mock.keys(), vec![(Key::KEY_F, 0)]);
+    }
+
+    #[test]
+    fn discard_fallback() {
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .fallback(LeaderFallback::Discard)
+            .sequence(&[Key::KEY_F, Key::KEY_S], Macro::new().tap(Key::KEY_F12));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_F);
+        mock.tap(Key::KEY_X);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.tap(Key::KEY_X);
+        assert_eq!(mock.keys(), vec![(Key::KEY_X, 1), (Key::KEY_X, 0)]);
+    }
+
+    #[test]
+    fn over_layers() {
+        // Sequences are the keys typed, the fallback replays them through the
+        // layers.
+        let leader = Leader::new(Layers::new(Passthrough).layer(Layer::new().map(Key::KEY_G, Action::Key(Key::KEY_H))))
+            .key(Key::KEY_RIGHTALT)
+            .sequence(&[Key::KEY_G, Key::KEY_S], Macro::new().text("ok"));
+        let mut mock = Mock::new(leader);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_G);
+        let status = mock.command(&["status"]).unwrap_or_default();
+        assert!(status.ends_with(&["layer=0".to_string(), "active=0".to_string(), "leader=KEY_G".to_string()]));
+
+        mock.tap(Key::KEY_S);
+        assert_eq!(mock.keys(), vec![(Key::KEY_O, 1), (Key::KEY_O, 0), (Key::KEY_K, 1), (Key::KEY_K, 0)]);
+
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.tap(Key::KEY_G);
+        mock.tap(Key::KEY_X);
+        assert_eq!(mock.keys(), vec![(Key::KEY_H, 1), (Key::KEY_H, 0), (Key::KEY_X, 1), (Key::KEY_X, 0)]);
+    }
+
+    #[test]
+    fn held_before_leader() {
+        let leader = Leader::new(Passthrough)
+            .key(Key::KEY_RIGHTALT)
+            .sequence(&[Key::KEY_F], Macro::new().tap(Key::KEY_F12));
+        let mut mock = Mock::new(leader);
+
+        mock.press(Key::KEY_LEFTSHIFT);
+        mock.tap(Key::KEY_RIGHTALT);
+        mock.release(Key::KEY_LEFTSHIFT);
+        mock.press(Key::KEY_F);
+        mock.release(Key::KEY_F);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 1), (Key::KEY_LEFTSHIFT, 0), (Key::KEY_F12, 1), (Key::KEY_F12, 0)]);
+    }
+}
@@ -1,624 +1,1151 @@
+#![allow(clippy::needless_return)]
+
+use std::convert::{ TryFrom, TryInto };
 use std::env;
-use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::thread;
+use std::thread::JoinHandle;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
 use std::time;
+use std::time::{ Duration, Instant, SystemTime };
+
+use evdev_rs::{ Device, DeviceWrapper, GrabMode, InputEvent as InInputEvent, TimeVal };
+use evdev_rs::enums::{ EventCode, EV_SYN };
+
+use evdev::{ EventType, InputEvent as OutInputEvent, Key };
+
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::poll::{ poll, PollFd, PollFlags };
+
+mod backend;
+mod bench;
+mod combo;
+mod config;
+mod control;
+mod error;
+mod escape;
+mod handler;
+mod hotplug;
+mod keymap;
+mod layer;
+mod leader;
+mod macros;
+mod mock;
+mod record;
+mod selector;
+mod signals;
+mod stats;
+mod sticky;
+mod tap_hold;
+mod timer;
+mod trace;
+mod uinput;
+mod waiter;
 
-use evdev_rs::{ Device, ReadFlag, GrabMode, InputEvent as InInputEvent };
+pub use backend::{ EventSource, OutputSink };
+pub use bench::bench;
+pub use combo::{ Combo, Combos };
+pub use config::{ parse_duration, parse_key, Config };
+pub use control::{ default_socket_path, request, Reply };
+pub use error::{ ConfigError, NhkError, RecordingError };
+pub use escape::EscapeChord;
+pub use handler::{ Context, Control, Handler, Passthrough };
+pub use keymap::{ Keymap, Unicode };
+pub use layer::{ Action, Layer, LayerId, Layers };
+pub use leader::{ Leader, LeaderCallback, LeaderFallback };
+pub use macros::{ Macro, Player, Step };
+pub use mock::{ Mock, MockSink, MockSource };
+pub use record::{ load_events, parse_events, play, record, replay, write_event, write_header, write_output };
+pub use selector::{ list_devices, DeviceInfo, DeviceSelector };
+pub use stats::{ Histogram, Latency, Stats };
+pub use sticky::Sticky;
+pub use tap_hold::{ TapHold, TapHoldConfig, TapHoldMode };
+pub use timer::TimerId;
+pub use trace::{ Trace, TARGET as TRACE_TARGET };
+pub use uinput::Capabilities;
+pub use waiter::EventLoop;
 
-use evdev::{uinput::VirtualDevice, uinput::VirtualDeviceBuilder, AttributeSet, EventType, InputEvent as OutInputEvent, Key, RelativeAxisType };
+use control::ControlSocket;
+use escape::Escape;
+use hotplug::{ Identity, Watcher };
+use signals::Signals;
+use stats::Stage;
+use timer::{ Action as TimerAction, Timers };
+use uinput::{ Framer, OutputDevice };
+use waiter::{ Ready, Token, Waiter };
 
 pub type EventHandler = Box<dyn Fn(InInputEvent, &Sender<OutInputEvent>) -> bool>;
 
-fn dev_uinput_from_file(file_name: String) -> Result<(Device, VirtualDevice), std::io::Error> {
-    let file = File::open(file_name).unwrap();
-    let dev = Device::new_from_file(file).unwrap();
-
-    let mut keys = AttributeSet::<Key>::new();
-    {
-        keys.insert(Key::KEY_RESERVED);
-        keys.insert(Key::KEY_ESC);
-        keys.insert(Key::KEY_1);
-        keys.insert(Key::KEY_2);
-        keys.insert(Key::KEY_3);
-        keys.insert(Key::KEY_4);
-        keys.insert(Key::KEY_5);
-        keys.insert(Key::KEY_6);
-        keys.insert(Key::KEY_7);
-        keys.insert(Key::KEY_8);
-        keys.insert(Key::KEY_9);
-        keys.insert(Key::KEY_0);
-        keys.insert(Key::KEY_MINUS);
-        keys.insert(Key::KEY_EQUAL);
-        keys.insert(Key::KEY_BACKSPACE);
-        keys.insert(Key::KEY_TAB);
-        keys.insert(Key::KEY_Q);
-        keys.insert(Key::KEY_W);
-        keys.insert(Key::KEY_E);
-        keys.insert(Key::KEY_R);
-        keys.insert(Key::KEY_T);
-        keys.insert(Key::KEY_Y);
-        keys.insert(Key::KEY_U);
-        keys.insert(Key::KEY_I);
-        keys.insert(Key::KEY_O);
-        keys.insert(Key::KEY_P);
-        keys.insert(Key::KEY_LEFTBRACE);
-        keys.insert(Key::KEY_RIGHTBRACE);
-        keys.insert(Key::KEY_ENTER);
-        keys.insert(Key::KEY_LEFTCTRL);
-        keys.insert(Key::KEY_A);
-        keys.insert(Key::KEY_S);
-        keys.insert(Key::KEY_D);
-        keys.insert(Key::KEY_F);
-        keys.insert(Key::KEY_G);
-        keys.insert(Key::KEY_H);
-        keys.insert(Key::KEY_J);
-        keys.insert(Key::KEY_K);
-        keys.insert(Key::KEY_L);
-        keys.insert(Key::KEY_SEMICOLON);
-        keys.insert(Key::KEY_APOSTROPHE);
-        keys.insert(Key::KEY_GRAVE);
-        keys.insert(Key::KEY_LEFTSHIFT);
-        keys.insert(Key::KEY_BACKSLASH);
-        keys.insert(Key::KEY_Z);
-        keys.insert(Key::KEY_X);
-        keys.insert(Key::KEY_C);
-        keys.insert(Key::KEY_V);
-        keys.insert(Key::KEY_B);
-        keys.insert(Key::KEY_N);
-        keys.insert(Key::KEY_M);
-        keys.insert(Key::KEY_COMMA);
-        keys.insert(Key::KEY_DOT);
-        keys.insert(Key::KEY_SLASH);
-        keys.insert(Key::KEY_RIGHTSHIFT);
-        keys.insert(Key::KEY_KPASTERISK);
-        keys.insert(Key::KEY_LEFTALT);
-        keys.insert(Key::KEY_SPACE);
-        keys.insert(Key::KEY_CAPSLOCK);
-        keys.insert(Key::KEY_F1);
-        keys.insert(Key::KEY_F2);
-        keys.insert(Key::KEY_F3);
-        keys.insert(Key::KEY_F4);
-        keys.insert(Key::KEY_F5);
-        keys.insert(Key::KEY_F6);
-        keys.insert(Key::KEY_F7);
-        keys.insert(Key::KEY_F8);
-        keys.insert(Key::KEY_F9);
-        keys.insert(Key::KEY_F10);
-        keys.insert(Key::KEY_NUMLOCK);
-        keys.insert(Key::KEY_SCROLLLOCK);
-        keys.insert(Key::KEY_KP7);
-        keys.insert(Key::KEY_KP8);
-        keys.insert(Key::KEY_KP9);
-        keys.insert(Key::KEY_KPMINUS);
-        keys.insert(Key::KEY_KP4);
-        keys.insert(Key::KEY_KP5);
-        keys.insert(Key::KEY_KP6);
-        keys.insert(Key::KEY_KPPLUS);
-        keys.insert(Key::KEY_KP1);
-        keys.insert(Key::KEY_KP2);
-        keys.insert(Key::KEY_KP3);
-        keys.insert(Key::KEY_KP0);
-        keys.insert(Key::KEY_KPDOT);
-        keys.insert(Key::KEY_ZENKAKUHANKAKU);
-        keys.insert(Key::KEY_102ND);
-        keys.insert(Key::KEY_F11);
-        keys.insert(Key::KEY_F12);
-        keys.insert(Key::KEY_RO);
-        keys.insert(Key::KEY_KATAKANA);
-        keys.insert(Key::KEY_HIRAGANA);
-        keys.insert(Key::KEY_HENKAN);
-        keys.insert(Key::KEY_KATAKANAHIRAGANA);
-        keys.insert(Key::KEY_MUHENKAN);
-        keys.insert(Key::KEY_KPJPCOMMA);
-        keys.insert(Key::KEY_KPENTER);
-        keys.insert(Key::KEY_RIGHTCTRL);
-        keys.insert(Key::KEY_KPSLASH);
-        keys.insert(Key::KEY_SYSRQ);
-        keys.insert(Key::KEY_RIGHTALT);
-        keys.insert(Key::KEY_LINEFEED);
-        keys.insert(Key::KEY_HOME);
-        keys.insert(Key::KEY_UP);
-        keys.insert(Key::KEY_PAGEUP);
-        keys.insert(Key::KEY_LEFT);
-        keys.insert(Key::KEY_RIGHT);
-        keys.insert(Key::KEY_END);
-        keys.insert(Key::KEY_DOWN);
-        keys.insert(Key::KEY_PAGEDOWN);
-        keys.insert(Key::KEY_INSERT);
-        keys.insert(Key::KEY_DELETE);
-        keys.insert(Key::KEY_MACRO);
-        keys.insert(Key::KEY_MUTE);
-        keys.insert(Key::KEY_VOLUMEDOWN);
-        keys.insert(Key::KEY_VOLUMEUP);
-        keys.insert(Key::KEY_POWER);
-        keys.insert(Key::KEY_KPEQUAL);
-        keys.insert(Key::KEY_KPPLUSMINUS);
-        keys.insert(Key::KEY_PAUSE);
-        keys.insert(Key::KEY_SCALE);
-        keys.insert(Key::KEY_KPCOMMA);
-        keys.insert(Key::KEY_HANGEUL);
-        keys.insert(Key::KEY_HANJA);
-        keys.insert(Key::KEY_YEN);
-        keys.insert(Key::KEY_LEFTMETA);
-        keys.insert(Key::KEY_RIGHTMETA);
-        keys.insert(Key::KEY_COMPOSE);
-        keys.insert(Key::KEY_STOP);
-        keys.insert(Key::KEY_AGAIN);
-        keys.insert(Key::KEY_PROPS);
-        keys.insert(Key::KEY_UNDO);
-        keys.insert(Key::KEY_FRONT);
-        keys.insert(Key::KEY_COPY);
-        keys.insert(Key::KEY_OPEN);
-        keys.insert(Key::KEY_PASTE);
-        keys.insert(Key::KEY_FIND);
-        keys.insert(Key::KEY_CUT);
-        keys.insert(Key::KEY_HELP);
-        keys.insert(Key::KEY_MENU);
-        keys.insert(Key::KEY_CALC);
-        keys.insert(Key::KEY_SETUP);
-        keys.insert(Key::KEY_SLEEP);
-        keys.insert(Key::KEY_WAKEUP);
-        keys.insert(Key::KEY_FILE);
-        keys.insert(Key::KEY_SENDFILE);
-        keys.insert(Key::KEY_DELETEFILE);
-        keys.insert(Key::KEY_XFER);
-        keys.insert(Key::KEY_PROG1);
-        keys.insert(Key::KEY_PROG2);
-        keys.insert(Key::KEY_WWW);
-        keys.insert(Key::KEY_MSDOS);
-        keys.insert(Key::KEY_COFFEE);
-        keys.insert(Key::KEY_DIRECTION);
-        keys.insert(Key::KEY_CYCLEWINDOWS);
-        keys.insert(Key::KEY_MAIL);
-        keys.insert(Key::KEY_BOOKMARKS);
-        keys.insert(Key::KEY_COMPUTER);
-        keys.insert(Key::KEY_BACK);
-        keys.insert(Key::KEY_FORWARD);
-        keys.insert(Key::KEY_CLOSECD);
-        keys.insert(Key::KEY_EJECTCD);
-        keys.insert(Key::KEY_EJECTCLOSECD);
-        keys.insert(Key::KEY_NEXTSONG);
-        keys.insert(Key::KEY_PLAYPAUSE);
-        keys.insert(Key::KEY_PREVIOUSSONG);
-        keys.insert(Key::KEY_STOPCD);
-        keys.insert(Key::KEY_RECORD);
-        keys.insert(Key::KEY_REWIND);
-        keys.insert(Key::KEY_PHONE);
-        keys.insert(Key::KEY_ISO);
-        keys.insert(Key::KEY_CONFIG);
-        keys.insert(Key::KEY_HOMEPAGE);
-        keys.insert(Key::KEY_REFRESH);
-        keys.insert(Key::KEY_EXIT);
-        keys.insert(Key::KEY_MOVE);
-        keys.insert(Key::KEY_EDIT);
-        keys.insert(Key::KEY_SCROLLUP);
-        keys.insert(Key::KEY_SCROLLDOWN);
-        keys.insert(Key::KEY_KPLEFTPAREN);
-        keys.insert(Key::KEY_KPRIGHTPAREN);
-        keys.insert(Key::KEY_NEW);
-        keys.insert(Key::KEY_REDO);
-        keys.insert(Key::KEY_F13);
-        keys.insert(Key::KEY_F14);
-        keys.insert(Key::KEY_F15);
-        keys.insert(Key::KEY_F16);
-        keys.insert(Key::KEY_F17);
-        keys.insert(Key::KEY_F18);
-        keys.insert(Key::KEY_F19);
-        keys.insert(Key::KEY_F20);
-        keys.insert(Key::KEY_F21);
-        keys.insert(Key::KEY_F22);
-        keys.insert(Key::KEY_F23);
-        keys.insert(Key::KEY_F24);
-        keys.insert(Key::KEY_PLAYCD);
-        keys.insert(Key::KEY_PAUSECD);
-        keys.insert(Key::KEY_PROG3);
-        keys.insert(Key::KEY_PROG4);
-        keys.insert(Key::KEY_DASHBOARD);
-        keys.insert(Key::KEY_SUSPEND);
-        keys.insert(Key::KEY_CLOSE);
-        keys.insert(Key::KEY_PLAY);
-        keys.insert(Key::KEY_FASTFORWARD);
-        keys.insert(Key::KEY_BASSBOOST);
-        keys.insert(Key::KEY_PRINT);
-        keys.insert(Key::KEY_HP);
-        keys.insert(Key::KEY_CAMERA);
-        keys.insert(Key::KEY_SOUND);
-        keys.insert(Key::KEY_QUESTION);
-        keys.insert(Key::KEY_EMAIL);
-        keys.insert(Key::KEY_CHAT);
-        keys.insert(Key::KEY_SEARCH);
-        keys.insert(Key::KEY_CONNECT);
-        keys.insert(Key::KEY_FINANCE);
-        keys.insert(Key::KEY_SPORT);
-        keys.insert(Key::KEY_SHOP);
-        keys.insert(Key::KEY_ALTERASE);
-        keys.insert(Key::KEY_CANCEL);
-        keys.insert(Key::KEY_BRIGHTNESSDOWN);
-        keys.insert(Key::KEY_BRIGHTNESSUP);
-        keys.insert(Key::KEY_MEDIA);
-        keys.insert(Key::KEY_SWITCHVIDEOMODE);
-        keys.insert(Key::KEY_KBDILLUMTOGGLE);
-        keys.insert(Key::KEY_KBDILLUMDOWN);
-        keys.insert(Key::KEY_KBDILLUMUP);
-        keys.insert(Key::KEY_SEND);
-        keys.insert(Key::KEY_REPLY);
-        keys.insert(Key::KEY_FORWARDMAIL);
-        keys.insert(Key::KEY_SAVE);
-        keys.insert(Key::KEY_DOCUMENTS);
-        keys.insert(Key::KEY_BATTERY);
-        keys.insert(Key::KEY_BLUETOOTH);
-        keys.insert(Key::KEY_WLAN);
-        keys.insert(Key::KEY_UWB);
-        keys.insert(Key::KEY_UNKNOWN);
-        keys.insert(Key::KEY_VIDEO_NEXT);
-        keys.insert(Key::KEY_VIDEO_PREV);
-        keys.insert(Key::KEY_BRIGHTNESS_CYCLE);
-        keys.insert(Key::KEY_BRIGHTNESS_AUTO);
-        keys.insert(Key::KEY_DISPLAY_OFF);
-        keys.insert(Key::KEY_WWAN);
-        keys.insert(Key::KEY_RFKILL);
-        keys.insert(Key::KEY_MICMUTE);
-        keys.insert(Key::BTN_0);
-        keys.insert(Key::BTN_1);
-        keys.insert(Key::BTN_2);
-        keys.insert(Key::BTN_3);
-        keys.insert(Key::BTN_4);
-        keys.insert(Key::BTN_5);
-        keys.insert(Key::BTN_6);
-        keys.insert(Key::BTN_7);
-        keys.insert(Key::BTN_8);
-        keys.insert(Key::BTN_9);
-        keys.insert(Key::BTN_LEFT);
-        keys.insert(Key::BTN_RIGHT);
-        keys.insert(Key::BTN_MIDDLE);
-        keys.insert(Key::BTN_SIDE);
-        keys.insert(Key::BTN_EXTRA);
-        keys.insert(Key::BTN_FORWARD);
-        keys.insert(Key::BTN_BACK);
-        keys.insert(Key::BTN_TASK);
-        keys.insert(Key::BTN_TRIGGER);
-        keys.insert(Key::BTN_THUMB);
-        keys.insert(Key::BTN_THUMB2);
-        keys.insert(Key::BTN_TOP);
-        keys.insert(Key::BTN_TOP2);
-        keys.insert(Key::BTN_PINKIE);
-        keys.insert(Key::BTN_BASE);
-        keys.insert(Key::BTN_BASE2);
-        keys.insert(Key::BTN_BASE3);
-        keys.insert(Key::BTN_BASE4);
-        keys.insert(Key::BTN_BASE5);
-        keys.insert(Key::BTN_BASE6);
-        keys.insert(Key::BTN_DEAD);
-        keys.insert(Key::BTN_SOUTH);
-        keys.insert(Key::BTN_EAST);
-        keys.insert(Key::BTN_C);
-        keys.insert(Key::BTN_NORTH);
-        keys.insert(Key::BTN_WEST);
-        keys.insert(Key::BTN_Z);
-        keys.insert(Key::BTN_TL);
-        keys.insert(Key::BTN_TR);
-        keys.insert(Key::BTN_TL2);
-        keys.insert(Key::BTN_TR2);
-        keys.insert(Key::BTN_SELECT);
-        keys.insert(Key::BTN_START);
-        keys.insert(Key::BTN_MODE);
-        keys.insert(Key::BTN_THUMBL);
-        keys.insert(Key::BTN_THUMBR);
-        keys.insert(Key::BTN_TOOL_PEN);
-        keys.insert(Key::BTN_TOOL_RUBBER);
-        keys.insert(Key::BTN_TOOL_BRUSH);
-        keys.insert(Key::BTN_TOOL_PENCIL);
-        keys.insert(Key::BTN_TOOL_AIRBRUSH);
-        keys.insert(Key::BTN_TOOL_FINGER);
-        keys.insert(Key::BTN_TOOL_MOUSE);
-        keys.insert(Key::BTN_TOOL_LENS);
-        keys.insert(Key::BTN_TOOL_QUINTTAP);
-        keys.insert(Key::BTN_TOUCH);
-        keys.insert(Key::BTN_STYLUS);
-        keys.insert(Key::BTN_STYLUS2);
-        keys.insert(Key::BTN_TOOL_DOUBLETAP);
-        keys.insert(Key::BTN_TOOL_TRIPLETAP);
-        keys.insert(Key::BTN_TOOL_QUADTAP);
-        keys.insert(Key::BTN_GEAR_DOWN);
-        keys.insert(Key::BTN_GEAR_UP);
-        keys.insert(Key::KEY_OK);
-        keys.insert(Key::KEY_SELECT);
-        keys.insert(Key::KEY_GOTO);
-        keys.insert(Key::KEY_CLEAR);
-        keys.insert(Key::KEY_POWER2);
-        keys.insert(Key::KEY_OPTION);
-        keys.insert(Key::KEY_INFO);
-        keys.insert(Key::KEY_TIME);
-        keys.insert(Key::KEY_VENDOR);
-        keys.insert(Key::KEY_ARCHIVE);
-        keys.insert(Key::KEY_PROGRAM);
-        keys.insert(Key::KEY_CHANNEL);
-        keys.insert(Key::KEY_FAVORITES);
-        keys.insert(Key::KEY_EPG);
-        keys.insert(Key::KEY_PVR);
-        keys.insert(Key::KEY_MHP);
-        keys.insert(Key::KEY_LANGUAGE);
-        keys.insert(Key::KEY_TITLE);
-        keys.insert(Key::KEY_SUBTITLE);
-        keys.insert(Key::KEY_ANGLE);
-        keys.insert(Key::KEY_ZOOM);
-        keys.insert(Key::KEY_MODE);
-        keys.insert(Key::KEY_KEYBOARD);
-        keys.insert(Key::KEY_SCREEN);
-        keys.insert(Key::KEY_PC);
-        keys.insert(Key::KEY_TV);
-        keys.insert(Key::KEY_TV2);
-        keys.insert(Key::KEY_VCR);
-        keys.insert(Key::KEY_VCR2);
-        keys.insert(Key::KEY_SAT);
-        keys.insert(Key::KEY_SAT2);
-        keys.insert(Key::KEY_CD);
-        keys.insert(Key::KEY_TAPE);
-        keys.insert(Key::KEY_RADIO);
-        keys.insert(Key::KEY_TUNER);
-        keys.insert(Key::KEY_PLAYER);
-        keys.insert(Key::KEY_TEXT);
-        keys.insert(Key::KEY_DVD);
-        keys.insert(Key::KEY_AUX);
-        keys.insert(Key::KEY_MP3);
-        keys.insert(Key::KEY_AUDIO);
-        keys.insert(Key::KEY_VIDEO);
-        keys.insert(Key::KEY_DIRECTORY);
-        keys.insert(Key::KEY_LIST);
-        keys.insert(Key::KEY_MEMO);
-        keys.insert(Key::KEY_CALENDAR);
-        keys.insert(Key::KEY_RED);
-        keys.insert(Key::KEY_GREEN);
-        keys.insert(Key::KEY_YELLOW);
-        keys.insert(Key::KEY_BLUE);
-        keys.insert(Key::KEY_CHANNELUP);
-        keys.insert(Key::KEY_CHANNELDOWN);
-        keys.insert(Key::KEY_FIRST);
-        keys.insert(Key::KEY_LAST);
-        keys.insert(Key::KEY_AB);
-        keys.insert(Key::KEY_NEXT);
-        keys.insert(Key::KEY_RESTART);
-        keys.insert(Key::KEY_SLOW);
-        keys.insert(Key::KEY_SHUFFLE);
-        keys.insert(Key::KEY_BREAK);
-        keys.insert(Key::KEY_PREVIOUS);
-        keys.insert(Key::KEY_DIGITS);
-        keys.insert(Key::KEY_TEEN);
-        keys.insert(Key::KEY_TWEN);
-        keys.insert(Key::KEY_VIDEOPHONE);
-        keys.insert(Key::KEY_GAMES);
-        keys.insert(Key::KEY_ZOOMIN);
-        keys.insert(Key::KEY_ZOOMOUT);
-        keys.insert(Key::KEY_ZOOMRESET);
-        keys.insert(Key::KEY_WORDPROCESSOR);
-        keys.insert(Key::KEY_EDITOR);
-        keys.insert(Key::KEY_SPREADSHEET);
-        keys.insert(Key::KEY_GRAPHICSEDITOR);
-        keys.insert(Key::KEY_PRESENTATION);
-        keys.insert(Key::KEY_DATABASE);
-        keys.insert(Key::KEY_NEWS);
-        keys.insert(Key::KEY_VOICEMAIL);
-        keys.insert(Key::KEY_ADDRESSBOOK);
-        keys.insert(Key::KEY_MESSENGER);
-        keys.insert(Key::KEY_DISPLAYTOGGLE);
-        keys.insert(Key::KEY_SPELLCHECK);
-        keys.insert(Key::KEY_LOGOFF);
-        keys.insert(Key::KEY_DOLLAR);
-        keys.insert(Key::KEY_EURO);
-        keys.insert(Key::KEY_FRAMEBACK);
-        keys.insert(Key::KEY_FRAMEFORWARD);
-        keys.insert(Key::KEY_CONTEXT_MENU);
-        keys.insert(Key::KEY_MEDIA_REPEAT);
-        keys.insert(Key::KEY_10CHANNELSUP);
-        keys.insert(Key::KEY_10CHANNELSDOWN);
-        keys.insert(Key::KEY_IMAGES);
-        keys.insert(Key::KEY_DEL_EOL);
-        keys.insert(Key::KEY_DEL_EOS);
-        keys.insert(Key::KEY_INS_LINE);
-        keys.insert(Key::KEY_DEL_LINE);
-        keys.insert(Key::KEY_FN);
-        keys.insert(Key::KEY_FN_ESC);
-        keys.insert(Key::KEY_FN_F1);
-        keys.insert(Key::KEY_FN_F2);
-        keys.insert(Key::KEY_FN_F3);
-        keys.insert(Key::KEY_FN_F4);
-        keys.insert(Key::KEY_FN_F5);
-        keys.insert(Key::KEY_FN_F6);
-        keys.insert(Key::KEY_FN_F7);
-        keys.insert(Key::KEY_FN_F8);
-        keys.insert(Key::KEY_FN_F9);
-        keys.insert(Key::KEY_FN_F10);
-        keys.insert(Key::KEY_FN_F11);
-        keys.insert(Key::KEY_FN_F12);
-        keys.insert(Key::KEY_FN_1);
-        keys.insert(Key::KEY_FN_2);
-        keys.insert(Key::KEY_FN_D);
-        keys.insert(Key::KEY_FN_E);
-        keys.insert(Key::KEY_FN_F);
-        keys.insert(Key::KEY_FN_S);
-        keys.insert(Key::KEY_FN_B);
-        keys.insert(Key::KEY_BRL_DOT1);
-        keys.insert(Key::KEY_BRL_DOT2);
-        keys.insert(Key::KEY_BRL_DOT3);
-        keys.insert(Key::KEY_BRL_DOT4);
-        keys.insert(Key::KEY_BRL_DOT5);
-        keys.insert(Key::KEY_BRL_DOT6);
-        keys.insert(Key::KEY_BRL_DOT7);
-        keys.insert(Key::KEY_BRL_DOT8);
-        keys.insert(Key::KEY_BRL_DOT9);
-        keys.insert(Key::KEY_BRL_DOT10);
-        keys.insert(Key::KEY_NUMERIC_0);
-        keys.insert(Key::KEY_NUMERIC_1);
-        keys.insert(Key::KEY_NUMERIC_2);
-        keys.insert(Key::KEY_NUMERIC_3);
-        keys.insert(Key::KEY_NUMERIC_4);
-        keys.insert(Key::KEY_NUMERIC_5);
-        keys.insert(Key::KEY_NUMERIC_6);
-        keys.insert(Key::KEY_NUMERIC_7);
-        keys.insert(Key::KEY_NUMERIC_8);
-        keys.insert(Key::KEY_NUMERIC_9);
-        keys.insert(Key::KEY_NUMERIC_STAR);
-        keys.insert(Key::KEY_NUMERIC_POUND);
-        keys.insert(Key::KEY_CAMERA_FOCUS);
-        keys.insert(Key::KEY_WPS_BUTTON);
-        keys.insert(Key::KEY_TOUCHPAD_TOGGLE);
-        keys.insert(Key::KEY_TOUCHPAD_ON);
-        keys.insert(Key::KEY_TOUCHPAD_OFF);
-        keys.insert(Key::KEY_CAMERA_ZOOMIN);
-        keys.insert(Key::KEY_CAMERA_ZOOMOUT);
-        keys.insert(Key::KEY_CAMERA_UP);
-        keys.insert(Key::KEY_CAMERA_DOWN);
-        keys.insert(Key::KEY_CAMERA_LEFT);
-        keys.insert(Key::KEY_CAMERA_RIGHT);
-        keys.insert(Key::KEY_ATTENDANT_ON);
-        keys.insert(Key::KEY_ATTENDANT_OFF);
-        keys.insert(Key::KEY_ATTENDANT_TOGGLE);
-        keys.insert(Key::KEY_LIGHTS_TOGGLE);
-        keys.insert(Key::BTN_DPAD_UP);
-        keys.insert(Key::BTN_DPAD_DOWN);
-        keys.insert(Key::BTN_DPAD_LEFT);
-        keys.insert(Key::BTN_DPAD_RIGHT);
-        keys.insert(Key::KEY_ALS_TOGGLE);
-        keys.insert(Key::KEY_BUTTONCONFIG);
-        keys.insert(Key::KEY_TASKMANAGER);
-        keys.insert(Key::KEY_JOURNAL);
-        keys.insert(Key::KEY_CONTROLPANEL);
-        keys.insert(Key::KEY_APPSELECT);
-        keys.insert(Key::KEY_SCREENSAVER);
-        keys.insert(Key::KEY_VOICECOMMAND);
-        keys.insert(Key::KEY_BRIGHTNESS_MIN);
-        keys.insert(Key::KEY_BRIGHTNESS_MAX);
-        keys.insert(Key::KEY_KBDINPUTASSIST_PREV);
-        keys.insert(Key::KEY_KBDINPUTASSIST_NEXT);
-        keys.insert(Key::KEY_KBDINPUTASSIST_PREVGROUP);
-        keys.insert(Key::KEY_KBDINPUTASSIST_NEXTGROUP);
-        keys.insert(Key::KEY_KBDINPUTASSIST_ACCEPT);
-        keys.insert(Key::KEY_KBDINPUTASSIST_CANCEL);
-        keys.insert(Key::BTN_TRIGGER_HAPPY1);
-        keys.insert(Key::BTN_TRIGGER_HAPPY2);
-        keys.insert(Key::BTN_TRIGGER_HAPPY3);
-        keys.insert(Key::BTN_TRIGGER_HAPPY4);
-        keys.insert(Key::BTN_TRIGGER_HAPPY5);
-        keys.insert(Key::BTN_TRIGGER_HAPPY6);
-        keys.insert(Key::BTN_TRIGGER_HAPPY7);
-        keys.insert(Key::BTN_TRIGGER_HAPPY8);
-        keys.insert(Key::BTN_TRIGGER_HAPPY9);
-        keys.insert(Key::BTN_TRIGGER_HAPPY10);
-        keys.insert(Key::BTN_TRIGGER_HAPPY11);
-        keys.insert(Key::BTN_TRIGGER_HAPPY12);
-        keys.insert(Key::BTN_TRIGGER_HAPPY13);
-        keys.insert(Key::BTN_TRIGGER_HAPPY14);
-        keys.insert(Key::BTN_TRIGGER_HAPPY15);
-        keys.insert(Key::BTN_TRIGGER_HAPPY16);
-        keys.insert(Key::BTN_TRIGGER_HAPPY17);
-        keys.insert(Key::BTN_TRIGGER_HAPPY18);
-        keys.insert(Key::BTN_TRIGGER_HAPPY19);
-        keys.insert(Key::BTN_TRIGGER_HAPPY20);
-        keys.insert(Key::BTN_TRIGGER_HAPPY21);
-        keys.insert(Key::BTN_TRIGGER_HAPPY22);
-        keys.insert(Key::BTN_TRIGGER_HAPPY23);
-        keys.insert(Key::BTN_TRIGGER_HAPPY24);
-        keys.insert(Key::BTN_TRIGGER_HAPPY25);
-        keys.insert(Key::BTN_TRIGGER_HAPPY26);
-        keys.insert(Key::BTN_TRIGGER_HAPPY27);
-        keys.insert(Key::BTN_TRIGGER_HAPPY28);
-        keys.insert(Key::BTN_TRIGGER_HAPPY29);
-        keys.insert(Key::BTN_TRIGGER_HAPPY30);
-        keys.insert(Key::BTN_TRIGGER_HAPPY31);
-        keys.insert(Key::BTN_TRIGGER_HAPPY32);
-        keys.insert(Key::BTN_TRIGGER_HAPPY33);
-        keys.insert(Key::BTN_TRIGGER_HAPPY34);
-        keys.insert(Key::BTN_TRIGGER_HAPPY35);
-        keys.insert(Key::BTN_TRIGGER_HAPPY36);
-        keys.insert(Key::BTN_TRIGGER_HAPPY37);
-        keys.insert(Key::BTN_TRIGGER_HAPPY38);
-        keys.insert(Key::BTN_TRIGGER_HAPPY39);
-        keys.insert(Key::BTN_TRIGGER_HAPPY40);
-    }
-
-    let mut rel_axes = AttributeSet::<RelativeAxisType>::new();
-    {
-        rel_axes.insert(RelativeAxisType::REL_X);
-        rel_axes.insert(RelativeAxisType::REL_Y);
-        rel_axes.insert(RelativeAxisType::REL_Z);
-        rel_axes.insert(RelativeAxisType::REL_RX);
-        rel_axes.insert(RelativeAxisType::REL_RY);
-        rel_axes.insert(RelativeAxisType::REL_RZ);
-        rel_axes.insert(RelativeAxisType::REL_HWHEEL);
-        rel_axes.insert(RelativeAxisType::REL_DIAL);
-        rel_axes.insert(RelativeAxisType::REL_WHEEL);
-        rel_axes.insert(RelativeAxisType::REL_MISC);
-        rel_axes.insert(RelativeAxisType::REL_RESERVED);
-        rel_axes.insert(RelativeAxisType::REL_WHEEL_HI_RES);
-        rel_axes.insert(RelativeAxisType::REL_HWHEEL_HI_RES);
-    }
-
-    let device = VirtualDeviceBuilder::new()?
-        .name("NHK")
-        .with_keys(&keys)?
-        .with_relative_axes(&rel_axes)?
-        .build()
-        .unwrap();
-
-    return Ok((
-        dev,
-        device,
-    ));
-}
-
-fn next_event(dev: &mut Device) -> Result<InInputEvent, std::io::Error> {
-    return dev.next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING).map(|val| val.1);
+pub type SourceId = usize;
+
+pub type MultiEventHandler = Box<dyn Fn(SourceId, InInputEvent, &Sender<OutInputEvent>) -> bool>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Output {
+    Shared,
+    PerSource,
+}
+
+impl Default for Output {
+    fn default() -> Output {
+        return Output::Shared;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub capabilities: Capabilities,
+    pub output: Output,
+    pub hotplug: bool,
+    pub tick: Option<Duration>,
+    pub escape: Option<EscapeChord>,
+    pub raw: bool,
+    pub event_loop: EventLoop,
+    pub latency: Option<Latency>,
+    pub trace: Trace,
+    pub control: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        return Options {
+            capabilities: Capabilities::default(),
+            output: Output::default(),
+            hotplug: false,
+            tick: None,
+            escape: Some(EscapeChord::default()),
+            raw: false,
+            event_loop: EventLoop::default(),
+            latency: None,
+            trace: Trace::default(),
+            control: None,
+        };
+    }
+}
+
+type Writer = JoinHandle<Result<(), NhkError>>;
+
+struct Source<S: EventSource = Device> {
+    info: DeviceInfo,
+    identity: Identity,
+    dev: Option<S>,
+    held: Vec<EventCode>,
+}
+
+impl Source {
+    fn new(path: String, dev: Device) -> Source {
+        return Source {
+            info: DeviceInfo::new(&path, &dev),
+            identity: Identity::of(&dev),
+            dev: Some(dev),
+            held: Vec::new(),
+        };
+    }
+}
+
+impl<S: EventSource> Source<S> {
+    fn with_info(info: DeviceInfo, dev: S) -> Source<S> {
+        return Source { info, identity: Identity::default(), dev: Some(dev), held: Vec::new() };
+    }
+}
+
+impl<S: EventSource> Drop for Source<S> {
+    fn drop(&mut self) {
+        if let Some(dev) = &mut self.dev {
+            dev.grab(GrabMode::Ungrab).ok();
+            log::debug!("device released path={:?}", self.info.path);
+        }
+    }
+}
+
+fn track_held(held: &mut Vec<EventCode>, ev: &InInputEvent) {
+    if let EventCode::EV_KEY(_) = ev.event_code {
+        held.retain(|code| *code != ev.event_code);
+        if ev.value != 0 {
+            held.push(ev.event_code);
+        }
+    }
+}
+
+// The read loop runs on the system clock, the mock on one of its own.
+#[derive(Clone, Copy, Debug)]
+enum Clock {
+    System,
+    Fixed(Instant, SystemTime),
+}
+
+struct Outputs<O: OutputSink = OutputDevice> {
+    txs: Vec<Sender<OutInputEvent>>,
+    writers: Vec<Writer>,
+    inline: Vec<(Receiver<OutInputEvent>, Emitter<O>)>,
+    clock: Clock,
+    trace: Trace,
+    raw: bool,
+    event_loop: EventLoop,
+    latency: Option<Latency>,
+    // The oldest input event read since the last flush.
+    pending: Option<SystemTime>,
+}
+
+impl<O: OutputSink> Outputs<O> {
+    fn new(options: &Options) -> Outputs<O> {
+        return Outputs {
+            txs: Vec::new(),
+            writers: Vec::new(),
+            inline: Vec::new(),
+            clock: Clock::System,
+            trace: options.trace,
+            raw: options.raw,
+            event_loop: options.event_loop,
+            latency: options.latency.clone(),
+            pending: None,
+        };
+    }
+
+    fn add(&mut self, uinput: O) {
+        let (tx, rx): (Sender<OutInputEvent>, Receiver<OutInputEvent>) = mpsc::channel();
+        self.txs.push(tx);
+
+        let emitter = Emitter::new(uinput, self.trace, self.raw, self.latency.clone());
+        match self.event_loop {
+            EventLoop::Threaded => self.writers.push(write_loop(emitter, rx)),
+            EventLoop::Single => self.inline.push((rx, emitter)),
+        }
+    }
+
+    fn received(&mut self, ev: &InInputEvent) -> Option<SystemTime> {
+        let latency = self.latency.as_ref()?;
+        let time: SystemTime = ev.time.try_into().ok()?;
+
+        latency.record_since(Stage::Read, time);
+        self.pending = Some(self.pending.map_or(time, |pending| pending.min(time)));
+        return Some(time);
+    }
+
+    fn handled(&self, time: Option<SystemTime>) {
+        if let (Some(latency), Some(time)) = (&self.latency, time) {
+            latency.record_since(Stage::Handled, time);
+        }
+    }
+
+    // Ends the current frames. Without writer threads this is also where
+    // everything the handler queued up gets written out.
+    fn flush(&mut self) -> Result<(), NhkError> {
+        let since = self.pending.take();
+        for tx in &self.txs {
+            tx.send(uinput::flush_marker(since)).ok();
+        }
+
+        for (rx, emitter) in &mut self.inline {
+            for ev in rx.try_iter() {
+                emitter.handle(ev)?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn now(&self) -> Instant {
+        return match self.clock {
+            Clock::System => Instant::now(),
+            Clock::Fixed(now, _) => now,
+        };
+    }
+
+    fn wall(&self) -> SystemTime {
+        return match self.clock {
+            Clock::System => SystemTime::now(),
+            Clock::Fixed(_, wall) => wall,
+        };
+    }
+
+    fn context<'a>(&'a self, source: SourceId, timers: &'a mut Timers) -> Context<'a> {
+        return Context::new(source, self.now(), self.wall(), &self.txs, timers);
+    }
+
+    fn any_finished(&self) -> bool {
+        return self.writers.iter().any(|writer| writer.is_finished());
+    }
+
+    fn finish_inline(&mut self) -> Result<(), NhkError> {
+        let mut result = Ok(());
+        for (rx, mut emitter) in self.inline.drain(..) {
+            result = result.and(rx.try_iter().try_for_each(|ev| emitter.handle(ev)).and(emitter.finish()));
+        }
+
+        return result;
+    }
+
+    fn join(mut self) -> Result<(), NhkError> {
+        self.txs.clear();
+
+        let mut result = self.finish_inline();
+        for writer in self.writers.drain(..) {
+            result = result.and(writer.join().expect("panic!"));
+        }
+
+        return result;
+    }
+}
+
+impl<O: OutputSink> Drop for Outputs<O> {
+    // Also runs while unwinding out of a panicking handler, so the writers
+    // still get to lift every held key before the process goes away.
+    fn drop(&mut self) {
+        self.txs.clear();
+        self.finish_inline().ok();
+        for writer in self.writers.drain(..) {
+            writer.join().ok();
+        }
+    }
+}
+
+struct Guards {
+    signals: Option<Signals>,
+    escape: Option<Escape>,
+    control: Option<ControlSocket>,
+    // Devices stay open but ungrabbed while paused from the control socket.
+    paused: bool,
+}
+
+fn grab<S: EventSource>(id: SourceId, info: &DeviceInfo, dev: &mut S, guards: &Guards) -> Result<(), NhkError> {
+    dev.grab(GrabMode::Grab).map_err(NhkError::Grab)?;
+    if let Some(escape) = &guards.escape {
+        escape.add(id, dev.fd()).map_err(NhkError::Grab)?;
+    }
+
+    log::info!("device grabbed source={} path={:?} name={:?}", id, info.path, info.name);
+    return Ok(());
+}
+
+enum Flow {
+    Continue,
+    Exit(Control),
+    Removed,
+}
+
+fn flow(control: Control) -> Flow {
+    return match control {
+        Control::Continue => Flow::Continue,
+        control => Flow::Exit(control),
+    };
+}
+
+fn dev_from_file(file_name: &str) -> Result<Device, NhkError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(OFlag::O_NONBLOCK.bits())
+        .open(file_name)
+        .map_err(|e| NhkError::device_open(file_name, e))?;
+
+    return Device::new_from_file(file).map_err(|e| NhkError::device_open(file_name, e));
+}
+
+fn uinputs_for(sources: &[Source], options: &Options) -> Result<Vec<OutputDevice>, NhkError> {
+    let devs: Vec<&Device> = sources.iter().filter_map(|source| source.dev.as_ref()).collect();
+
+    return match options.output {
+        Output::Shared => Ok(vec![uinput::create(options.capabilities, &devs)?]),
+        Output::PerSource => devs.iter().map(|dev| uinput::create(options.capabilities, &[dev])).collect(),
+    };
+}
+
+pub(crate) fn key_code(ev: &InInputEvent) -> Option<u16> {
+    return match ev.event_code {
+        EventCode::EV_KEY(_) => Some(ev.as_raw().code),
+        _ => None,
+    };
+}
+
+pub(crate) fn key_input(time: &TimeVal, code: u16, value: i32) -> InInputEvent {
+    return InInputEvent::new(time, &evdev_rs::util::int_to_event_code(EventType::KEY.0 as u32, code as u32), value);
+}
+
+pub(crate) fn syn_input(time: &TimeVal) -> InInputEvent {
+    return InInputEvent::new(time, &EventCode::EV_SYN(EV_SYN::SYN_REPORT), 0);
 }
 
 pub fn passthrough_ev(ev: InInputEvent, tx: &Sender<OutInputEvent>) {
-    tx.send(OutInputEvent::from(ev.as_raw())).unwrap();
+    tx.send(OutInputEvent::from(ev.as_raw())).ok();
 }
 
 pub fn send_key(tx: &Sender<OutInputEvent>, key: Key, value: i32) {
-    tx.send(OutInputEvent::new_now(EventType::KEY, key.code(), value)).unwrap();
+    tx.send(OutInputEvent::new_now(EventType::KEY, key.code(), value)).ok();
 }
 
 pub fn send_syn(tx: &Sender<OutInputEvent>) {
-    tx.send(OutInputEvent::new_now(EventType::SYNCHRONIZATION, 0, 0)).unwrap();
+    tx.send(OutInputEvent::new_now(EventType::SYNCHRONIZATION, 0, 0)).ok();
 }
 
+#[deprecated(note = "blocks the read loop, use Context::emit_after or Context::call_after instead")]
 pub fn sleep(duration: u64)  {
     thread::sleep(time::Duration::from_millis(duration));
 }
 
-fn read_loop(dev: &mut Device, tx: Sender<OutInputEvent>, event_handler: EventHandler) {
-    dev.grab(GrabMode::Grab).unwrap();
+struct Emitter<O: OutputSink> {
+    uinput: O,
+    framer: Framer,
+    pressed: Vec<u16>,
+    trace: Trace,
+    latency: Option<Latency>,
+    wrote: bool,
+}
+
+impl<O: OutputSink> Emitter<O> {
+    fn new(uinput: O, trace: Trace, raw: bool, latency: Option<Latency>) -> Emitter<O> {
+        return Emitter { uinput, framer: Framer::new(raw), pressed: Vec::new(), trace, latency, wrote: false };
+    }
+
+    fn write(&mut self, frame: &[OutInputEvent]) -> Result<(), NhkError> {
+        self.uinput.write(frame).map_err(NhkError::Emit)?;
+        self.wrote = true;
+        return Ok(());
+    }
+
+    fn handle(&mut self, ev: OutInputEvent) -> Result<(), NhkError> {
+        if uinput::is_flush_marker(&ev) {
+            if let Some(frame) = self.framer.flush() {
+                self.write(&frame)?;
+            }
+            if let (true, Some(latency), Some(since)) = (self.wrote, &self.latency, uinput::flush_marker_time(&ev)) {
+                latency.record_since(Stage::Emitted, since);
+            }
+            self.wrote = false;
+            return Ok(());
+        }
+
+        trace::output(self.trace, &ev);
+
+        if ev.event_type() == EventType::KEY {
+            self.pressed.retain(|&code| code != ev.code());
+            if ev.value() != 0 {
+                self.pressed.push(ev.code());
+            }
+        }
+
+        if let Some(frame) = self.framer.push(ev) {
+            self.write(&frame)?;
+        }
+
+        return Ok(());
+    }
+
+    // Nothing will release these anymore.
+    fn finish(&mut self) -> Result<(), NhkError> {
+        let mut frame = self.framer.flush().unwrap_or_default();
+        if !self.pressed.is_empty() {
+            frame.extend(self.pressed.drain(..).map(|code| OutInputEvent::new_now(EventType::KEY, code, 0)));
+            frame.push(uinput::syn_report());
+        }
+        if !frame.is_empty() {
+            self.uinput.write(&frame).map_err(NhkError::Emit)?;
+        }
+
+        return Ok(());
+    }
+}
+
+fn write_loop<O: OutputSink>(mut emitter: Emitter<O>, rx: Receiver<OutInputEvent>) -> Writer {
+    return thread::spawn(move || {
+        while let Ok(ev) = rx.recv() {
+            emitter.handle(ev)?;
+        }
+
+        return emitter.finish();
+    });
+}
+
+fn read_source<S: EventSource, O: OutputSink>(id: SourceId, source: &mut Source<S>, outputs: &mut Outputs<O>, timers: &mut Timers, handler: &mut dyn Handler) -> Flow {
+    let dev = match &mut source.dev {
+        Some(dev) => dev,
+        None => return Flow::Continue,
+    };
 
     loop {
-        let ev = next_event(dev);
-        match ev {
-            Ok(ev) => if event_handler(ev, &tx) { break; },
-            Err(_e) => (),
+        match dev.next_event() {
+            Ok(ev) => {
+                let time = outputs.received(&ev);
+                trace::input(outputs.trace, id, &ev);
+                track_held(&mut source.held, &ev);
+                let flow = flow(handler.on_event(ev, &mut outputs.context(id, timers)));
+                outputs.handled(time);
+
+                if let Flow::Exit(control) = flow {
+                    return Flow::Exit(control);
+                }
+            },
+            Err(e) if e.raw_os_error() == Some(Errno::ENODEV as i32) => return Flow::Removed,
+            Err(_e) => return Flow::Continue,
+        }
+    }
+}
+
+// Feeds releases for every key still down on a lost device through the
+// handler, so whatever it mapped them to gets released as well.
+fn release_held<S: EventSource, O: OutputSink>(id: SourceId, source: &mut Source<S>, outputs: &Outputs<O>, timers: &mut Timers, handler: &mut dyn Handler) -> Flow {
+    let time = TimeVal::try_from(outputs.wall()).unwrap_or_else(|_| TimeVal::new(0, 0));
+
+    for code in source.held.drain(..).collect::<Vec<EventCode>>() {
+        if let Flow::Exit(control) = flow(handler.on_event(InInputEvent::new(&time, &code, 0), &mut outputs.context(id, timers))) {
+            return Flow::Exit(control);
         }
     }
+
+    return flow(handler.on_event(syn_input(&time), &mut outputs.context(id, timers)));
 }
 
-pub fn run(dev_path: String, event_handler: EventHandler) {
-    let debug = match env::var("DEBUG") {
-        Ok(val) => val == "1",
-        Err(_) => false,
+// Keeps reading a device while it isn't grabbed, so its queue doesn't fill
+// up, without feeding anything to the handler.
+fn discard<S: EventSource>(source: &mut Source<S>) -> Flow {
+    let dev = match &mut source.dev {
+        Some(dev) => dev,
+        None => return Flow::Continue,
     };
 
-    let (mut dev, mut uinput) = dev_uinput_from_file(dev_path).unwrap();
+    loop {
+        match dev.next_event() {
+            Ok(_ev) => (),
+            Err(e) if e.raw_os_error() == Some(Errno::ENODEV as i32) => return Flow::Removed,
+            Err(_e) => return Flow::Continue,
+        }
+    }
+}
 
-    let (tx, rx): (Sender<OutInputEvent>, Receiver<OutInputEvent>) = mpsc::channel();
+fn pause<S: EventSource, O: OutputSink>(sources: &mut [Source<S>], outputs: &Outputs<O>, timers: &mut Timers, handler: &mut dyn Handler) -> Flow {
+    for (id, source) in sources.iter_mut().enumerate() {
+        if source.dev.is_none() {
+            continue;
+        }
 
-    let write_loop_thread = thread::spawn(move || {
-        while let Ok(ev) = rx.recv() {
-            if debug { println!("{:?}", ev); }
-            
-            uinput.emit(&[ev]).unwrap();
+        if let Flow::Exit(control) = release_held(id, source, outputs, timers, handler) {
+            return Flow::Exit(control);
         }
-    });
+        if let Some(dev) = &mut source.dev {
+            dev.grab(GrabMode::Ungrab).ok();
+        }
+    }
+
+    log::info!("paused, devices ungrabbed");
+    return Flow::Continue;
+}
+
+fn resume<S: EventSource>(sources: &mut [Source<S>], guards: &Guards) -> Result<(), String> {
+    for id in 0..sources.len() {
+        let source = &mut sources[id];
+        let dev = match &mut source.dev {
+            Some(dev) => dev,
+            None => continue,
+        };
+
+        // All or nothing, a device that is read while not grabbed would
+        // type twice.
+        if let Err(e) = grab(id, &source.info, dev, guards) {
+            for source in &mut sources[..id] {
+                if let Some(dev) = &mut source.dev {
+                    dev.grab(GrabMode::Ungrab).ok();
+                }
+            }
+            return Err(e.to_string());
+        }
+    }
+
+    log::info!("resumed");
+    return Ok(());
+}
+
+fn inject<O: OutputSink>(key: Key, values: &[i32], outputs: &Outputs<O>, timers: &mut Timers) -> Reply {
+    let ctx = outputs.context(0, timers);
+    let tx = ctx.tx().ok_or_else(|| "no output device".to_string())?;
+    for &value in values {
+        send_key(tx, key, value);
+        send_syn(tx);
+    }
+
+    return Ok(Vec::new());
+}
+
+fn execute<S: EventSource, O: OutputSink>(command: &[&str], sources: &mut [Source<S>], outputs: &Outputs<O>, timers: &mut Timers, handler: &mut dyn Handler, guards: &mut Guards, options: &Options) -> (Reply, Flow) {
+    let reply = match command {
+        ["status"] => {
+            let mut lines = vec![
+                format!("state={}", if guards.paused { "paused" } else { "running" }),
+                format!("event-loop={}", format!("{:?}", options.event_loop).to_lowercase()),
+                format!("devices={}", sources.iter().filter(|source| source.dev.is_some()).count()),
+            ];
+            if let Some(Ok(status)) = handler.on_command(command, &mut outputs.context(0, timers)) {
+                lines.extend(status);
+            }
+            if let Some(latency) = &options.latency {
+                let stats = latency.snapshot();
+                for (stage, histogram) in [("read", &stats.read), ("handled", &stats.handled), ("emitted", &stats.emitted)] {
+                    lines.push(format!("latency.{}=p50:{:.1?} p99:{:.1?} max:{:.1?} n:{}", stage, histogram.p50(), histogram.p99(), histogram.max(), histogram.count()));
+                }
+            }
+            Ok(lines)
+        },
+        ["devices"] => Ok(sources.iter().enumerate().map(|(id, source)| {
+            let state = match (&source.dev, guards.paused) {
+                (None, _) => "lost",
+                (Some(_), true) => "paused",
+                (Some(_), false) => "grabbed",
+            };
+            format!("{}\t{}\t{}\t{}", id, state, source.info.path, source.info.name)
+        }).collect()),
+        ["pause"] => {
+            if !guards.paused {
+                guards.paused = true;
+                if let Flow::Exit(control) = pause(sources, outputs, timers, handler) {
+                    return (Ok(Vec::new()), Flow::Exit(control));
+                }
+            }
+            Ok(Vec::new())
+        },
+        ["resume"] => match guards.paused {
+            true => resume(sources, guards).map(|_| {
+                guards.paused = false;
+                Vec::new()
+            }),
+            false => Ok(Vec::new()),
+        },
+        ["reload"] => return (Ok(Vec::new()), Flow::Exit(Control::Reload)),
+        ["inject", key, action @ ..] => {
+            let values: &[i32] = match action {
+                [] | ["tap"] => &[1, 0],
+                ["press"] => &[1],
+                ["release"] => &[0],
+                _ => return (Err("usage: inject <key> [press|release|tap]".to_string()), Flow::Continue),
+            };
+
+            match parse_key(key) {
+                Some(key) => inject(key, values, outputs, timers),
+                None => Err(format!("unknown key \"{}\"", key)),
+            }
+        },
+        _ => handler.on_command(command, &mut outputs.context(0, timers))
+            .unwrap_or_else(|| Err(format!("unknown command \"{}\"", command[0]))),
+    };
+
+    return (reply, Flow::Continue);
+}
+
+fn select(selector: &DeviceSelector, options: &Options) -> Result<Vec<(String, Device)>, NhkError> {
+    // A bare path is opened directly, so permission problems and typos are
+    // reported as such instead of as an empty match.
+    let paths = match &selector.path {
+        Some(path) if selector.matches(&DeviceInfo { path: path.clone(), ..DeviceInfo::default() }) => vec![path.clone()],
+        _ => selector.select().into_iter().map(|info| info.path).collect(),
+    };
+
+    if paths.is_empty() && !options.hotplug {
+        return Err(NhkError::NoMatchingDevice(selector.to_string(), list_devices()));
+    }
+
+    let mut devs = Vec::new();
+    for path in paths {
+        match dev_from_file(&path) {
+            Ok(dev) => {
+                log::info!("device opened path={:?}", path);
+                devs.push((path, dev));
+            },
+            Err(NhkError::DeviceOpen(_, e)) if options.hotplug && e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("device gone before it could be opened path={:?}", path);
+            },
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(devs);
+}
+
+fn attach(path: String, sources: &mut Vec<Source>, outputs: &mut Outputs, guards: &Guards, selectors: &[DeviceSelector], options: &Options) -> Option<SourceId> {
+    if sources.iter().any(|source| source.dev.is_some() && source.info.path == path) {
+        return None;
+    }
+
+    let mut dev = match dev_from_file(&path) {
+        Ok(dev) => dev,
+        Err(e) => {
+            log::debug!("ignoring device path={:?} error=\"{}\"", path, e);
+            return None;
+        },
+    };
+
+    if dev.name() == Some(uinput::VIRTUAL_DEVICE_NAME) {
+        return None;
+    }
+
+    let identity = Identity::of(&dev);
+    let lost = sources.iter().position(|source| source.dev.is_none() && source.identity == identity);
+    let info = DeviceInfo::new(&path, &dev);
+
+    let id = match lost {
+        Some(id) => {
+            log::info!("device reconnected source={} path={:?}", id, path);
+            if let Err(e) = grab(id, &info, &mut dev, guards) {
+                return skip(&path, &mut dev, e);
+            }
+            sources[id].info = info;
+            sources[id].dev = Some(dev);
+            id
+        },
+        None => {
+            if !selectors.iter().any(|selector| selector.matches(&info)) {
+                return None;
+            }
+
+            log::info!("device attached path={:?}", path);
+            let id = sources.len();
+            if let Err(e) = grab(id, &info, &mut dev, guards) {
+                return skip(&path, &mut dev, e);
+            }
+            if options.output == Output::PerSource {
+                match uinput::create(options.capabilities, &[&dev]) {
+                    Ok(uinput) => outputs.add(uinput),
+                    Err(e) => {
+                        if let Some(escape) = &guards.escape {
+                            escape.remove(id);
+                        }
+                        return skip(&path, &mut dev, e);
+                    },
+                }
+            }
+            sources.push(Source::new(path, dev));
+            sources.len() - 1
+        },
+    };
+
+    return Some(id);
+}
+
+// A device that can't be taken over is left alone, rather than taking
+// the others down with it.
+fn skip(path: &str, dev: &mut Device, e: NhkError) -> Option<SourceId> {
+    dev.grab(GrabMode::Ungrab).ok();
+    log::warn!("skipping device path={:?} error=\"{}\"", path, e);
+    return None;
+}
+
+fn deadline(next_tick: Option<Instant>, next_timer: Option<Instant>) -> Option<Instant> {
+    return match (next_tick, next_timer) {
+        (Some(next_tick), Some(next_timer)) => Some(next_tick.min(next_timer)),
+        (next_tick, next_timer) => next_tick.or(next_timer),
+    };
+}
+
+fn fire_timers<O: OutputSink>(outputs: &Outputs<O>, timers: &mut Timers, handler: &mut dyn Handler) -> Flow {
+    while let Some((timer, source, action)) = timers.pop_due(outputs.now()) {
+        match action {
+            TimerAction::Emit(events) => {
+                if let Some(tx) = outputs.context(source, timers).tx() {
+                    for ev in events {
+                        tx.send(ev).ok();
+                    }
+                }
+            },
+            TimerAction::Call => {
+                if let Flow::Exit(control) = flow(handler.on_timer(timer, &mut outputs.context(source, timers))) {
+                    return Flow::Exit(control);
+                }
+            },
+        }
+    }
+
+    return Flow::Continue;
+}
+
+// Opens and grabs a device the watcher reported, if it is one of ours.
+type Attach<S, O> = Box<dyn FnMut(String, &mut Vec<Source<S>>, &mut Outputs<O>, &Guards) -> Option<SourceId>>;
+
+// Everything the read loop works on between waits. The mock takes the same
+// turns on its own clock, handing over the sources it fed instead of waiting.
+struct Runner<S: EventSource, O: OutputSink> {
+    sources: Vec<Source<S>>,
+    outputs: Outputs<O>,
+    timers: Timers,
+    guards: Guards,
+    waiter: Waiter,
+    hotplug: Option<(Watcher, Attach<S, O>)>,
+    next_tick: Option<Instant>,
+    options: Options,
+}
+
+impl<S: EventSource, O: OutputSink> Runner<S, O> {
+    fn new(sources: Vec<Source<S>>, outputs: Outputs<O>, guards: Guards, attach: Option<Attach<S, O>>, options: &Options) -> Result<Runner<S, O>, NhkError> {
+        let hotplug = match attach {
+            Some(attach) => Some((Watcher::new().map_err(NhkError::Read)?, attach)),
+            None => None,
+        };
+
+        return Ok(Runner {
+            sources,
+            outputs,
+            timers: Timers::default(),
+            guards,
+            waiter: Waiter::new(options.event_loop).map_err(NhkError::Read)?,
+            hotplug,
+            next_tick: None,
+            options: options.clone(),
+        });
+    }
+
+    fn start(&mut self, handler: &mut dyn Handler) -> Result<(), NhkError> {
+        self.guards.paused = false;
+        for (id, source) in self.sources.iter_mut().enumerate() {
+            if let Some(dev) = &mut source.dev {
+                grab(id, &source.info, dev, &self.guards)?;
+            }
+        }
+
+        handler.on_start(&mut self.outputs.context(0, &mut self.timers));
+        for (id, source) in self.sources.iter().enumerate() {
+            handler.on_device_added(&source.info, &mut self.outputs.context(id, &mut self.timers));
+        }
+
+        for (id, source) in self.sources.iter().enumerate() {
+            if let Some(dev) = &source.dev {
+                self.waiter.add(dev.fd(), Token::Source(id)).map_err(NhkError::Read)?;
+            }
+        }
+        if let Some(signals) = &self.guards.signals {
+            self.waiter.add(signals.as_raw_fd(), Token::Signals).map_err(NhkError::Read)?;
+        }
+        if let Some((watcher, _)) = &self.hotplug {
+            self.waiter.add(watcher.as_raw_fd(), Token::Watcher).map_err(NhkError::Read)?;
+        }
+        if let Some(control) = &self.guards.control {
+            self.waiter.add(control.as_raw_fd(), Token::Control).map_err(NhkError::Read)?;
+            for (client, fd) in control.clients() {
+                self.waiter.add(fd, Token::Client(client)).map_err(NhkError::Read)?;
+            }
+        }
+
+        self.next_tick = self.options.tick.map(|tick| self.outputs.now() + tick);
+        return self.outputs.flush();
+    }
+
+    fn run(&mut self, handler: &mut dyn Handler) -> Result<Control, NhkError> {
+        self.start(handler)?;
+
+        loop {
+            let ready = self.waiter.wait(deadline(self.next_tick, self.timers.next_deadline())).map_err(NhkError::Read)?;
+            if let Some(control) = self.turn(ready, handler)? {
+                return Ok(control);
+            }
+        }
+    }
+
+    // Handles whatever the wait found ready, then the timers and the tick
+    // that came due, and flushes. Some when the loop should end.
+    fn turn(&mut self, ready: Vec<Ready>, handler: &mut dyn Handler) -> Result<Option<Control>, NhkError> {
+        let mut signalled = false;
+        let mut changed = false;
+        let mut connected = false;
+        let mut clients = Vec::new();
+
+        for ready in ready {
+            let id = match ready.token {
+                Token::Source(id) => id,
+                Token::Signals => { signalled = true; continue; },
+                Token::Watcher => { changed = true; continue; },
+                Token::Control => { connected = true; continue; },
+                Token::Client(client) => { clients.push(client); continue; },
+                Token::Timer => continue,
+            };
+
+            let mut flow = match self.guards.paused {
+                true => discard(&mut self.sources[id]),
+                false => read_source(id, &mut self.sources[id], &mut self.outputs, &mut self.timers, handler),
+            };
+            if let Flow::Continue = flow {
+                if ready.hangup {
+                    flow = Flow::Removed;
+                }
+            }
+
+            match flow {
+                Flow::Continue => (),
+                Flow::Exit(control) => return Ok(Some(control)),
+                Flow::Removed => {
+                    if let Flow::Exit(control) = release_held(id, &mut self.sources[id], &self.outputs, &mut self.timers, handler) {
+                        return Ok(Some(control));
+                    }
+                    log::warn!("device lost source={} path={:?}", id, self.sources[id].info.path);
+                    self.waiter.remove(Token::Source(id));
+                    self.sources[id].dev = None;
+                    if let Some(escape) = &self.guards.escape {
+                        escape.remove(id);
+                    }
+                    handler.on_device_removed(&self.sources[id].info, &mut self.outputs.context(id, &mut self.timers));
+
+                    if self.hotplug.is_none() {
+                        return Err(NhkError::DeviceRemoved(self.sources[id].info.path.clone()));
+                    }
+                },
+            }
+        }
+
+        if signalled && self.guards.signals.as_mut().is_some_and(|signals| signals.received()) {
+            log::info!("stopping on signal");
+            return Ok(Some(Control::Stop));
+        }
+
+        // Taken out for the duration, commands need the guards as well.
+        if let Some(mut control) = self.guards.control.take() {
+            if connected {
+                for (client, fd) in control.accept() {
+                    if self.waiter.add(fd, Token::Client(client)).is_err() {
+                        control.close(client);
+                    }
+                }
+            }
+
+            let mut exit = None;
+            for client in clients {
+                let (lines, mut closed) = control.read(client);
+                for line in lines {
+                    let command: Vec<&str> = line.split_whitespace().collect();
+                    if command.is_empty() {
+                        continue;
+                    }
+
+                    log::debug!("control command client={} command={:?}", client, command[0]);
+                    let (reply, flow) = execute(&command, &mut self.sources, &self.outputs, &mut self.timers, handler, &mut self.guards, &self.options);
+                    closed |= control.reply(client, &reply).is_err();
+                    if let Flow::Exit(control) = flow {
+                        exit = Some(control);
+                        break;
+                    }
+                }
+
+                if closed {
+                    self.waiter.remove(Token::Client(client));
+                    control.close(client);
+                }
+                if exit.is_some() {
+                    break;
+                }
+            }
+
+            self.guards.control = Some(control);
+            if let Some(control) = exit {
+                return Ok(Some(control));
+            }
+        }
+
+        if let Some((watcher, attach)) = &mut self.hotplug {
+            if changed {
+                for path in watcher.changed_nodes() {
+                    if let Some(id) = attach(path, &mut self.sources, &mut self.outputs, &self.guards) {
+                        if let Some(dev) = &mut self.sources[id].dev {
+                            if self.guards.paused {
+                                dev.grab(GrabMode::Ungrab).ok();
+                            }
+                            self.waiter.add(dev.fd(), Token::Source(id)).map_err(NhkError::Read)?;
+                        }
+                        handler.on_device_added(&self.sources[id].info, &mut self.outputs.context(id, &mut self.timers));
+                    }
+                }
+            }
+        }
+
+        if let Flow::Exit(control) = fire_timers(&self.outputs, &mut self.timers, handler) {
+            return Ok(Some(control));
+        }
+
+        if let (Some(tick), Some(deadline)) = (self.options.tick, self.next_tick) {
+            if self.outputs.now() >= deadline {
+                self.next_tick = Some(deadline + tick);
+                if let Flow::Exit(control) = flow(handler.on_tick(&mut self.outputs.context(0, &mut self.timers))) {
+                    return Ok(Some(control));
+                }
+            }
+        }
+
+        self.outputs.flush()?;
+        if self.outputs.any_finished() {
+            return Ok(Some(Control::Stop));
+        }
+
+        return Ok(None);
+    }
+
+    // Ungrabs before tearing down the outputs, so a reload never leaves the
+    // devices grabbed without anything forwarding their events. The writers
+    // lift whatever is still held.
+    fn stop(self, handler: &mut dyn Handler) -> (Result<(), NhkError>, Guards) {
+        let Runner { sources, outputs, mut timers, guards, .. } = self;
+        handler.on_stop(&mut outputs.context(0, &mut timers));
+
+        drop(sources);
+        if let Some(escape) = &guards.escape {
+            escape.clear();
+        }
+
+        return (outputs.join(), guards);
+    }
+}
+
+fn open_sources(selector: impl Into<DeviceSelector>) -> Result<Vec<Source>, NhkError> {
+    return Ok(select(&selector.into(), &Options::default())?
+        .into_iter()
+        .map(|(path, dev)| Source::new(path, dev))
+        .collect());
+}
+
+// Reads without grabbing until `f` returns true or SIGINT/SIGTERM arrives.
+fn watch<S: EventSource>(sources: &mut [Source<S>], mut f: impl FnMut(&DeviceInfo, InInputEvent) -> bool) -> Result<(), NhkError> {
+    let mut signals = Signals::new().map_err(NhkError::Read)?;
+
+    loop {
+        let mut fds: Vec<PollFd> = sources.iter()
+            .filter_map(|source| source.dev.as_ref())
+            .map(|dev| PollFd::new(dev.fd(), PollFlags::POLLIN))
+            .collect();
+        fds.push(PollFd::new(signals.as_raw_fd(), PollFlags::POLLIN));
+
+        match poll(&mut fds, -1) {
+            Ok(_) => (),
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => return Err(NhkError::Read(error::nix_error(e))),
+        }
+
+        for (source, fd) in sources.iter_mut().zip(fds.iter()) {
+            let dev = match &mut source.dev {
+                Some(dev) => dev,
+                None => continue,
+            };
+
+            loop {
+                match dev.next_event() {
+                    Ok(ev) => {
+                        if f(&source.info, ev) {
+                            return Ok(());
+                        }
+                    },
+                    Err(e) if e.raw_os_error() == Some(Errno::ENODEV as i32) => return Err(NhkError::DeviceRemoved(source.info.path.clone())),
+                    Err(_e) => break,
+                }
+            }
+
+            if fd.revents().is_some_and(|revents| revents.intersects(PollFlags::POLLHUP | PollFlags::POLLERR | PollFlags::POLLNVAL)) {
+                return Err(NhkError::DeviceRemoved(source.info.path.clone()));
+            }
+        }
+
+        if fds[fds.len() - 1].revents().is_some_and(|revents| !revents.is_empty()) && signals.received() {
+            return Ok(());
+        }
+    }
+}
+
+pub fn monitor(selector: impl Into<DeviceSelector>, f: impl FnMut(&DeviceInfo, InInputEvent) -> bool) -> Result<(), NhkError> {
+    let mut sources = open_sources(selector)?;
+    return watch(&mut sources, f);
+}
+
+pub fn run(selector: impl Into<DeviceSelector>, handler: impl Handler) -> Result<(), NhkError> {
+    return run_with_options(selector, Options::default(), handler);
+}
+
+pub fn run_with_options(selector: impl Into<DeviceSelector>, options: Options, handler: impl Handler) -> Result<(), NhkError> {
+    return run_many(vec![selector.into()], options, handler);
+}
+
+pub fn run_many<S: Into<DeviceSelector>>(selectors: Vec<S>, options: Options, handler: impl Handler) -> Result<(), NhkError> {
+    return run_reloading(selectors.into_iter().map(Into::into).collect(), options, handler, || None);
+}
+
+// On Control::Reload everything `reload` returns replaces what was running,
+// None reopens the devices with the same handler.
+pub(crate) fn run_reloading<H: Handler>(mut selectors: Vec<DeviceSelector>, mut options: Options, mut handler: H, mut reload: impl FnMut() -> Option<(Vec<DeviceSelector>, Options, H)>) -> Result<(), NhkError> {
+    if env::var("DEBUG").is_ok_and(|val| val == "1") {
+        log::warn!("DEBUG=1 no longer prints events, use Options::trace or `set trace on` in a config");
+    }
+
+    let control = match &options.control {
+        Some(path) => Some(ControlSocket::bind(path).map_err(|e| NhkError::Control(path.display().to_string(), e))?),
+        None => None,
+    };
+    let mut guards = Guards {
+        signals: Some(Signals::new().map_err(NhkError::Read)?),
+        escape: options.escape.clone().map(Escape::new),
+        control,
+        paused: false,
+    };
+
+    loop {
+        let mut sources: Vec<Source> = Vec::new();
+        for selector in &selectors {
+            for (path, dev) in select(selector, &options)? {
+                if !sources.iter().any(|source| source.info.path == path) {
+                    sources.push(Source::new(path, dev));
+                }
+            }
+        }
+
+        let mut outputs = Outputs::new(&options);
+        for uinput in uinputs_for(&sources, &options)? {
+            outputs.add(uinput);
+        }
+
+        let attach: Option<Attach<Device, OutputDevice>> = match options.hotplug {
+            true => {
+                let (selectors, options) = (selectors.clone(), options.clone());
+                Some(Box::new(move |path, sources: &mut Vec<Source>, outputs: &mut Outputs, guards: &Guards| attach(path, sources, outputs, guards, &selectors, &options)))
+            },
+            false => None,
+        };
+
+        let mut runner = Runner::new(sources, outputs, guards, attach, &options)?;
+        let read_result = runner.run(&mut handler);
+        let (write_result, returned) = runner.stop(&mut handler);
+        guards = returned;
+
+        match read_result {
+            Ok(Control::Reload) => {
+                write_result?;
+
+                if let Some((new_selectors, new_options, new_handler)) = reload() {
+                    if new_options.escape != options.escape {
+                        guards.escape = new_options.escape.clone().map(Escape::new);
+                    }
+                    selectors = new_selectors;
+                    options = new_options;
+                    handler = new_handler;
+                    log::info!("configuration reloaded");
+                }
+                log::info!("handler reloaded, reopening devices");
+            },
+            read_result => return read_result.map(|_| ()).and(write_result),
+        }
+    }
+}
+
+// The read loop over sources and sinks of any kind, e.g. MockSource and
+// MockSink, with one sink per source for Output::PerSource. Nothing is
+// reopened: there is no hotplug, no escape chord and a reload stops.
+pub fn run_sources<S: EventSource, O: OutputSink>(sources: Vec<(DeviceInfo, S)>, sinks: Vec<O>, options: Options, mut handler: impl Handler) -> Result<(), NhkError> {
+    let control = match &options.control {
+        Some(path) => Some(ControlSocket::bind(path).map_err(|e| NhkError::Control(path.display().to_string(), e))?),
+        None => None,
+    };
+    let guards = Guards { signals: Some(Signals::new().map_err(NhkError::Read)?), escape: None, control, paused: false };
+
+    let mut outputs = Outputs::new(&options);
+    for sink in sinks {
+        outputs.add(sink);
+    }
 
-    read_loop(&mut dev, tx, event_handler);
+    let sources = sources.into_iter().map(|(info, dev)| Source::with_info(info, dev)).collect();
+    let mut runner = Runner::new(sources, outputs, guards, None, &options)?;
+    let read_result = runner.run(&mut handler);
+    let (write_result, _guards) = runner.stop(&mut handler);
 
-    write_loop_thread.join().expect("panic!");
+    return read_result.map(|_| ()).and(write_result);
 }
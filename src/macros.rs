@@ -0,0 +1,363 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use evdev::Key;
+
+use crate::{ send_key, send_syn, Context, Keymap, TimerId };
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    Press(Key),
+    Release(Key),
+    Tap(Key),
+    Delay(Duration),
+    Text(String),
+    Repeat(usize, Vec<Step>),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Macro {
+    steps: Vec<Step>,
+}
+
+impl Macro {
+    pub fn new() -> Macro {
+        return Macro::default();
+    }
+
+    pub fn step(mut self, step: Step) -> Macro {
+        self.steps.push(step);
+        return self;
+    }
+
+    pub fn press(self, key: Key) -> Macro {
+        return self.step(Step::Press(key));
+    }
+
+    pub fn release(self, key: Key) -> Macro {
+        return self.step(Step::Release(key));
+    }
+
+    pub fn tap(self, key: Key) -> Macro {
+        return self.step(Step::Tap(key));
+    }
+
+    // Presses the keys in order and releases them in reverse, like Ctrl+Shift+T.
+    pub fn chord(mut self, keys: &[Key]) -> Macro {
+        for &key in keys {
+            self = self.press(key);
+        }
+        for &key in keys.iter().rev() {
+            self = self.release(key);
+        }
+        return self;
+    }
+
+    pub fn delay(self, delay: Duration) -> Macro {
+        return self.step(Step::Delay(delay));
+    }
+
+    pub fn text(self, text: &str) -> Macro {
+        return self.step(Step::Text(text.to_string()));
+    }
+
+    pub fn repeat(self, count: usize, body: Macro) -> Macro {
+        return self.step(Step::Repeat(count, body.steps));
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        return &self.steps;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Key(u16, i32),
+    Delay(Duration),
+}
+
+fn expand(steps: &[Step], keymap: &Keymap, ops: &mut VecDeque<Op>) {
+    for step in steps {
+        match step {
+            Step::Press(key) => ops.push_back(Op::Key(key.code(), 1)),
+            Step::Release(key) => ops.push_back(Op::Key(key.code(), 0)),
+            Step::Tap(key) => ops.extend([Op::Key(key.code(), 1), Op::Key(key.code(), 0)]),
+            Step::Delay(delay) => ops.push_back(Op::Delay(*delay)),
+            Step::Text(text) => {
+                for c in text.chars() {
+                    match keymap.events(c) {
+                        Some(events) => ops.extend(events.into_iter().map(|(key, value)| Op::Key(key.code(), value))),
+                        None => log::warn!("macro text character can't be typed char={:?}", c),
+                    }
+                }
+            },
+            Step::Repeat(count, body) => {
+                for _ in 0..*count {
+                    expand(body, keymap, ops);
+                }
+            },
+        }
+    }
+}
+
+// Plays macros one key event at a time, sleeping on a timer for delays so
+// input keeps flowing meanwhile. Macros triggered during playback are
+// queued behind it. Keys a macro leaves down are released when it ends.
+// Any handler can own one: `play` sends through the context, `on_timer`
+// carries on after a delay and `cancel` stops it.
+pub struct Player {
+    queue: VecDeque<Op>,
+    timer: Option<TimerId>,
+    held: Vec<u16>,
+    interrupt: Option<Key>,
+    keymap: Keymap,
+}
+
+impl Default for Player {
+    fn default() -> Player {
+        return Player { queue: VecDeque::new(), timer: None, held: Vec::new(), interrupt: Some(Key::KEY_ESC), keymap: Keymap::default() };
+    }
+}
+
+impl Player {
+    pub fn new() -> Player {
+        return Player::default();
+    }
+
+    // The key that stops a playing macro, Esc unless changed. Checking for
+    // it is up to the owner, with `interrupts`.
+    pub fn interrupt(mut self, key: Option<Key>) -> Player {
+        self.interrupt = key;
+        return self;
+    }
+
+    // How text is typed, US unless changed.
+    pub fn keymap(mut self, keymap: Keymap) -> Player {
+        self.keymap = keymap;
+        return self;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        return !self.queue.is_empty() || self.timer.is_some() || !self.held.is_empty();
+    }
+
+    pub fn interrupts(&self, code: u16) -> bool {
+        return self.is_playing() && self.interrupt.is_some_and(|key| key.code() == code);
+    }
+
+    pub fn play(&mut self, m: &Macro, ctx: &mut Context) {
+        self.queue(m);
+        self.send(ctx);
+    }
+
+    // False when the timer isn't the player's.
+    pub fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> bool {
+        if !self.timeout(timer) {
+            return false;
+        }
+
+        self.send(ctx);
+        return true;
+    }
+
+    pub fn cancel(&mut self, ctx: &mut Context) {
+        let held = self.stop(ctx);
+        if let (Some(tx), false) = (ctx.tx(), held.is_empty()) {
+            for code in held {
+                send_key(tx, Key::new(code), 0);
+            }
+            send_syn(tx);
+        }
+    }
+
+    fn send(&mut self, ctx: &mut Context) {
+        while let Some((code, value)) = self.next(ctx) {
+            if let Some(tx) = ctx.tx() {
+                send_key(tx, Key::new(code), value);
+                send_syn(tx);
+            }
+        }
+    }
+
+    pub(crate) fn queue(&mut self, m: &Macro) {
+        expand(&m.steps, &self.keymap, &mut self.queue);
+    }
+
+    // The next key event to send, or None when done or waiting on a delay.
+    pub(crate) fn next(&mut self, ctx: &mut Context) -> Option<(u16, i32)> {
+        if self.timer.is_some() {
+            return None;
+        }
+
+        let op = match self.queue.pop_front() {
+            Some(op) => op,
+            None => return self.held.pop().map(|code| (code, 0)),
+        };
+
+        return match op {
+            Op::Key(code, value) => {
+                self.held.retain(|&held| held != code);
+                if value == 1 {
+                    self.held.push(code);
+                }
+                Some((code, value))
+            },
+            Op::Delay(delay) => {
+                self.timer = Some(ctx.call_after(delay));
+                None
+            },
+        };
+    }
+
+    pub(crate) fn timeout(&mut self, timer: TimerId) -> bool {
+        if self.timer != Some(timer) {
+            return false;
+        }
+
+        self.timer = None;
+        return true;
+    }
+
+    // Drops whatever is left and returns the keys to release.
+    pub(crate) fn stop(&mut self, ctx: &mut Context) -> Vec<u16> {
+        self.queue.clear();
+        if let Some(timer) = self.timer.take() {
+            ctx.cancel(timer);
+        }
+
+        let mut held = std::mem::take(&mut self.held);
+        held.reverse();
+        return held;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev_rs::InputEvent as InInputEvent;
+
+    use super::*;
+    use crate::{ key_code, Action, Control, Handler, Layer, Layers, Mock, Passthrough };
+
+    // Plays its macro on F5 without any layers, and passes the rest on,
+    // Esc included.
+    struct Hotkey {
+        player: Player,
+        m: Macro,
+    }
+
+    impl Handler for Hotkey {
+        fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+            let code = match key_code(&ev) {
+                Some(code) => code,
+                None => return Passthrough.on_event(ev, ctx),
+            };
+
+            if ev.value == 1 && self.player.interrupts(code) {
+                self.player.cancel(ctx);
+            }
+            if code != Key::KEY_F5.code() {
+                return Passthrough.on_event(ev, ctx);
+            }
+
+            if ev.value == 1 {
+                self.player.play(&self.m, ctx);
+            }
+            return Control::Continue;
+        }
+
+        fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+            self.player.on_timer(timer, ctx);
+            return Control::Continue;
+        }
+    }
+
+    #[test]
+    fn delay_keeps_input_flowing() {
+        let m = Macro::new().tap(Key::KEY_A).delay(Duration::from_millis(100)).tap(Key::KEY_B);
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(Layer::new().map(Key::KEY_F5, Action::Macro(m))));
+        mock.tap(Key::KEY_F5);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0)]);
+
+        mock.advance(Duration::from_millis(50));
+        mock.tap(Key::KEY_X);
+        assert_eq!(mock.keys(), vec![(Key::KEY_X, 1), (Key::KEY_X, 0)]);
+
+        mock.advance(Duration::from_millis(50));
+        assert_eq!(mock.keys(), vec![(Key::KEY_B, 1), (Key::KEY_B, 0)]);
+    }
+
+    #[test]
+    fn queued_behind_delay() {
+        let m = Macro::new().tap(Key::KEY_A).delay(Duration::from_millis(100));
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(Layer::new().map(Key::KEY_F5, Action::Macro(m))));
+        mock.tap(Key::KEY_F5);
+        mock.tap(Key::KEY_F5);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0)]);
+
+        mock.advance(Duration::from_millis(100));
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0)]);
+    }
+
+    #[test]
+    fn interrupt_releases_held() {
+        let m = Macro::new()
+            .press(Key::KEY_LEFTCTRL)
+            .delay(Duration::from_millis(100))
+            .tap(Key::KEY_C)
+            .release(Key::KEY_LEFTCTRL);
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(Layer::new().map(Key::KEY_F5, Action::Macro(m))));
+        mock.tap(Key::KEY_F5);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1)]);
+
+        // The interrupting key itself goes nowhere.
+        mock.tap(Key::KEY_ESC);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 0)]);
+
+        mock.advance(Duration::from_secs(1));
+        mock.tap(Key::KEY_ESC);
+        assert_eq!(mock.keys(), vec![(Key::KEY_ESC, 1), (Key::KEY_ESC, 0)]);
+    }
+
+    #[test]
+    fn held_released_at_end() {
+        let m = Macro::new().press(Key::KEY_LEFTSHIFT).tap(Key::KEY_A);
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(Layer::new().map(Key::KEY_F5, Action::Macro(m))));
+        mock.tap(Key::KEY_F5);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 1), (Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_LEFTSHIFT, 0)]);
+    }
+
+    #[test]
+    fn text_and_repeat() {
+        let m = Macro::new().text("a!").repeat(2, Macro::new().tap(Key::KEY_B));
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(Layer::new().map(Key::KEY_F5, Action::Macro(m))));
+        mock.tap(Key::KEY_F5);
+        assert_eq!(mock.keys(), vec![
+            (Key::KEY_A, 1), (Key::KEY_A, 0),
+            (Key::KEY_LEFTSHIFT, 1), (Key::KEY_1, 1), (Key::KEY_1, 0), (Key::KEY_LEFTSHIFT, 0),
+            (Key::KEY_B, 1), (Key::KEY_B, 0),
+            (Key::KEY_B, 1), (Key::KEY_B, 0),
+        ]);
+    }
+
+    #[test]
+    fn any_handler() {
+        let m = Macro::new().press(Key::KEY_LEFTCTRL).delay(Duration::from_millis(100)).tap(Key::KEY_T).release(Key::KEY_LEFTCTRL).delay(Duration::from_millis(100)).text("a");
+        let mut mock = Mock::new(Hotkey { player: Player::new(), m });
+        mock.tap(Key::KEY_F5);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1)]);
+
+        mock.advance(Duration::from_millis(100));
+        assert_eq!(mock.keys(), vec![(Key::KEY_T, 1), (Key::KEY_T, 0), (Key::KEY_LEFTCTRL, 0)]);
+
+        mock.advance(Duration::from_millis(100));
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0)]);
+        assert!(!mock.handler().player.is_playing());
+
+        // Cancelled, whatever it holds is let go.
+        mock.tap(Key::KEY_F5);
+        mock.tap(Key::KEY_ESC);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1), (Key::KEY_LEFTCTRL, 0), (Key::KEY_ESC, 1), (Key::KEY_ESC, 0)]);
+        mock.advance(Duration::from_secs(1));
+        assert_eq!(mock.keys(), vec![]);
+    }
+}
@@ -1,3 +1,323 @@
+#![allow(clippy::needless_return)]
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{ BufWriter, Write };
+use std::process;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Instant;
+
+use nardi_hot_key as nhk;
+
+use evdev_rs::InputEvent as InInputEvent;
+
+use log::{ LevelFilter, Log, Metadata, Record };
+
+use nhk::{ Config, DeviceSelector, EventLoop, Latency, Passthrough };
+
+const USAGE: &str = "usage:
+    nhk list                 list input devices
+    nhk monitor <device>     print events from a device without grabbing it
+    nhk run <config> [--stats]
+                             start remapping with a config file, --stats
+                             prints latency percentiles on exit
+    nhk check <config>       validate a config file
+    nhk record <device> <file>
+                             record events from a device in evemu format
+    nhk replay <file> [--config <config>] [--output <file>] [--uinput]
+                             feed a recording through a config's handler (or
+                             straight through) and print or play the output
+    nhk diff <file> <file>   compare two recorded outputs, ignoring timing
+    nhk ctl [--socket <path>] <command>
+                             send a command to a running nhk: status, devices,
+                             layer <name>, toggle <name>, pause, resume,
+                             reload or inject <key> [press|release|tap]
+    nhk bench [--config <config>] [--rate <n>] [--count <n>] [--uinput]
+              [--event-loop threaded|single]
+                             time a config's handler (or straight through) in
+                             the read loop on synthetic typing at n key events
+                             per second, see docs/bench.md
+
+<device> is a path like /dev/input/event3 or a selector like name:Keychron,
+id:046d:c52b, bus:usb, phys:usb-0000:00:14.0-2/input0 or by-id:*-kbd
+
+NHK_LOG=off|error|warn|info|debug sets what gets logged to stderr (default
+info). Event traces are only written with `set trace on` or `set trace
+redacted` in the config, and `set control on` makes a running nhk listen for
+`nhk ctl` on $XDG_RUNTIME_DIR/nhk.sock.";
+
+struct Logger {
+    level: LevelFilter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        return metadata.target() == nhk::TRACE_TARGET || metadata.level() <= self.level;
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.target() {
+            nhk::TRACE_TARGET => eprintln!("nhk: trace: {}", record.args()),
+            _ => eprintln!("nhk: {}: {}", record.level().as_str().to_lowercase(), record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+fn init_logging() -> Result<(), String> {
+    let level = match env::var("NHK_LOG") {
+        Ok(level) => LevelFilter::from_str(&level).map_err(|_| format!("invalid NHK_LOG level \"{}\"", level))?,
+        Err(_) => LevelFilter::Info,
+    };
+
+    // Traces are gated by the run's options rather than the level.
+    log::set_logger(Box::leak(Box::new(Logger { level }))).map_err(|e| e.to_string())?;
+    log::set_max_level(LevelFilter::Trace);
+    return Ok(());
+}
+
+fn list() -> Result<(), String> {
+    let devices = nhk::list_devices();
+    if devices.is_empty() {
+        return Err("no readable input devices found".to_string());
+    }
+
+    for device in devices {
+        println!("{}", device);
+        println!("    {}", device.capabilities.join(" "));
+    }
+
+    return Ok(());
+}
+
+fn monitor(device: &str) -> Result<(), String> {
+    let selector = DeviceSelector::from_str(device)?;
+
+    return nhk::monitor(selector, |info, ev| {
+        println!("{} {}.{:06} {} {}", info.path, ev.time.tv_sec, ev.time.tv_usec, ev.event_code, ev.value);
+        return false;
+    }).map_err(|e| e.to_string());
+}
+
+fn run(config: &str, args: &[&str]) -> Result<(), String> {
+    let stats = match args {
+        [] => false,
+        ["--stats"] => true,
+        [arg, ..] => return Err(format!("unknown run option \"{}\"", arg)),
+    };
+
+    let mut config = Config::load(config).map_err(|e| e.to_string())?;
+    if !stats {
+        return config.run().map_err(|e| e.to_string());
+    }
+
+    let latency = Latency::new();
+    config.options.latency = Some(latency.clone());
+
+    let result = config.run();
+    eprintln!("{}", latency.snapshot());
+    return result.map_err(|e| e.to_string());
+}
+
+fn check(path: &str) -> Result<(), String> {
+    let config = Config::load(path).map_err(|e| e.to_string())?;
+
+    println!("{}: ok, {} device selector(s), layers: {}", path, config.selectors.len(), config.layer_names().join(", "));
+    return Ok(());
+}
+
+fn record(device: &str, path: &str) -> Result<(), String> {
+    let selector = DeviceSelector::from_str(device)?;
+    let file = File::create(path).map_err(|e| format!("failed to create {}: {}", path, e))?;
+
+    return nhk::record(selector, BufWriter::new(file)).map_err(|e| e.to_string());
+}
+
+fn replay(path: &str, args: &[&str]) -> Result<(), String> {
+    let mut config = None;
+    let mut output = None;
+    let mut uinput = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--config" => config = Some(*args.next().ok_or("--config needs a file")?),
+            "--output" => output = Some(*args.next().ok_or("--output needs a file")?),
+            "--uinput" => uinput = true,
+            arg => return Err(format!("unknown replay option \"{}\"", arg)),
+        }
+    }
+
+    let events = nhk::load_events(path).map_err(|e| e.to_string())?;
+    let result = match config {
+        Some(config) => {
+            let config = Config::load(config).map_err(|e| e.to_string())?;
+            nhk::replay(&events, config.handler(), config.options.raw)
+        },
+        None => nhk::replay(&events, Passthrough, false),
+    };
+
+    match output {
+        Some(output) => {
+            let file = File::create(output).map_err(|e| format!("failed to create {}: {}", output, e))?;
+            let mut file = BufWriter::new(file);
+            nhk::write_output(&mut file, &result).and_then(|_| file.flush()).map_err(|e| format!("failed to write {}: {}", output, e))?;
+        },
+        None if !uinput => {
+            let stdout = io::stdout();
+            nhk::write_output(&mut stdout.lock(), &result).map_err(|e| e.to_string())?;
+        },
+        None => (),
+    }
+
+    if uinput {
+        nhk::play(&result).map_err(|e| e.to_string())?;
+    }
+
+    return Ok(());
+}
+
+fn bench(args: &[&str]) -> Result<(), String> {
+    let mut config = None;
+    let mut rate = 1000;
+    let mut count = 10000;
+    let mut uinput = false;
+    let mut event_loop = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--config" => config = Some(*args.next().ok_or("--config needs a file")?),
+            "--rate" => {
+                let value = args.next().ok_or("--rate needs a number")?;
+                rate = value.parse().ok().filter(|&rate| rate > 0).ok_or(format!("invalid rate \"{}\"", value))?;
+            },
+            "--count" => {
+                let value = args.next().ok_or("--count needs a number")?;
+                count = value.parse().map_err(|_| format!("invalid count \"{}\"", value))?;
+            },
+            "--uinput" => uinput = true,
+            "--event-loop" => event_loop = match *args.next().ok_or("--event-loop needs threaded or single")? {
+                "threaded" => Some(EventLoop::Threaded),
+                "single" => Some(EventLoop::Single),
+                value => return Err(format!("invalid event loop \"{}\"", value)),
+            },
+            arg => return Err(format!("unknown bench option \"{}\"", arg)),
+        }
+    }
+
+    let start = Instant::now();
+    let stats = match config {
+        Some(config) => {
+            let config = Config::load(config).map_err(|e| e.to_string())?;
+            nhk::bench(config.handler(), rate, count, uinput, event_loop.unwrap_or(config.options.event_loop))
+        },
+        None => nhk::bench(Passthrough, rate, count, uinput, event_loop.unwrap_or_default()),
+    }.map_err(|e| e.to_string())?;
+    let elapsed = start.elapsed();
+
+    // Every key event is read with its SYN_REPORT.
+    let keys = stats.read.count() / 2;
+    println!("{} key events in {:.2?}, {:.0} events/s (target {})", keys, elapsed, keys as f64 / elapsed.as_secs_f64(), rate);
+    println!("{}", stats);
+    return Ok(());
+}
+
+fn ctl(args: &[&str]) -> Result<(), String> {
+    let (path, command) = match args {
+        ["--socket", path, command @ ..] => (PathBuf::from(path), command),
+        command => (nhk::default_socket_path(), command),
+    };
+
+    if command.is_empty() {
+        return Err("ctl needs a command: status, devices, layer <name>, toggle <name>, pause, resume, reload or inject <key> [press|release|tap]".to_string());
+    }
+
+    let reply = nhk::request(&path, &command.join(" ")).map_err(|e| format!("failed to talk to {}: {}", path.display(), e))?;
+    for line in reply? {
+        println!("{}", line);
+    }
+
+    return Ok(());
+}
+
+fn describe(ev: Option<&InInputEvent>) -> String {
+    return match ev {
+        Some(ev) => format!("{} {}", ev.event_code, ev.value),
+        None => "(end)".to_string(),
+    };
+}
+
+fn diff(a: &str, b: &str) -> Result<(), String> {
+    let left = nhk::load_events(a).map_err(|e| e.to_string())?;
+    let right = nhk::load_events(b).map_err(|e| e.to_string())?;
+
+    let same = |l: &InInputEvent, r: &InInputEvent| l.event_code == r.event_code && l.value == r.value;
+    let first = (0..left.len().max(right.len()))
+        .find(|&i| !matches!((left.get(i), right.get(i)), (Some(l), Some(r)) if same(l, r)));
+
+    let first = match first {
+        Some(first) => first,
+        None => {
+            println!("{} events, no differences", left.len());
+            return Ok(());
+        },
+    };
+
+    println!("--- {}", a);
+    println!("+++ {}", b);
+    for i in first.saturating_sub(3)..first {
+        println!("  {:>6}  {}", i, describe(left.get(i)));
+    }
+    for i in first..(first + 5).min(left.len().max(right.len())) {
+        println!("- {:>6}  {}", i, describe(left.get(i)));
+        println!("+ {:>6}  {}", i, describe(right.get(i)));
+    }
+
+    let differing = (0..left.len().max(right.len()))
+        .filter(|&i| !matches!((left.get(i), right.get(i)), (Some(l), Some(r)) if same(l, r)))
+        .count();
+    return Err(format!("outputs differ from event {} on, {} of {}/{} events differ", first, differing, left.len(), right.len()));
+}
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    if let Err(e) = init_logging() {
+        eprintln!("nhk: {}", e);
+        process::exit(2);
+    }
+
+    let result = match args.as_slice() {
+        ["list"] => list(),
+        ["monitor", device] => monitor(device),
+        ["run", config, args @ ..] => run(config, args),
+        ["check", config] => check(config),
+        ["record", device, path] => record(device, path),
+        ["replay", path, args @ ..] => replay(path, args),
+        ["diff", a, b] => diff(a, b),
+        ["bench", args @ ..] => bench(args),
+        ["ctl", args @ ..] => ctl(args),
+        ["help"] | ["-h"] | ["--help"] => {
+            println!("{}", USAGE);
+            Ok(())
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        },
+    };
+
+    if let Err(e) = result {
+        eprintln!("nhk: {}", e);
+        process::exit(1);
+    }
 }
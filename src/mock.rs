@@ -0,0 +1,523 @@
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+use evdev_rs::{ GrabMode, InputEvent as InInputEvent, TimeVal };
+use evdev_rs::enums::EventCode;
+
+use evdev::{ EventType, InputEvent as OutInputEvent, Key };
+
+use nix::errno::Errno;
+use nix::sys::eventfd::{ eventfd, EfdFlags };
+use nix::unistd::{ close, read, write };
+
+use crate::{ deadline, execute, key_input, syn_input, Clock, Control, DeviceInfo, EventLoop, EventSource, Guards, Handler, NhkError, Options, Output, OutputSink, Outputs, Reply, Runner, Source, SourceId };
+use crate::error::nix_error;
+use crate::waiter::{ Ready, Token };
+
+struct Queue {
+    events: VecDeque<InInputEvent>,
+    closed: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    fd: RawFd,
+}
+
+impl Shared {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        return match self.queue.lock() {
+            Ok(queue) => queue,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        close(self.fd).ok();
+    }
+}
+
+// An input device fed from anywhere, e.g. another thread. Its eventfd is
+// readable while events are queued, so the real waiter can sleep on it.
+#[derive(Clone)]
+pub struct MockSource {
+    shared: Arc<Shared>,
+}
+
+impl MockSource {
+    pub fn new() -> io::Result<MockSource> {
+        let fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC).map_err(nix_error)?;
+        let queue = Queue { events: VecDeque::new(), closed: false };
+
+        return Ok(MockSource { shared: Arc::new(Shared { queue: Mutex::new(queue), fd }) });
+    }
+
+    pub fn push(&self, ev: InInputEvent) {
+        self.extend(vec![ev]);
+    }
+
+    // All at once, so the loop never wakes up to half a frame.
+    pub fn extend(&self, events: Vec<InInputEvent>) {
+        let mut queue = self.shared.queue();
+        queue.events.extend(events);
+        write(self.shared.fd, &1u64.to_ne_bytes()).ok();
+    }
+
+    // Once the queue is empty, reads fail like they do on an unplugged device.
+    pub fn close(&self) {
+        let mut queue = self.shared.queue();
+        queue.closed = true;
+        write(self.shared.fd, &1u64.to_ne_bytes()).ok();
+    }
+}
+
+impl EventSource for MockSource {
+    fn fd(&self) -> RawFd {
+        return self.shared.fd;
+    }
+
+    fn next_event(&mut self) -> io::Result<InInputEvent> {
+        let mut queue = self.shared.queue();
+        if let Some(ev) = queue.events.pop_front() {
+            return Ok(ev);
+        }
+        if queue.closed {
+            return Err(io::Error::from_raw_os_error(Errno::ENODEV as i32));
+        }
+
+        // Under the lock, so a push can't slip in between.
+        let mut count = [0; 8];
+        read(self.shared.fd, &mut count).ok();
+        return Err(io::Error::from(io::ErrorKind::WouldBlock));
+    }
+
+    fn grab(&mut self, _mode: GrabMode) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+// Keeps every frame written to it.
+#[derive(Clone, Default)]
+pub struct MockSink {
+    frames: Arc<Mutex<Vec<Vec<OutInputEvent>>>>,
+}
+
+impl MockSink {
+    pub fn new() -> MockSink {
+        return MockSink::default();
+    }
+
+    fn frames(&self) -> MutexGuard<'_, Vec<Vec<OutInputEvent>>> {
+        return match self.frames.lock() {
+            Ok(frames) => frames,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+
+    // The frames written since the last call.
+    pub fn take(&self) -> Vec<Vec<OutInputEvent>> {
+        return std::mem::take(&mut *self.frames());
+    }
+}
+
+impl OutputSink for MockSink {
+    fn write(&mut self, frame: &[OutInputEvent]) -> io::Result<()> {
+        self.frames().push(frame.to_vec());
+        return Ok(());
+    }
+}
+
+// Mock sources and sinks don't fail, only a broken setup gets here.
+fn check<T>(result: Result<T, NhkError>) -> T {
+    return match result {
+        Ok(value) => value,
+        Err(e) => panic!("mock read loop failed: {}", e),
+    };
+}
+
+// Runs a handler in the read loop over mock sources and sinks, on a virtual
+// clock that only moves when told to. Every call is one turn of the loop,
+// so the output is framed and flushed the way it is on a real device.
+pub struct Mock<H> {
+    handler: H,
+    runner: Option<Runner<MockSource, MockSink>>,
+    sources: Vec<MockSource>,
+    sinks: Vec<MockSink>,
+    start: Instant,
+    wall: SystemTime,
+    elapsed: Duration,
+    started: bool,
+}
+
+impl<H: Handler> Mock<H> {
+    pub fn new(handler: H) -> Mock<H> {
+        return Mock::with_sources(handler, 1, Output::Shared);
+    }
+
+    pub fn with_sources(handler: H, sources: usize, output: Output) -> Mock<H> {
+        return Mock::with_options(handler, sources, Options { output, ..Options::default() });
+    }
+
+    // Output is always written inline, so it is there when a call returns.
+    // Hotplug, the escape chord and the control socket don't apply.
+    pub fn with_options(handler: H, sources: usize, options: Options) -> Mock<H> {
+        let options = Options { event_loop: EventLoop::Single, hotplug: false, escape: None, control: None, ..options };
+        let sinks = match options.output {
+            Output::Shared => 1,
+            Output::PerSource => sources.max(1),
+        };
+
+        let mut outputs = Outputs::new(&options);
+        let sinks: Vec<MockSink> = (0..sinks).map(|_| MockSink::new()).collect();
+        for sink in &sinks {
+            outputs.add(sink.clone());
+        }
+
+        let sources: Vec<MockSource> = (0..sources.max(1)).map(|_| MockSource::new().expect("mock source")).collect();
+        let devices = sources.iter().enumerate()
+            .map(|(id, source)| {
+                let info = DeviceInfo { path: format!("mock:{}", id), name: format!("NHK mock {}", id), ..DeviceInfo::default() };
+                Source::with_info(info, source.clone())
+            })
+            .collect();
+
+        let guards = Guards { signals: None, escape: None, control: None, paused: false };
+        let runner = Runner::new(devices, outputs, guards, None, &options).expect("mock read loop");
+
+        return Mock {
+            handler,
+            runner: Some(runner),
+            sources,
+            sinks,
+            start: Instant::now(),
+            wall: UNIX_EPOCH + Duration::from_secs(1_000_000_000),
+            elapsed: Duration::from_secs(0),
+            started: false,
+        };
+    }
+
+    pub fn handler(&self) -> &H {
+        return &self.handler;
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        return &mut self.handler;
+    }
+
+    pub fn now(&self) -> Instant {
+        return self.start + self.elapsed;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        return self.elapsed;
+    }
+
+    pub fn next_timer(&self) -> Option<Duration> {
+        let runner = self.runner.as_ref()?;
+        return deadline(runner.next_tick, runner.timers.next_deadline()).map(|deadline| deadline.saturating_duration_since(self.now()));
+    }
+
+    pub fn time(&self) -> TimeVal {
+        return TimeVal::try_from(self.wall + self.elapsed).unwrap_or_else(|_| TimeVal::new(0, 0));
+    }
+
+    fn clock(&self) -> Clock {
+        return Clock::Fixed(self.start + self.elapsed, self.wall + self.elapsed);
+    }
+
+    // The runner on the current time, and the handler to run it with.
+    fn runner(&mut self) -> Option<(&mut Runner<MockSource, MockSink>, &mut H)> {
+        let clock = self.clock();
+        let runner = self.runner.as_mut()?;
+        runner.outputs.clock = clock;
+        return Some((runner, &mut self.handler));
+    }
+
+    pub fn start(&mut self) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+
+        if let Some((runner, handler)) = self.runner() {
+            check(runner.start(handler));
+        }
+    }
+
+    // Ends the run like the read loop does: on_stop, then the writers lift
+    // every key still held. The mock takes no input after this.
+    pub fn stop(&mut self) {
+        if !self.started {
+            return;
+        }
+
+        if let Some(mut runner) = self.runner.take() {
+            runner.outputs.clock = self.clock();
+            check(runner.stop(&mut self.handler).0);
+        }
+    }
+
+    fn turn(&mut self, ready: Vec<Ready>) -> Control {
+        self.start();
+
+        return match self.runner() {
+            Some((runner, handler)) => check(runner.turn(ready, handler)).unwrap_or(Control::Continue),
+            None => Control::Stop,
+        };
+    }
+
+    // Queues the events on a source and lets the loop read them.
+    pub fn feed(&mut self, source: SourceId, events: Vec<InInputEvent>) -> Control {
+        let source = source.min(self.sources.len() - 1);
+        self.sources[source].extend(events);
+
+        return self.turn(vec![Ready { token: Token::Source(source), hangup: false }]);
+    }
+
+    pub fn push(&mut self, source: SourceId, ev: InInputEvent) -> Control {
+        return self.feed(source, vec![ev]);
+    }
+
+    pub fn event(&mut self, source: SourceId, code: EventCode, value: i32) -> Control {
+        let ev = InInputEvent::new(&self.time(), &code, value);
+        return self.push(source, ev);
+    }
+
+    pub fn key(&mut self, source: SourceId, key: Key, value: i32) -> Control {
+        let time = self.time();
+        return self.feed(source, vec![key_input(&time, key.code(), value), syn_input(&time)]);
+    }
+
+    pub fn press(&mut self, key: Key) -> Control {
+        return self.key(0, key, 1);
+    }
+
+    pub fn release(&mut self, key: Key) -> Control {
+        return self.key(0, key, 0);
+    }
+
+    pub fn tap(&mut self, key: Key) -> Control {
+        return match self.press(key) {
+            Control::Continue => self.release(key),
+            control => control,
+        };
+    }
+
+    // Moves the clock forward, waking the loop at every timer and tick that
+    // comes due on the way.
+    pub fn advance(&mut self, duration: Duration) -> Control {
+        self.start();
+
+        let target = self.elapsed + duration;
+
+        while let Some(next) = self.next_timer() {
+            if self.elapsed + next > target {
+                break;
+            }
+            self.elapsed += next;
+
+            let control = self.turn(Vec::new());
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        self.elapsed = target;
+        return Control::Continue;
+    }
+
+    // What `nhk ctl` would get back from the control socket.
+    pub fn command(&mut self, command: &[&str]) -> Reply {
+        self.start();
+
+        let (runner, handler) = match self.runner() {
+            Some(runner) => runner,
+            None => return Err("stopped".to_string()),
+        };
+        let (reply, _flow) = execute(command, &mut runner.sources, &runner.outputs, &mut runner.timers, handler, &mut runner.guards, &runner.options);
+        check(runner.outputs.flush());
+
+        return reply;
+    }
+
+    pub fn tick(&mut self) -> Control {
+        self.start();
+
+        let (runner, handler) = match self.runner() {
+            Some(runner) => runner,
+            None => return Control::Stop,
+        };
+        let control = handler.on_tick(&mut runner.outputs.context(0, &mut runner.timers));
+        check(runner.outputs.flush());
+
+        return control;
+    }
+
+    // The frames in a sink since the last look, as written to the device.
+    pub fn frames_for(&mut self, sink: usize) -> Vec<Vec<OutInputEvent>> {
+        return match self.sinks.get(sink) {
+            Some(sink) => sink.take(),
+            None => Vec::new(),
+        };
+    }
+
+    pub fn frames(&mut self) -> Vec<Vec<OutInputEvent>> {
+        return self.frames_for(0);
+    }
+
+    pub fn output(&mut self) -> Vec<OutInputEvent> {
+        return self.output_for(0);
+    }
+
+    pub fn output_for(&mut self, sink: usize) -> Vec<OutInputEvent> {
+        return self.frames_for(sink).into_iter().flatten().collect();
+    }
+
+    // The key events in the shared sink, without the SYN_REPORTs around them.
+    pub fn keys(&mut self) -> Vec<(Key, i32)> {
+        return self.output().iter()
+            .filter(|ev| ev.event_type() == EventType::KEY)
+            .map(|ev| (Key::new(ev.code()), ev.value()))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ key_code, Context, Passthrough, TimerId };
+
+    // Sends every key 100ms late, and a tap of Esc 250ms after each press.
+    struct Late {
+        esc: Vec<TimerId>,
+    }
+
+    impl Handler for Late {
+        fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+            if key_code(&ev).is_some() {
+                ctx.emit_after(Duration::from_millis(100), vec![OutInputEvent::from(ev.as_raw())]);
+                if ev.value == 1 {
+                    self.esc.push(ctx.call_after(Duration::from_millis(250)));
+                }
+            }
+            return Control::Continue;
+        }
+
+        fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+            self.esc.retain(|&esc| esc != timer);
+            if let Some(tx) = ctx.tx() {
+                for value in [1, 0] {
+                    tx.send(OutInputEvent::new(EventType::KEY, Key::KEY_ESC.code(), value)).ok();
+                }
+            }
+            return Control::Continue;
+        }
+    }
+
+    #[test]
+    fn timers() {
+        let mut mock = Mock::new(Late { esc: Vec::new() });
+        mock.press(Key::KEY_A);
+        mock.advance(Duration::from_millis(50));
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.advance(Duration::from_millis(50));
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1)]);
+        assert_eq!(mock.elapsed(), Duration::from_millis(100));
+
+        // Everything due on the way fires, in order.
+        mock.advance(Duration::from_secs(1));
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 0), (Key::KEY_ESC, 1), (Key::KEY_ESC, 0)]);
+        assert!(mock.handler().esc.is_empty());
+    }
+
+    #[test]
+    fn per_source() {
+        let mut mock = Mock::with_sources(Passthrough, 2, Output::PerSource);
+        mock.key(1, Key::KEY_B, 1);
+        mock.key(0, Key::KEY_A, 1);
+
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1)]);
+        let second: Vec<u16> = mock.output_for(1).iter().filter(|ev| ev.event_type() == EventType::KEY).map(|ev| ev.code()).collect();
+        assert_eq!(second, vec![Key::KEY_B.code()]);
+    }
+
+    fn frame(events: &[(EventType, u16, i32)]) -> Vec<(u16, u16, i32)> {
+        return events.iter().map(|&(kind, code, value)| (kind.0, code, value)).collect();
+    }
+
+    fn frames<H: Handler>(mock: &mut Mock<H>) -> Vec<Vec<(u16, u16, i32)>> {
+        return mock.frames().iter()
+            .map(|frame| frame.iter().map(|ev| (ev.event_type().0, ev.code(), ev.value())).collect())
+            .collect();
+    }
+
+    #[test]
+    fn framing() {
+        let mut mock = Mock::new(Passthrough);
+        let time = mock.time();
+        mock.feed(0, vec![
+            key_input(&time, Key::KEY_LEFTSHIFT.code(), 1),
+            key_input(&time, Key::KEY_A.code(), 1),
+            syn_input(&time),
+            key_input(&time, Key::KEY_A.code(), 0),
+            syn_input(&time),
+        ]);
+
+        let syn = (EventType::SYNCHRONIZATION, 0, 0);
+        assert_eq!(frames(&mut mock), vec![
+            frame(&[(EventType::KEY, Key::KEY_LEFTSHIFT.code(), 1), (EventType::KEY, Key::KEY_A.code(), 1), syn]),
+            frame(&[(EventType::KEY, Key::KEY_A.code(), 0), syn]),
+        ]);
+    }
+
+    #[test]
+    fn flush() {
+        // A timer that emits a key without a SYN_REPORT still gets one when
+        // the loop flushes.
+        let mut mock = Mock::new(Late { esc: Vec::new() });
+        mock.press(Key::KEY_A);
+        assert_eq!(frames(&mut mock), Vec::<Vec<(u16, u16, i32)>>::new());
+
+        mock.advance(Duration::from_millis(100));
+        let syn = (EventType::SYNCHRONIZATION, 0, 0);
+        assert_eq!(frames(&mut mock), vec![frame(&[(EventType::KEY, Key::KEY_A.code(), 1), syn])]);
+    }
+
+    #[test]
+    fn release_on_stop() {
+        let mut mock = Mock::new(Passthrough);
+        mock.press(Key::KEY_A);
+        mock.press(Key::KEY_B);
+        mock.release(Key::KEY_A);
+        mock.output();
+
+        mock.stop();
+        let syn = (EventType::SYNCHRONIZATION, 0, 0);
+        assert_eq!(frames(&mut mock), vec![frame(&[(EventType::KEY, Key::KEY_B.code(), 0), syn])]);
+
+        // Nothing runs after the loop stopped.
+        assert_eq!(mock.press(Key::KEY_C), Control::Stop);
+        assert_eq!(mock.keys(), vec![]);
+    }
+
+    #[test]
+    fn source() {
+        let mut source = MockSource::new().unwrap();
+        let time = TimeVal::new(1, 0);
+        source.push(key_input(&time, Key::KEY_A.code(), 1));
+
+        assert_eq!(source.next_event().map(|ev| ev.value).ok(), Some(1));
+        assert_eq!(source.next_event().map_err(|e| e.kind()).err(), Some(io::ErrorKind::WouldBlock));
+
+        source.close();
+        assert_eq!(source.next_event().map_err(|e| e.raw_os_error()).err(), Some(Some(Errno::ENODEV as i32)));
+    }
+}
@@ -0,0 +1,329 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use evdev_rs::{ Device, DeviceWrapper, InputEvent as InInputEvent, TimeVal };
+use evdev_rs::enums::{ int_to_event_type, EventCode, EventType as InEventType, InputProp, EV_ABS };
+use evdev_rs::util::int_to_event_code;
+
+use evdev::InputEvent as OutInputEvent;
+
+use crate::{ open_sources, uinput, watch, Capabilities, Control, DeviceSelector, Handler, Mock, NhkError, Options, RecordingError };
+
+// How long a replay keeps running timers after the last recorded event.
+const DRAIN_LIMIT: Duration = Duration::from_secs(60);
+
+fn write_mask(out: &mut dyn Write, prefix: &str, mask: &[u8]) -> io::Result<()> {
+    for chunk in mask.chunks(8) {
+        write!(out, "{}", prefix)?;
+        for i in 0..8 {
+            write!(out, " {:02x}", chunk.get(i).copied().unwrap_or(0))?;
+        }
+        writeln!(out)?;
+    }
+
+    return Ok(());
+}
+
+fn code_mask(dev: &Device, ev_type: InEventType) -> Vec<u8> {
+    let max = InEventType::get_max(&ev_type).unwrap_or(0) as u32;
+    let mut mask = vec![0u8; max as usize / 8 + 1];
+
+    for code in 0..=max {
+        if dev.has_event_code(&int_to_event_code(ev_type as u32, code)) {
+            mask[code as usize / 8] |= 1 << (code % 8);
+        }
+    }
+
+    return mask;
+}
+
+// Writes the device description the way evemu-record does, so the file
+// also works with evemu-device and evemu-play.
+pub fn write_header(out: &mut dyn Write, dev: &Device) -> io::Result<()> {
+    writeln!(out, "# EVEMU 1.3")?;
+    writeln!(out, "# Input device name: \"{}\"", dev.name().unwrap_or(""))?;
+    writeln!(out, "N: {}", dev.name().unwrap_or(""))?;
+    writeln!(out, "I: {:04x} {:04x} {:04x} {:04x}", dev.bustype(), dev.vendor_id(), dev.product_id(), dev.version())?;
+
+    let mut props = vec![0u8; 8];
+    for prop in InputProp::INPUT_PROP_POINTER.iter() {
+        if dev.has_property(&prop) {
+            props[prop as usize / 8] |= 1 << (prop as usize % 8);
+        }
+    }
+    write_mask(out, "P:", &props)?;
+
+    let types: Vec<InEventType> = InEventType::EV_SYN.iter()
+        .filter(|ev_type| *ev_type != InEventType::EV_MAX && dev.has_event_type(ev_type))
+        .collect();
+
+    let mut type_mask = vec![0u8; 4];
+    for ev_type in &types {
+        type_mask[*ev_type as usize / 8] |= 1 << (*ev_type as usize % 8);
+    }
+    write_mask(out, "B: 00", &type_mask)?;
+
+    for ev_type in types.iter().filter(|ev_type| **ev_type != InEventType::EV_SYN) {
+        write_mask(out, &format!("B: {:02x}", *ev_type as u32), &code_mask(dev, *ev_type))?;
+    }
+
+    for code in EventCode::EV_ABS(EV_ABS::ABS_X).iter() {
+        if let (EventCode::EV_ABS(axis), Some(absinfo)) = (code, dev.abs_info(&code)) {
+            writeln!(out, "A: {:02x} {} {} {} {} {}", axis as u32, absinfo.minimum, absinfo.maximum, absinfo.fuzz, absinfo.flat, absinfo.resolution)?;
+        }
+    }
+
+    return Ok(());
+}
+
+fn write_line(out: &mut dyn Write, time: Duration, ev_type: u16, code: u16, value: i32) -> io::Result<()> {
+    write!(out, "E: {}.{:06} {:04x} {:04x} {:04}\t", time.as_secs(), time.subsec_micros(), ev_type, code, value)?;
+
+    let event_type = int_to_event_type(ev_type as u32).filter(|event_type| *event_type != InEventType::EV_UNK);
+    return match event_type {
+        Some(_) if ev_type == 0 && code == 0 => writeln!(out, "# ------------ SYN_REPORT ({}) ----------", value),
+        Some(event_type) => writeln!(out, "# {} / {:<20} {}", event_type, int_to_event_code(ev_type as u32, code as u32), value),
+        None => writeln!(out, "#"),
+    };
+}
+
+pub fn write_event(out: &mut dyn Write, time: Duration, ev: &InInputEvent) -> io::Result<()> {
+    let raw = ev.as_raw();
+    return write_line(out, time, raw.type_, raw.code, raw.value);
+}
+
+pub fn write_output(out: &mut dyn Write, events: &[(Duration, OutInputEvent)]) -> io::Result<()> {
+    writeln!(out, "# EVEMU 1.3")?;
+    for (time, ev) in events {
+        write_line(out, *time, ev.event_type().0, ev.code(), ev.value())?;
+    }
+
+    return Ok(());
+}
+
+fn since(start: &TimeVal, time: &TimeVal) -> Duration {
+    // Wide enough that no pair of timestamps overflows; earlier is zero.
+    let micros = (time.tv_sec as i128 - start.tv_sec as i128) * 1_000_000 + (time.tv_usec as i128 - start.tv_usec as i128);
+    return Duration::from_micros(micros.clamp(0, u64::MAX as i128) as u64);
+}
+
+// Records the first device the selector matches until SIGINT/SIGTERM, with
+// times relative to the first event like evemu-record.
+pub fn record(selector: impl Into<DeviceSelector>, mut out: impl Write) -> Result<(), NhkError> {
+    let mut sources = open_sources(selector)?;
+    sources.truncate(1);
+
+    if let Some(dev) = &sources[0].dev {
+        write_header(&mut out, dev).and_then(|_| out.flush()).map_err(NhkError::Record)?;
+    }
+
+    let mut start: Option<TimeVal> = None;
+    let mut result = Ok(());
+    watch(&mut sources, |_info, ev| {
+        let time = since(start.get_or_insert(ev.time), &ev.time);
+        result = write_event(&mut out, time, &ev).and_then(|_| out.flush());
+        return result.is_err();
+    })?;
+
+    return result.map_err(NhkError::Record);
+}
+
+pub fn parse_events(file: &str, source: &str) -> Result<Vec<InInputEvent>, RecordingError> {
+    let mut events = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let error = |message: &str| RecordingError::Parse { file: file.to_string(), line: i + 1, message: message.to_string() };
+
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first() != Some(&"E:") {
+            continue;
+        }
+        if fields.len() != 5 {
+            return Err(error("expected \"E: <sec>.<usec> <type> <code> <value>\""));
+        }
+
+        let mut time = fields[1].splitn(2, '.');
+        let sec = time.next().and_then(|sec| sec.parse::<i64>().ok());
+        let usec = time.next().and_then(|usec| usec.parse::<i64>().ok());
+        let time = match (sec, usec) {
+            (Some(sec), Some(usec)) if (0..=i64::MAX / 1_000_000).contains(&sec) && (0..1_000_000).contains(&usec) => TimeVal::new(sec, usec),
+            _ => return Err(error(&format!("invalid timestamp \"{}\"", fields[1]))),
+        };
+
+        let ev_type = u16::from_str_radix(fields[2], 16).ok()
+            .filter(|&ev_type| int_to_event_type(ev_type as u32).is_some_and(|ev_type| ev_type != InEventType::EV_UNK && ev_type != InEventType::EV_MAX))
+            .ok_or_else(|| error(&format!("invalid event type \"{}\"", fields[2])))?;
+        let code = u16::from_str_radix(fields[3], 16)
+            .map_err(|_e| error(&format!("invalid event code \"{}\"", fields[3])))?;
+        let value = fields[4].parse::<i32>()
+            .map_err(|_e| error(&format!("invalid value \"{}\"", fields[4])))?;
+
+        events.push(InInputEvent::new(&time, &int_to_event_code(ev_type as u32, code as u32), value));
+    }
+
+    return Ok(events);
+}
+
+pub fn load_events(path: impl AsRef<Path>) -> Result<Vec<InInputEvent>, RecordingError> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+
+    let source = fs::read_to_string(path).map_err(|e| RecordingError::Read(file.clone(), e))?;
+    return parse_events(&file, &source);
+}
+
+// The frames written since the last look, stamped with the current time.
+fn drain<H: Handler>(mock: &mut Mock<H>, output: &mut Vec<(Duration, OutInputEvent)>) {
+    let time = mock.elapsed();
+    output.extend(mock.output().into_iter().map(|ev| (time, ev)));
+}
+
+fn advance_to<H: Handler>(mock: &mut Mock<H>, target: Duration, output: &mut Vec<(Duration, OutInputEvent)>) -> Control {
+    // One timer at a time, so everything it emits is stamped with its own
+    // deadline rather than the end of the step.
+    while let Some(next) = mock.next_timer() {
+        if mock.elapsed() + next > target {
+            break;
+        }
+
+        let control = mock.advance(next);
+        drain(mock, output);
+        if control != Control::Continue {
+            return control;
+        }
+    }
+
+    return mock.advance(target.saturating_sub(mock.elapsed()));
+}
+
+// Feeds recorded events through a handler in the mock read loop, keeping
+// their original spacing on the virtual clock. Keys still held at the end
+// are released like they are when `run` stops.
+pub fn replay<H: Handler>(events: &[InInputEvent], handler: H, raw: bool) -> Vec<(Duration, OutInputEvent)> {
+    let mut mock = Mock::with_options(handler, 1, Options { raw, ..Options::default() });
+    let mut output = Vec::new();
+
+    mock.start();
+    drain(&mut mock, &mut output);
+
+    let start = match events.first() {
+        Some(ev) => ev.time,
+        None => return output,
+    };
+
+    for ev in events {
+        if advance_to(&mut mock, since(&start, &ev.time), &mut output) != Control::Continue {
+            break;
+        }
+
+        let control = mock.push(0, InInputEvent::new(&mock.time(), &ev.event_code, ev.value));
+        drain(&mut mock, &mut output);
+        if control != Control::Continue {
+            break;
+        }
+    }
+
+    let last = mock.elapsed();
+    while let Some(next) = mock.next_timer() {
+        let target = mock.elapsed() + next;
+        if target > last + DRAIN_LIMIT || advance_to(&mut mock, target, &mut output) != Control::Continue {
+            break;
+        }
+    }
+
+    mock.stop();
+    drain(&mut mock, &mut output);
+
+    return output;
+}
+
+// Plays handler output on a real uinput device, in real time.
+pub fn play(events: &[(Duration, OutInputEvent)]) -> Result<(), NhkError> {
+    let mut uinput = uinput::create(Capabilities::Default, &[])?;
+    let start = Instant::now();
+
+    let mut frame = Vec::new();
+    for (time, ev) in events {
+        frame.push(*ev);
+        if !uinput::is_syn_report(ev) {
+            continue;
+        }
+
+        thread::sleep((start + *time).saturating_duration_since(Instant::now()));
+        uinput.write(&frame).map_err(NhkError::Emit)?;
+        frame.clear();
+    }
+
+    if !frame.is_empty() {
+        frame.push(uinput::syn_report());
+        uinput.write(&frame).map_err(NhkError::Emit)?;
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use evdev::{ EventType, Key };
+
+    use super::*;
+    use crate::Passthrough;
+
+    #[test]
+    fn round_trip() {
+        let mut output = Vec::new();
+        for (ms, key, value) in [(0, Key::KEY_A, 1), (120, Key::KEY_A, 0), (1500, Key::KEY_B, 1)] {
+            output.push((Duration::from_millis(ms), OutInputEvent::new(EventType::KEY, key.code(), value)));
+            output.push((Duration::from_millis(ms), OutInputEvent::new(EventType::SYNCHRONIZATION, 0, 0)));
+        }
+
+        let mut text = Vec::new();
+        write_output(&mut text, &output).unwrap();
+        let text = String::from_utf8_lossy(&text);
+        assert!(text.starts_with("# EVEMU 1.3\nE: 0.000000 0001 001e 0001\t# EV_KEY / KEY_A"));
+
+        let parsed = parse_events("t", &text).unwrap();
+        assert_eq!(parsed.len(), output.len());
+
+        // Replaying straight through gives back the same events and times,
+        // and KEY_B, still held at the end, is released when the loop stops.
+        let events = |events: &[(Duration, OutInputEvent)]| -> Vec<(Duration, u16, u16, i32)> {
+            return events.iter().map(|(time, ev)| (*time, ev.event_type().0, ev.code(), ev.value())).collect();
+        };
+        let mut expected = output.clone();
+        expected.push((Duration::from_millis(1500), OutInputEvent::new(EventType::KEY, Key::KEY_B.code(), 0)));
+        expected.push((Duration::from_millis(1500), OutInputEvent::new(EventType::SYNCHRONIZATION, 0, 0)));
+        assert_eq!(events(&replay(&parsed, Passthrough, false)), events(&expected));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |source: &str| parse_events("t", source).err().map(|e| e.to_string());
+
+        assert_eq!(error("# comment\nN: name\n\n"), None);
+        assert_eq!(error("E: 0.000000 0001 001e\n"), Some("t:1: expected \"E: <sec>.<usec> <type> <code> <value>\"".to_string()));
+        assert_eq!(error("\nE: 0.x 0001 001e 1\n"), Some("t:2: invalid timestamp \"0.x\"".to_string()));
+        assert_eq!(error("E: 0.1000000 0001 001e 1\n"), Some("t:1: invalid timestamp \"0.1000000\"".to_string()));
+        assert_eq!(error("E: -1.000000 0001 001e 1\n"), Some("t:1: invalid timestamp \"-1.000000\"".to_string()));
+        assert_eq!(error("E: 0.000000 00ff 001e 1\n"), Some("t:1: invalid event type \"00ff\"".to_string()));
+        assert_eq!(error("E: 0.000000 0001 zz 1\n"), Some("t:1: invalid event code \"zz\"".to_string()));
+        assert_eq!(error("E: 0.000000 0001 001e x\n"), Some("t:1: invalid value \"x\"".to_string()));
+    }
+
+    #[test]
+    fn since_clamps() {
+        let start = TimeVal::new(10, 500_000);
+        assert_eq!(since(&start, &TimeVal::new(11, 0)), Duration::from_millis(500));
+        assert_eq!(since(&start, &TimeVal::new(9, 0)), Duration::from_secs(0));
+        assert_eq!(since(&TimeVal::new(0, 0), &TimeVal::new(i64::MAX, 999_999)), Duration::from_micros(u64::MAX));
+    }
+}
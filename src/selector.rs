@@ -0,0 +1,359 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use evdev_rs::{ Device, DeviceWrapper };
+use evdev_rs::enums::EventType;
+
+use regex::Regex;
+
+use crate::uinput::VIRTUAL_DEVICE_NAME;
+
+const INPUT_DIR: &str = "/dev/input";
+const BY_ID_DIR: &str = "/dev/input/by-id";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub path: String,
+    pub name: String,
+    pub phys: String,
+    pub uniq: String,
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub by_id: Vec<String>,
+    pub capabilities: Vec<String>,
+}
+
+impl DeviceInfo {
+    pub fn new(path: &str, dev: &Device) -> DeviceInfo {
+        return DeviceInfo {
+            path: path.to_string(),
+            name: dev.name().unwrap_or("").to_string(),
+            phys: dev.phys().unwrap_or("").to_string(),
+            uniq: dev.uniq().unwrap_or("").to_string(),
+            bustype: dev.bustype(),
+            vendor: dev.vendor_id(),
+            product: dev.product_id(),
+            by_id: by_id_links(path),
+            capabilities: EventType::EV_KEY.iter()
+                .filter(|ev_type| dev.has_event_type(ev_type))
+                .map(|ev_type| ev_type.to_string())
+                .collect(),
+        };
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: \"{}\" bus {:#06x} id {:04x}:{:04x}", self.path, self.name, self.bustype, self.vendor, self.product)?;
+        if !self.phys.is_empty() {
+            write!(f, " phys {}", self.phys)?;
+        }
+        for link in &self.by_id {
+            write!(f, " by-id {}", link)?;
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DeviceSelector {
+    pub path: Option<String>,
+    pub name: Option<String>,
+    pub name_regex: Option<Regex>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub bustype: Option<u16>,
+    pub phys: Option<String>,
+    pub by_id: Option<String>,
+}
+
+impl DeviceSelector {
+    pub fn path(path: &str) -> DeviceSelector {
+        return DeviceSelector { path: Some(path.to_string()), ..DeviceSelector::default() };
+    }
+
+    pub fn name(name: &str) -> DeviceSelector {
+        return DeviceSelector { name: Some(name.to_string()), ..DeviceSelector::default() };
+    }
+
+    pub fn name_regex(name_regex: Regex) -> DeviceSelector {
+        return DeviceSelector { name_regex: Some(name_regex), ..DeviceSelector::default() };
+    }
+
+    pub fn usb_id(vendor: u16, product: u16) -> DeviceSelector {
+        return DeviceSelector { vendor: Some(vendor), product: Some(product), ..DeviceSelector::default() };
+    }
+
+    pub fn bustype(bustype: u16) -> DeviceSelector {
+        return DeviceSelector { bustype: Some(bustype), ..DeviceSelector::default() };
+    }
+
+    pub fn phys(phys: &str) -> DeviceSelector {
+        return DeviceSelector { phys: Some(phys.to_string()), ..DeviceSelector::default() };
+    }
+
+    pub fn by_id(pattern: &str) -> DeviceSelector {
+        return DeviceSelector { by_id: Some(pattern.to_string()), ..DeviceSelector::default() };
+    }
+
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        return self.path.as_ref().is_none_or(|path| *path == info.path)
+            && self.name.as_ref().is_none_or(|name| info.name.contains(name.as_str()))
+            && self.name_regex.as_ref().is_none_or(|name_regex| name_regex.is_match(&info.name))
+            && self.vendor.is_none_or(|vendor| vendor == info.vendor)
+            && self.product.is_none_or(|product| product == info.product)
+            && self.bustype.is_none_or(|bustype| bustype == info.bustype)
+            && self.phys.as_ref().is_none_or(|phys| info.phys.contains(phys.as_str()))
+            && self.by_id.as_ref().is_none_or(|pattern| info.by_id.iter().any(|link| glob_match(pattern, link)));
+    }
+
+    pub fn select(&self) -> Vec<DeviceInfo> {
+        return list_devices().into_iter().filter(|info| self.matches(info)).collect();
+    }
+}
+
+impl From<String> for DeviceSelector {
+    fn from(path: String) -> DeviceSelector {
+        return DeviceSelector::path(&path);
+    }
+}
+
+impl From<&str> for DeviceSelector {
+    fn from(path: &str) -> DeviceSelector {
+        return DeviceSelector::path(path);
+    }
+}
+
+// Accepts a plain path or a single `kind:value` criterion, e.g.
+// `name:Keychron`, `name-regex:^AT .*keyboard$`, `id:046d:c52b`,
+// `bus:usb`, `phys:usb-0000:00:14.0-1` or `by-id:*-event-kbd`.
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DeviceSelector, String> {
+        if s.starts_with('/') {
+            return Ok(DeviceSelector::path(s));
+        }
+
+        let (kind, value) = match s.find(':') {
+            Some(i) => (&s[..i], &s[i + 1..]),
+            None => return Err(format!("invalid device selector \"{}\"", s)),
+        };
+
+        return match kind {
+            "path" => Ok(DeviceSelector::path(value)),
+            "name" => Ok(DeviceSelector::name(value)),
+            "name-regex" => Regex::new(value)
+                .map(DeviceSelector::name_regex)
+                .map_err(|e| format!("invalid name regex \"{}\": {}", value, e)),
+            "id" => {
+                let mut parts = value.splitn(2, ':');
+                let vendor = parts.next().and_then(|part| u16::from_str_radix(part, 16).ok());
+                let product = parts.next().and_then(|part| u16::from_str_radix(part, 16).ok());
+                match (vendor, product) {
+                    (Some(vendor), Some(product)) => Ok(DeviceSelector::usb_id(vendor, product)),
+                    _ => Err(format!("invalid vendor:product \"{}\"", value)),
+                }
+            },
+            "bus" => parse_bustype(value)
+                .map(DeviceSelector::bustype)
+                .ok_or_else(|| format!("invalid bus type \"{}\"", value)),
+            "phys" => Ok(DeviceSelector::phys(value)),
+            "by-id" => Ok(DeviceSelector::by_id(value)),
+            _ => Err(format!("unknown device selector kind \"{}\"", kind)),
+        };
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(path) = &self.path { parts.push(format!("path:{}", path)); }
+        if let Some(name) = &self.name { parts.push(format!("name:{}", name)); }
+        if let Some(name_regex) = &self.name_regex { parts.push(format!("name-regex:{}", name_regex)); }
+        if let Some(vendor) = self.vendor { parts.push(format!("vendor:{:04x}", vendor)); }
+        if let Some(product) = self.product { parts.push(format!("product:{:04x}", product)); }
+        if let Some(bustype) = self.bustype { parts.push(format!("bus:{:#06x}", bustype)); }
+        if let Some(phys) = &self.phys { parts.push(format!("phys:{}", phys)); }
+        if let Some(by_id) = &self.by_id { parts.push(format!("by-id:{}", by_id)); }
+
+        return match parts.is_empty() {
+            true => write!(f, "any device"),
+            false => write!(f, "{}", parts.join(" ")),
+        };
+    }
+}
+
+fn parse_bustype(value: &str) -> Option<u16> {
+    return match value {
+        "pci" => Some(0x01),
+        "usb" => Some(0x03),
+        "hil" => Some(0x04),
+        "bluetooth" => Some(0x05),
+        "virtual" => Some(0x06),
+        "i8042" => Some(0x11),
+        "rs232" => Some(0x13),
+        "i2c" => Some(0x18),
+        "host" => Some(0x19),
+        _ => match value.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        },
+    };
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    return pattern[p..].iter().all(|c| *c == '*');
+}
+
+fn by_id_links(path: &str) -> Vec<String> {
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_e) => return Vec::new(),
+    };
+
+    let entries = match fs::read_dir(BY_ID_DIR) {
+        Ok(entries) => entries,
+        Err(_e) => return Vec::new(),
+    };
+
+    let mut links: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| fs::canonicalize(entry.path()).is_ok_and(|linked| linked == target))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    links.sort();
+
+    return links;
+}
+
+fn event_number(path: &Path) -> Option<u32> {
+    return path.file_name()?.to_str()?.strip_prefix("event")?.parse().ok();
+}
+
+pub fn list_devices() -> Vec<DeviceInfo> {
+    let entries = match fs::read_dir(INPUT_DIR) {
+        Ok(entries) => entries,
+        Err(_e) => return Vec::new(),
+    };
+
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| event_number(path).is_some())
+        .collect();
+    paths.sort_by_key(|path| event_number(path));
+
+    let mut devices = Vec::new();
+    for path in paths {
+        let path = path.to_string_lossy().into_owned();
+        let dev = match fs::File::open(&path).and_then(Device::new_from_file) {
+            Ok(dev) => dev,
+            Err(_e) => continue,
+        };
+
+        if dev.name() == Some(VIRTUAL_DEVICE_NAME) {
+            continue;
+        }
+
+        devices.push(DeviceInfo::new(&path, &dev));
+    }
+
+    return devices;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*-event-kbd", "usb-Keychron_K2-event-kbd"));
+        assert!(glob_match("usb-*-kbd", "usb-Keychron_K2-event-kbd"));
+        assert!(glob_match("usb-?eychron*", "usb-Keychron_K2-event-kbd"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("**", ""));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("*-event-kbd", "usb-Keychron_K2-event-mouse"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("a*b", "ab-"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn parse() {
+        let parse = |s: &str| DeviceSelector::from_str(s).map(|selector| selector.to_string());
+
+        assert_eq!(parse("/dev/input/event3"), Ok("path:/dev/input/event3".to_string()));
+        assert_eq!(parse("path:/dev/input/event3"), Ok("path:/dev/input/event3".to_string()));
+        assert_eq!(parse("name:AT Translated"), Ok("name:AT Translated".to_string()));
+        assert_eq!(parse("name-regex:^AT .*$"), Ok("name-regex:^AT .*$".to_string()));
+        assert_eq!(parse("id:046d:C52B"), Ok("vendor:046d product:c52b".to_string()));
+        assert_eq!(parse("bus:usb"), Ok("bus:0x0003".to_string()));
+        assert_eq!(parse("bus:0x19"), Ok("bus:0x0019".to_string()));
+        assert_eq!(parse("bus:17"), Ok("bus:0x0011".to_string()));
+        assert_eq!(parse("phys:usb-0000:00:14.0-2/input0"), Ok("phys:usb-0000:00:14.0-2/input0".to_string()));
+        assert_eq!(parse("by-id:*-kbd"), Ok("by-id:*-kbd".to_string()));
+
+        assert_eq!(parse("keyboard"), Err("invalid device selector \"keyboard\"".to_string()));
+        assert_eq!(parse("id:046d"), Err("invalid vendor:product \"046d\"".to_string()));
+        assert_eq!(parse("bus:serial"), Err("invalid bus type \"serial\"".to_string()));
+        assert_eq!(parse("color:red"), Err("unknown device selector kind \"color\"".to_string()));
+        assert!(parse("name-regex:(").is_err_and(|e| e.starts_with("invalid name regex \"(\": ")));
+    }
+
+    #[test]
+    fn matches() {
+        let info = DeviceInfo {
+            path: "/dev/input/event3".to_string(),
+            name: "Keychron K2".to_string(),
+            phys: "usb-0000:00:14.0-2/input0".to_string(),
+            bustype: 0x03,
+            vendor: 0x05ac,
+            product: 0x024f,
+            by_id: vec!["usb-Keychron_K2-event-kbd".to_string()],
+            ..DeviceInfo::default()
+        };
+        let matches = |s: &str| DeviceSelector::from_str(s).is_ok_and(|selector| selector.matches(&info));
+
+        assert!(matches("/dev/input/event3"));
+        assert!(matches("name:Keychron"));
+        assert!(matches("name-regex:^Keychron K\\d$"));
+        assert!(matches("id:05ac:024f"));
+        assert!(matches("bus:usb"));
+        assert!(matches("phys:14.0-2"));
+        assert!(matches("by-id:*-event-kbd"));
+        assert!(DeviceSelector::default().matches(&info));
+
+        assert!(!matches("/dev/input/event4"));
+        assert!(!matches("name:keychron"));
+        assert!(!matches("id:05ac:0250"));
+        assert!(!matches("bus:bluetooth"));
+        assert!(!matches("by-id:*-event-mouse"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::io;
+use std::os::unix::io::{ AsRawFd, RawFd };
+
+use nix::sys::signal::{ SigSet, SigmaskHow, Signal };
+use nix::sys::signalfd::{ SfdFlags, SignalFd };
+
+use crate::error::nix_error;
+
+// Turns SIGINT and SIGTERM into readable events for the read loop, so they
+// stop it like a handler would instead of killing the process with keys
+// still held down. The writer threads are spawned after this and inherit
+// the blocked mask.
+pub(crate) struct Signals {
+    fd: SignalFd,
+    old_mask: SigSet,
+}
+
+impl Signals {
+    pub(crate) fn new() -> io::Result<Signals> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGINT);
+        mask.add(Signal::SIGTERM);
+
+        let fd = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC).map_err(nix_error)?;
+        let old_mask = mask.thread_swap_mask(SigmaskHow::SIG_BLOCK).map_err(nix_error)?;
+
+        return Ok(Signals { fd, old_mask });
+    }
+
+    pub(crate) fn received(&mut self) -> bool {
+        let mut received = false;
+        while let Ok(Some(_siginfo)) = self.fd.read_signal() {
+            received = true;
+        }
+
+        return received;
+    }
+}
+
+impl AsRawFd for Signals {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.fd.as_raw_fd();
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        self.old_mask.thread_set_mask().ok();
+    }
+}
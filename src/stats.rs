@@ -0,0 +1,244 @@
+use std::fmt;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::time::{ Duration, SystemTime };
+
+// 32 sub-buckets per power of two, so every bucket is within about 3% of
+// the values it holds. Values below 64ns get a bucket each.
+const SUB_BITS: u32 = 5;
+const BUCKETS: usize = ((64 - SUB_BITS as usize) << SUB_BITS) + (1 << SUB_BITS);
+
+fn bucket(ns: u64) -> usize {
+    let bits = 64 - ns.leading_zeros();
+    if bits <= SUB_BITS + 1 {
+        return ns as usize;
+    }
+
+    let shift = bits - SUB_BITS - 1;
+    return ((shift as usize) << SUB_BITS) + (ns >> shift) as usize;
+}
+
+// The highest value that lands in the bucket.
+fn bucket_value(index: usize) -> u64 {
+    if index < 2 << SUB_BITS {
+        return index as u64;
+    }
+
+    let shift = (index >> SUB_BITS) - 1;
+    let mantissa = (index & ((1 << SUB_BITS) - 1)) as u64 + (1 << SUB_BITS);
+    // The top bucket ends at u64::MAX, one past it wraps to zero.
+    return ((mantissa + 1) << shift).wrapping_sub(1);
+}
+
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        return Histogram { counts: vec![0; BUCKETS], count: 0, max: 0 };
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let ns = duration.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[bucket(ns)] += 1;
+        self.count += 1;
+        self.max = self.max.max(ns);
+    }
+
+    pub fn count(&self) -> u64 {
+        return self.count;
+    }
+
+    pub fn max(&self) -> Duration {
+        return Duration::from_nanos(self.max);
+    }
+
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let rank = ((percentile.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(bucket_value(index).min(self.max));
+            }
+        }
+
+        return self.max();
+    }
+
+    pub fn p50(&self) -> Duration {
+        return self.percentile(50.0);
+    }
+
+    pub fn p99(&self) -> Duration {
+        return self.percentile(99.0);
+    }
+
+    pub fn clear(&mut self) {
+        *self = Histogram::default();
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.debug_struct("Histogram")
+            .field("count", &self.count)
+            .field("p50", &self.p50())
+            .field("p99", &self.p99())
+            .field("max", &self.max())
+            .finish();
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "p50 {:>9.1?}  p99 {:>9.1?}  max {:>9.1?}  ({} events)", self.p50(), self.p99(), self.max(), self.count);
+    }
+}
+
+// Every stage is measured from the kernel's timestamp on the input event:
+// until the read loop has it, until the handler returns, and until the
+// output it caused has been written to uinput. The last one is per read
+// batch, taken from its oldest event.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub read: Histogram,
+    pub handled: Histogram,
+    pub emitted: Histogram,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "read     {}", self.read)?;
+        writeln!(f, "handled  {}", self.handled)?;
+        return write!(f, "emitted  {}", self.emitted);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Read,
+    Handled,
+    Emitted,
+}
+
+// A handle on the stats of a running loop, cheap to clone and to hand to
+// Options before the run.
+#[derive(Clone, Debug, Default)]
+pub struct Latency {
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Latency {
+    pub fn new() -> Latency {
+        return Latency::default();
+    }
+
+    fn stats(&self) -> MutexGuard<'_, Stats> {
+        return match self.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        return self.stats().clone();
+    }
+
+    pub fn reset(&self) {
+        *self.stats() = Stats::default();
+    }
+
+    pub(crate) fn record(&self, stage: Stage, duration: Duration) {
+        let mut stats = self.stats();
+        match stage {
+            Stage::Read => stats.read.record(duration),
+            Stage::Handled => stats.handled.record(duration),
+            Stage::Emitted => stats.emitted.record(duration),
+        }
+    }
+
+    // Kernel timestamps are on the realtime clock, a sample that would be
+    // negative because the clock was stepped back is dropped.
+    pub(crate) fn record_since(&self, stage: Stage, since: SystemTime) {
+        if let Ok(duration) = SystemTime::now().duration_since(since) {
+            self.record(stage, duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        // Exact below 64ns, then every value is at most its bucket's top
+        // and within about 3% of it.
+        for ns in (0..100_000).chain((0..64).map(|shift| 1u64 << shift)).chain([u64::MAX - 1, u64::MAX]) {
+            let top = bucket_value(bucket(ns));
+            assert!(top >= ns, "ns={} top={}", ns, top);
+            assert!(ns < 64 || (top - ns) as f64 <= ns as f64 / 32.0, "ns={} top={}", ns, top);
+            assert!(bucket(ns) < BUCKETS);
+        }
+        assert!((1..BUCKETS).all(|index| bucket_value(index) > bucket_value(index - 1)));
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.p50(), Duration::from_secs(0));
+
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.max(), Duration::from_millis(100));
+
+        let near = |actual: Duration, expected: u64| {
+            let expected = Duration::from_millis(expected);
+            return actual >= expected && actual.as_secs_f64() <= expected.as_secs_f64() * 1.035;
+        };
+        assert!(near(histogram.p50(), 50), "{:?}", histogram);
+        assert!(near(histogram.p99(), 99), "{:?}", histogram);
+        assert_eq!(histogram.percentile(100.0), Duration::from_millis(100));
+        assert!(near(histogram.percentile(0.0), 1), "{:?}", histogram);
+
+        histogram.clear();
+        assert_eq!(histogram.count(), 0);
+    }
+
+    #[test]
+    fn small_values_exact() {
+        let mut histogram = Histogram::default();
+        for ns in [3, 5, 7, 9] {
+            histogram.record(Duration::from_nanos(ns));
+        }
+        assert_eq!(histogram.p50(), Duration::from_nanos(5));
+        assert_eq!(histogram.p99(), Duration::from_nanos(9));
+    }
+
+    #[test]
+    fn latency() {
+        let latency = Latency::new();
+        latency.record(Stage::Read, Duration::from_micros(10));
+        latency.record(Stage::Emitted, Duration::from_micros(30));
+        latency.clone().record(Stage::Emitted, Duration::from_micros(20));
+
+        let stats = latency.snapshot();
+        assert_eq!((stats.read.count(), stats.handled.count(), stats.emitted.count()), (1, 0, 2));
+        assert_eq!(stats.emitted.max(), Duration::from_micros(30));
+
+        latency.reset();
+        assert_eq!(latency.snapshot().emitted.count(), 0);
+    }
+}
@@ -0,0 +1,501 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use evdev_rs::{ InputEvent as InInputEvent, TimeVal };
+
+use evdev::Key;
+
+use crate::{ key_code, key_input, syn_input, Context, Control, DeviceInfo, Handler, Reply, TimerId };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    // Down, and whether another key was pressed meanwhile.
+    Held(bool),
+    Armed,
+    Locked,
+}
+
+struct Entry {
+    key: Key,
+    state: State,
+    timer: Option<TimerId>,
+}
+
+fn is_modifier(key: Key) -> bool {
+    return matches!(key,
+        Key::KEY_LEFTSHIFT | Key::KEY_RIGHTSHIFT | Key::KEY_LEFTCTRL | Key::KEY_RIGHTCTRL |
+        Key::KEY_LEFTALT | Key::KEY_RIGHTALT | Key::KEY_LEFTMETA | Key::KEY_RIGHTMETA);
+}
+
+fn is_letter(key: Key) -> bool {
+    return matches!(key.code(), 16..=25 | 30..=38 | 44..=50);
+}
+
+// Modifier state that outlives the keys that set it: sticky modifiers,
+// which stay down for the next key press, and Caps Word, which shifts
+// letters until a key that can't be part of a word. Each call returns the
+// key events to send for it.
+pub(crate) struct Modifiers {
+    sticky: Vec<Entry>,
+    timeout: Duration,
+    // Whether Caps Word is on, and whether it is holding shift.
+    caps_word: Option<bool>,
+}
+
+impl Default for Modifiers {
+    fn default() -> Modifiers {
+        return Modifiers { sticky: Vec::new(), timeout: Duration::from_secs(3), caps_word: None };
+    }
+}
+
+impl Modifiers {
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    // A tap arms the modifier for the next key press, a second tap locks it
+    // and a third unlocks it. Held down, it is an ordinary modifier.
+    pub(crate) fn press(&mut self, key: Key, ctx: &mut Context) -> Vec<(u16, i32)> {
+        let index = match self.sticky.iter().position(|sticky| sticky.key == key) {
+            Some(index) => index,
+            None => {
+                self.sticky.push(Entry { key, state: State::Held(false), timer: None });
+                return vec![(key.code(), 1)];
+            },
+        };
+
+        let sticky = &mut self.sticky[index];
+        return match sticky.state {
+            State::Armed => {
+                if let Some(timer) = sticky.timer.take() {
+                    ctx.cancel(timer);
+                }
+                sticky.state = State::Locked;
+                Vec::new()
+            },
+            State::Locked => {
+                self.sticky.remove(index);
+                vec![(key.code(), 0)]
+            },
+            State::Held(_) => Vec::new(),
+        };
+    }
+
+    pub(crate) fn release(&mut self, key: Key, ctx: &mut Context) -> Vec<(u16, i32)> {
+        let index = match self.sticky.iter().position(|sticky| sticky.key == key) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+
+        let timeout = self.timeout;
+        let sticky = &mut self.sticky[index];
+        return match sticky.state {
+            State::Held(false) => {
+                sticky.state = State::Armed;
+                sticky.timer = Some(ctx.call_after(timeout));
+                Vec::new()
+            },
+            State::Held(true) => {
+                self.sticky.remove(index);
+                vec![(key.code(), 0)]
+            },
+            State::Armed | State::Locked => Vec::new(),
+        };
+    }
+
+    pub(crate) fn timeout(&mut self, timer: TimerId) -> Option<Vec<(u16, i32)>> {
+        let index = self.sticky.iter().position(|sticky| sticky.timer == Some(timer))?;
+        let sticky = self.sticky.remove(index);
+
+        return Some(vec![(sticky.key.code(), 0)]);
+    }
+
+    // Before a key press goes out: Caps Word shifts it, leaves it alone or
+    // ends.
+    pub(crate) fn before_key(&mut self, key: Key) -> Vec<(u16, i32)> {
+        let shifted = match self.caps_word {
+            Some(shifted) => shifted,
+            None => return Vec::new(),
+        };
+        if is_modifier(key) {
+            return Vec::new();
+        }
+
+        let shift = is_letter(key) || key == Key::KEY_MINUS;
+        let word = shift || matches!(key.code(), 2..=11) || key == Key::KEY_BACKSPACE || key == Key::KEY_DELETE;
+
+        self.caps_word = match word {
+            true => Some(shift),
+            false => None,
+        };
+        return match (shifted, shift) {
+            (false, true) => vec![(Key::KEY_LEFTSHIFT.code(), 1)],
+            (true, false) => vec![(Key::KEY_LEFTSHIFT.code(), 0)],
+            _ => Vec::new(),
+        };
+    }
+
+    // After a key press went out: armed modifiers have been used up.
+    pub(crate) fn after_key(&mut self, key: Key, ctx: &mut Context) -> Vec<(u16, i32)> {
+        if is_modifier(key) {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        self.sticky.retain_mut(|sticky| match sticky.state {
+            State::Armed => {
+                if let Some(timer) = sticky.timer.take() {
+                    ctx.cancel(timer);
+                }
+                events.push((sticky.key.code(), 0));
+                false
+            },
+            State::Held(_) => {
+                sticky.state = State::Held(true);
+                true
+            },
+            State::Locked => true,
+        });
+
+        return events;
+    }
+
+    pub(crate) fn toggle_caps_word(&mut self) -> Vec<(u16, i32)> {
+        return match self.caps_word.take() {
+            Some(true) => vec![(Key::KEY_LEFTSHIFT.code(), 0)],
+            Some(false) => Vec::new(),
+            None => {
+                self.caps_word = Some(false);
+                Vec::new()
+            },
+        };
+    }
+
+    pub(crate) fn clear(&mut self, ctx: &mut Context) -> Vec<(u16, i32)> {
+        let mut events = Vec::new();
+        for sticky in self.sticky.drain(..) {
+            if let Some(timer) = sticky.timer {
+                ctx.cancel(timer);
+            }
+            events.push((sticky.key.code(), 0));
+        }
+        if self.caps_word.take() == Some(true) {
+            events.push((Key::KEY_LEFTSHIFT.code(), 0));
+        }
+
+        return events;
+    }
+
+    pub(crate) fn status(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if !self.sticky.is_empty() {
+            let sticky: Vec<String> = self.sticky.iter().map(|sticky| {
+                let state = match sticky.state {
+                    State::Held(_) => "held",
+                    State::Armed => "armed",
+                    State::Locked => "locked",
+                };
+                format!("{:?}:{}", sticky.key, state)
+            }).collect();
+            lines.push(format!("sticky={}", sticky.join(",")));
+        }
+        if self.caps_word.is_some() {
+            lines.push("caps-word=on".to_string());
+        }
+
+        return lines;
+    }
+}
+
+// Sticky modifiers and Caps Word on their own, for handlers without layers.
+// The sticky keys are modifiers that behave like Action::Sticky, the Caps
+// Word key toggles it like Action::CapsWord. Everything else goes to the
+// inner handler, shifted or with the armed modifiers around it.
+pub struct Sticky<H> {
+    inner: H,
+    keys: Vec<Key>,
+    caps_word: Option<Key>,
+    modifiers: Modifiers,
+}
+
+impl<H: Handler> Sticky<H> {
+    pub fn new(inner: H) -> Sticky<H> {
+        return Sticky { inner, keys: Vec::new(), caps_word: None, modifiers: Modifiers::default() };
+    }
+
+    pub fn key(mut self, key: Key) -> Sticky<H> {
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+        return self;
+    }
+
+    pub fn caps_word(mut self, key: Key) -> Sticky<H> {
+        self.caps_word = Some(key);
+        return self;
+    }
+
+    // How long a tapped modifier waits for the next key.
+    pub fn timeout(mut self, timeout: Duration) -> Sticky<H> {
+        self.modifiers.set_timeout(timeout);
+        return self;
+    }
+
+    fn send(&mut self, time: &TimeVal, events: &[(u16, i32)], ctx: &mut Context) -> Control {
+        for &(code, value) in events {
+            let control = self.inner.on_event(key_input(time, code, value), ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+
+    // Ends the frame after events that didn't come with one.
+    fn send_frame(&mut self, time: &TimeVal, events: &[(u16, i32)], ctx: &mut Context) -> Control {
+        if events.is_empty() {
+            return Control::Continue;
+        }
+
+        return match self.send(time, events, ctx) {
+            Control::Continue => self.inner.on_event(syn_input(time), ctx),
+            control => control,
+        };
+    }
+
+    fn dispatch(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        let key = match key_code(&ev) {
+            Some(code) => Key::new(code),
+            None => return self.inner.on_event(ev, ctx),
+        };
+
+        if self.keys.contains(&key) {
+            let events = match ev.value {
+                1 => self.modifiers.press(key, ctx),
+                0 => self.modifiers.release(key, ctx),
+                _ => Vec::new(),
+            };
+            return self.send(&ev.time, &events, ctx);
+        }
+
+        if self.caps_word == Some(key) {
+            let events = match ev.value {
+                1 => self.modifiers.toggle_caps_word(),
+                _ => Vec::new(),
+            };
+            return self.send(&ev.time, &events, ctx);
+        }
+
+        if ev.value != 1 {
+            return self.inner.on_event(ev, ctx);
+        }
+
+        let time = ev.time;
+        let before = self.modifiers.before_key(key);
+        let control = match self.send(&time, &before, ctx) {
+            Control::Continue => self.inner.on_event(ev, ctx),
+            control => control,
+        };
+        if control != Control::Continue {
+            return control;
+        }
+
+        let after = self.modifiers.after_key(key, ctx);
+        return self.send_frame(&time, &after, ctx);
+    }
+}
+
+impl<H: Handler> Handler for Sticky<H> {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return self.dispatch(ev, ctx);
+    }
+
+    fn on_start(&mut self, ctx: &mut Context) {
+        self.inner.on_start(ctx);
+    }
+
+    fn on_stop(&mut self, ctx: &mut Context) {
+        let time = TimeVal::try_from(ctx.wall_clock()).unwrap_or_else(|_| TimeVal::new(0, 0));
+        let events = self.modifiers.clear(ctx);
+        self.send_frame(&time, &events, ctx);
+        self.inner.on_stop(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) -> Control {
+        return self.inner.on_tick(ctx);
+    }
+
+    fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+        return match self.modifiers.timeout(timer) {
+            Some(events) => {
+                let time = TimeVal::try_from(ctx.wall_clock()).unwrap_or_else(|_| TimeVal::new(0, 0));
+                self.send_frame(&time, &events, ctx)
+            },
+            None => self.inner.on_timer(timer, ctx),
+        };
+    }
+
+    fn on_device_added(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_added(info, ctx);
+    }
+
+    fn on_device_removed(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_removed(info, ctx);
+    }
+
+    fn on_command(&mut self, command: &[&str], ctx: &mut Context) -> Option<Reply> {
+        let inner = self.inner.on_command(command, ctx);
+        let status = self.modifiers.status();
+
+        return match command {
+            ["status"] if !status.is_empty() => {
+                let mut lines = match inner {
+                    Some(Ok(lines)) => lines,
+                    _ => Vec::new(),
+                };
+                lines.extend(status);
+                Some(Ok(lines))
+            },
+            _ => inner,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ Action, Layer, Layers, Mock, Passthrough };
+
+    #[test]
+    fn next_key() {
+        let layer = Layer::new().map(Key::KEY_LEFTSHIFT, Action::Sticky(Key::KEY_LEFTSHIFT));
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(layer));
+
+        mock.tap(Key::KEY_LEFTSHIFT);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 1)]);
+
+        mock.tap(Key::KEY_A);
+        mock.tap(Key::KEY_B);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_LEFTSHIFT, 0), (Key::KEY_A, 0), (Key::KEY_B, 1), (Key::KEY_B, 0)]);
+    }
+
+    #[test]
+    fn held() {
+        let layer = Layer::new().map(Key::KEY_LEFTSHIFT, Action::Sticky(Key::KEY_LEFTSHIFT));
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(layer));
+
+        mock.press(Key::KEY_LEFTSHIFT);
+        mock.tap(Key::KEY_A);
+        mock.tap(Key::KEY_B);
+        mock.release(Key::KEY_LEFTSHIFT);
+        assert_eq!(mock.keys(), vec![
+            (Key::KEY_LEFTSHIFT, 1),
+            (Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_B, 1), (Key::KEY_B, 0),
+            (Key::KEY_LEFTSHIFT, 0),
+        ]);
+    }
+
+    #[test]
+    fn lock() {
+        let layer = Layer::new().map(Key::KEY_LEFTSHIFT, Action::Sticky(Key::KEY_LEFTSHIFT));
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(layer).sticky_timeout(Duration::from_secs(1)));
+
+        mock.tap(Key::KEY_LEFTSHIFT);
+        mock.tap(Key::KEY_LEFTSHIFT);
+        let status = mock.command(&["status"]).unwrap_or_default();
+        assert!(status.contains(&"sticky=KEY_LEFTSHIFT:locked".to_string()));
+
+        mock.advance(Duration::from_secs(5));
+        mock.tap(Key::KEY_A);
+        mock.tap(Key::KEY_B);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 1), (Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_B, 1), (Key::KEY_B, 0)]);
+
+        mock.tap(Key::KEY_LEFTSHIFT);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 0)]);
+    }
+
+    #[test]
+    fn timeout() {
+        let layer = Layer::new().map(Key::KEY_LEFTSHIFT, Action::Sticky(Key::KEY_LEFTSHIFT));
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(layer).sticky_timeout(Duration::from_secs(1)));
+
+        mock.tap(Key::KEY_LEFTSHIFT);
+        mock.advance(Duration::from_millis(999));
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 1)]);
+
+        mock.advance(Duration::from_millis(1));
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 0)]);
+
+        mock.tap(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0)]);
+    }
+
+    #[test]
+    fn caps_word() {
+        let layer = Layer::new().map(Key::KEY_CAPSLOCK, Action::CapsWord);
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(layer));
+
+        mock.tap(Key::KEY_CAPSLOCK);
+        mock.tap(Key::KEY_A);
+        mock.tap(Key::KEY_1);
+        mock.tap(Key::KEY_B);
+        assert_eq!(mock.keys(), vec![
+            (Key::KEY_LEFTSHIFT, 1), (Key::KEY_A, 1), (Key::KEY_A, 0),
+            (Key::KEY_LEFTSHIFT, 0), (Key::KEY_1, 1), (Key::KEY_1, 0),
+            (Key::KEY_LEFTSHIFT, 1), (Key::KEY_B, 1), (Key::KEY_B, 0),
+        ]);
+
+        // A key that can't be part of a word ends it.
+        mock.tap(Key::KEY_SPACE);
+        mock.tap(Key::KEY_C);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTSHIFT, 0), (Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0), (Key::KEY_C, 1), (Key::KEY_C, 0)]);
+    }
+
+    #[test]
+    fn caps_word_toggled_off() {
+        let layer = Layer::new().map(Key::KEY_CAPSLOCK, Action::CapsWord);
+        let mut mock = Mock::new(Layers::new(Passthrough).layer(layer));
+
+        mock.tap(Key::KEY_CAPSLOCK);
+        mock.tap(Key::KEY_A);
+        mock.tap(Key::KEY_CAPSLOCK);
+        mock.tap(Key::KEY_B);
+        assert_eq!(mock.keys(), vec![
+            (Key::KEY_LEFTSHIFT, 1), (Key::KEY_A, 1), (Key::KEY_A, 0),
+            (Key::KEY_LEFTSHIFT, 0), (Key::KEY_B, 1), (Key::KEY_B, 0),
+        ]);
+    }
+
+    #[test]
+    fn wrapper() {
+        let sticky = Sticky::new(Passthrough)
+            .key(Key::KEY_LEFTCTRL)
+            .caps_word(Key::KEY_CAPSLOCK)
+            .timeout(Duration::from_secs(1));
+        let mut mock = Mock::new(sticky);
+
+        mock.tap(Key::KEY_LEFTCTRL);
+        let status = mock.command(&["status"]).unwrap_or_default();
+        assert!(status.contains(&"sticky=KEY_LEFTCTRL:armed".to_string()));
+
+        mock.tap(Key::KEY_C);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1), (Key::KEY_C, 1), (Key::KEY_LEFTCTRL, 0), (Key::KEY_C, 0)]);
+
+        mock.tap(Key::KEY_CAPSLOCK);
+        mock.tap(Key::KEY_A);
+        mock.tap(Key::KEY_SPACE);
+        assert_eq!(mock.keys(), vec![
+            (Key::KEY_LEFTSHIFT, 1), (Key::KEY_A, 1), (Key::KEY_A, 0),
+            (Key::KEY_LEFTSHIFT, 0), (Key::KEY_SPACE, 1), (Key::KEY_SPACE, 0),
+        ]);
+
+        // Whatever is still down is let go when the loop stops.
+        mock.tap(Key::KEY_LEFTCTRL);
+        mock.tap(Key::KEY_LEFTCTRL);
+        mock.output();
+        mock.stop();
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 0)]);
+    }
+}
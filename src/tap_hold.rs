@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::time::{ Duration, SystemTime };
+
+use evdev_rs::{ InputEvent as InInputEvent, TimeVal };
+
+use evdev::Key;
+
+use crate::{ key_code, key_input, syn_input, Context, Control, DeviceInfo, Handler, Reply, TimerId };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TapHoldMode {
+    TapPreferred,
+    HoldOnOtherKeyPress,
+    PermissiveHold,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TapHoldConfig {
+    pub tapping_term: Duration,
+    pub mode: TapHoldMode,
+    pub retro_tap: bool,
+}
+
+impl Default for TapHoldConfig {
+    fn default() -> TapHoldConfig {
+        return TapHoldConfig {
+            tapping_term: Duration::from_millis(200),
+            mode: TapHoldMode::TapPreferred,
+            retro_tap: false,
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Tap,
+    Hold,
+}
+
+pub(crate) struct Resolved<T> {
+    pub(crate) code: u16,
+    pub(crate) payload: T,
+    pub(crate) decision: Decision,
+    pub(crate) retro_tap: bool,
+    pub(crate) time: TimeVal,
+    pub(crate) buffer: Vec<InInputEvent>,
+}
+
+struct Pending<T> {
+    code: u16,
+    payload: T,
+    config: TapHoldConfig,
+    timer: Option<TimerId>,
+    time: TimeVal,
+    pressed: Vec<u16>,
+    buffer: Vec<InInputEvent>,
+}
+
+// Decides whether a dual-role key was tapped or held. Everything that
+// arrives while the decision is pending is buffered and handed back with
+// the decision, for the caller to replay in order.
+pub(crate) struct Resolver<T> {
+    pending: Option<Pending<T>>,
+}
+
+impl<T> Default for Resolver<T> {
+    fn default() -> Resolver<T> {
+        return Resolver { pending: None };
+    }
+}
+
+pub(crate) fn age(time: &TimeVal, now: SystemTime) -> Duration {
+    let time: Result<SystemTime, _> = (*time).try_into();
+
+    return match time {
+        Ok(time) => now.duration_since(time).unwrap_or_default(),
+        Err(_e) => Duration::from_secs(0),
+    };
+}
+
+impl<T> Resolver<T> {
+    pub(crate) fn is_pending(&self) -> bool {
+        return self.pending.is_some();
+    }
+
+    pub(crate) fn start(&mut self, ev: &InInputEvent, payload: T, config: TapHoldConfig, ctx: &mut Context) -> Option<Resolved<T>> {
+        let code = key_code(ev)?;
+        let mut pending = Pending {
+            code,
+            payload,
+            config,
+            timer: None,
+            time: ev.time,
+            pressed: Vec::new(),
+            buffer: Vec::new(),
+        };
+
+        // Replayed presses may already be older than the tapping term.
+        let remaining = config.tapping_term.saturating_sub(age(&ev.time, ctx.wall_clock()));
+        if remaining.as_millis() == 0 {
+            return Some(resolve(pending, Decision::Hold, true));
+        }
+
+        pending.timer = Some(ctx.call_after(remaining));
+        self.pending = Some(pending);
+
+        return None;
+    }
+
+    pub(crate) fn feed(&mut self, ev: InInputEvent, ctx: &mut Context) -> Option<Resolved<T>> {
+        let pending = self.pending.as_mut()?;
+
+        let decision = match key_code(&ev) {
+            Some(code) if code == pending.code => match ev.value {
+                0 => Some(Decision::Tap),
+                _ => None,
+            },
+            Some(code) => {
+                let decision = match ev.value {
+                    1 => {
+                        pending.pressed.push(code);
+                        match pending.config.mode {
+                            TapHoldMode::HoldOnOtherKeyPress => Some(Decision::Hold),
+                            _ => None,
+                        }
+                    },
+                    0 if pending.config.mode == TapHoldMode::PermissiveHold && pending.pressed.contains(&code) => Some(Decision::Hold),
+                    _ => None,
+                };
+                pending.buffer.push(ev);
+                decision
+            },
+            None => {
+                pending.buffer.push(ev);
+                None
+            },
+        };
+
+        let decision = decision?;
+        let pending = self.pending.take()?;
+        if let Some(timer) = pending.timer {
+            ctx.cancel(timer);
+        }
+
+        return Some(resolve(pending, decision, false));
+    }
+
+    pub(crate) fn timeout(&mut self, timer: TimerId) -> Option<Resolved<T>> {
+        if self.pending.as_ref()?.timer != Some(timer) {
+            return None;
+        }
+
+        let pending = self.pending.take()?;
+        return Some(resolve(pending, Decision::Hold, true));
+    }
+}
+
+fn resolve<T>(pending: Pending<T>, decision: Decision, timed_out: bool) -> Resolved<T> {
+    return Resolved {
+        code: pending.code,
+        payload: pending.payload,
+        decision,
+        retro_tap: pending.config.retro_tap && timed_out && pending.pressed.is_empty(),
+        time: pending.time,
+        buffer: pending.buffer,
+    };
+}
+
+struct Held {
+    code: u16,
+    hold: Key,
+    retro_tap: Option<Key>,
+}
+
+// Rewrites the input stream of the wrapped handler: a dual-role key reaches
+// it as either its tap key or its hold key, once that has been decided.
+pub struct TapHold<H> {
+    inner: H,
+    keys: HashMap<u16, (Key, Key, TapHoldConfig)>,
+    resolver: Resolver<(Key, Key)>,
+    held: Vec<Held>,
+}
+
+impl<H: Handler> TapHold<H> {
+    pub fn new(inner: H) -> TapHold<H> {
+        return TapHold {
+            inner,
+            keys: HashMap::new(),
+            resolver: Resolver::default(),
+            held: Vec::new(),
+        };
+    }
+
+    pub fn key(self, key: Key, tap: Key, hold: Key) -> TapHold<H> {
+        return self.key_with(key, tap, hold, TapHoldConfig::default());
+    }
+
+    pub fn key_with(mut self, key: Key, tap: Key, hold: Key, config: TapHoldConfig) -> TapHold<H> {
+        self.keys.insert(key.code(), (tap, hold, config));
+        return self;
+    }
+
+    fn dispatch(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        if self.resolver.is_pending() {
+            return match self.resolver.feed(ev, ctx) {
+                Some(resolved) => self.apply(resolved, ctx),
+                None => Control::Continue,
+            };
+        }
+
+        let code = match key_code(&ev) {
+            Some(code) => code,
+            None => return self.inner.on_event(ev, ctx),
+        };
+
+        if let Some(index) = self.held.iter().position(|held| held.code == code) {
+            if ev.value != 0 {
+                return self.inner.on_event(key_input(&ev.time, self.held[index].hold.code(), ev.value), ctx);
+            }
+
+            let held = self.held.remove(index);
+            let control = self.inner.on_event(key_input(&ev.time, held.hold.code(), 0), ctx);
+            return match held.retro_tap {
+                Some(tap) if control == Control::Continue => self.tap(&ev.time, tap, ctx),
+                _ => control,
+            };
+        }
+
+        if ev.value == 1 {
+            for held in self.held.iter_mut() {
+                held.retro_tap = None;
+            }
+        }
+
+        if let Some(&(tap, hold, config)) = self.keys.get(&code) {
+            return match ev.value {
+                1 => match self.resolver.start(&ev, (tap, hold), config, ctx) {
+                    Some(resolved) => self.apply(resolved, ctx),
+                    None => Control::Continue,
+                },
+                _ => Control::Continue,
+            };
+        }
+
+        return self.inner.on_event(ev, ctx);
+    }
+
+    fn tap(&mut self, time: &TimeVal, key: Key, ctx: &mut Context) -> Control {
+        for ev in [key_input(time, key.code(), 1), syn_input(time), key_input(time, key.code(), 0), syn_input(time)] {
+            let control = self.inner.on_event(ev, ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+
+    fn apply(&mut self, resolved: Resolved<(Key, Key)>, ctx: &mut Context) -> Control {
+        let (tap, hold) = resolved.payload;
+
+        let control = match resolved.decision {
+            Decision::Tap => self.tap(&resolved.time, tap, ctx),
+            Decision::Hold => {
+                self.held.push(Held {
+                    code: resolved.code,
+                    hold,
+                    retro_tap: match resolved.retro_tap { true => Some(tap), false => None },
+                });
+
+                match self.inner.on_event(key_input(&resolved.time, hold.code(), 1), ctx) {
+                    Control::Continue => self.inner.on_event(syn_input(&resolved.time), ctx),
+                    control => control,
+                }
+            },
+        };
+
+        if control != Control::Continue {
+            return control;
+        }
+
+        for ev in resolved.buffer {
+            let control = self.dispatch(ev, ctx);
+            if control != Control::Continue {
+                return control;
+            }
+        }
+
+        return Control::Continue;
+    }
+}
+
+impl<H: Handler> Handler for TapHold<H> {
+    fn on_event(&mut self, ev: InInputEvent, ctx: &mut Context) -> Control {
+        return self.dispatch(ev, ctx);
+    }
+
+    fn on_start(&mut self, ctx: &mut Context) {
+        self.inner.on_start(ctx);
+    }
+
+    fn on_stop(&mut self, ctx: &mut Context) {
+        self.inner.on_stop(ctx);
+    }
+
+    fn on_tick(&mut self, ctx: &mut Context) -> Control {
+        return self.inner.on_tick(ctx);
+    }
+
+    fn on_timer(&mut self, timer: TimerId, ctx: &mut Context) -> Control {
+        return match self.resolver.timeout(timer) {
+            Some(resolved) => self.apply(resolved, ctx),
+            None => self.inner.on_timer(timer, ctx),
+        };
+    }
+
+    fn on_device_added(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_added(info, ctx);
+    }
+
+    fn on_device_removed(&mut self, info: &DeviceInfo, ctx: &mut Context) {
+        self.inner.on_device_removed(info, ctx);
+    }
+
+    fn on_command(&mut self, command: &[&str], ctx: &mut Context) -> Option<Reply> {
+        return self.inner.on_command(command, ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{ Mock, Passthrough };
+    use crate::timer::Timers;
+
+    #[test]
+    fn tap() {
+        let mut timers = Timers::default();
+        let mut ctx = Context::new(0, Instant::now(), SystemTime::now(), &[], &mut timers);
+        let time = TimeVal::try_from(SystemTime::now()).unwrap();
+        let mut resolver = Resolver::default();
+
+        assert!(resolver.start(&key_input(&time, Key::KEY_A.code(), 1), (), TapHoldConfig::default(), &mut ctx).is_none());
+        assert!(resolver.feed(key_input(&time, Key::KEY_B.code(), 1), &mut ctx).is_none());
+
+        let resolved = resolver.feed(key_input(&time, Key::KEY_A.code(), 0), &mut ctx).unwrap();
+        assert_eq!(resolved.decision, Decision::Tap);
+        assert_eq!(resolved.buffer.len(), 1);
+        assert!(!resolver.is_pending());
+
+        // The decision took its timer with it.
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn hold_on_timeout() {
+        let mut timers = Timers::default();
+        let mut ctx = Context::new(0, Instant::now(), SystemTime::now(), &[], &mut timers);
+        let time = TimeVal::try_from(SystemTime::now()).unwrap();
+        let mut resolver = Resolver::default();
+        let config = TapHoldConfig { retro_tap: true, ..TapHoldConfig::default() };
+
+        resolver.start(&key_input(&time, Key::KEY_A.code(), 1), (), config, &mut ctx);
+        let timer = resolver.pending.as_ref().and_then(|pending| pending.timer).unwrap();
+        assert!(resolver.timeout(timer).is_some_and(|resolved| resolved.decision == Decision::Hold && resolved.retro_tap));
+        assert!(resolver.timeout(timer).is_none());
+    }
+
+    #[test]
+    fn no_retro_tap_after_other_key() {
+        let mut timers = Timers::default();
+        let mut ctx = Context::new(0, Instant::now(), SystemTime::now(), &[], &mut timers);
+        let time = TimeVal::try_from(SystemTime::now()).unwrap();
+        let mut resolver = Resolver::default();
+        let config = TapHoldConfig { retro_tap: true, ..TapHoldConfig::default() };
+
+        resolver.start(&key_input(&time, Key::KEY_A.code(), 1), (), config, &mut ctx);
+        resolver.feed(key_input(&time, Key::KEY_B.code(), 1), &mut ctx);
+        let timer = resolver.pending.as_ref().and_then(|pending| pending.timer).unwrap();
+        assert!(resolver.timeout(timer).is_some_and(|resolved| resolved.decision == Decision::Hold && !resolved.retro_tap));
+    }
+
+    #[test]
+    fn hold_on_other_key_press() {
+        let mut timers = Timers::default();
+        let mut ctx = Context::new(0, Instant::now(), SystemTime::now(), &[], &mut timers);
+        let time = TimeVal::try_from(SystemTime::now()).unwrap();
+        let mut resolver = Resolver::default();
+        let config = TapHoldConfig { mode: TapHoldMode::HoldOnOtherKeyPress, ..TapHoldConfig::default() };
+
+        resolver.start(&key_input(&time, Key::KEY_A.code(), 1), (), config, &mut ctx);
+        assert!(resolver.feed(syn_input(&time), &mut ctx).is_none());
+
+        let resolved = resolver.feed(key_input(&time, Key::KEY_B.code(), 1), &mut ctx).unwrap();
+        assert_eq!(resolved.decision, Decision::Hold);
+        assert_eq!(resolved.buffer.len(), 2);
+    }
+
+    #[test]
+    fn permissive_hold() {
+        let mut timers = Timers::default();
+        let mut ctx = Context::new(0, Instant::now(), SystemTime::now(), &[], &mut timers);
+        let time = TimeVal::try_from(SystemTime::now()).unwrap();
+        let mut resolver = Resolver::default();
+        let config = TapHoldConfig { mode: TapHoldMode::PermissiveHold, ..TapHoldConfig::default() };
+
+        resolver.start(&key_input(&time, Key::KEY_A.code(), 1), (), config, &mut ctx);
+        assert!(resolver.feed(key_input(&time, Key::KEY_B.code(), 1), &mut ctx).is_none());
+        // Released without being pressed in the meantime, not a tap of its own.
+        assert!(resolver.feed(key_input(&time, Key::KEY_C.code(), 0), &mut ctx).is_none());
+
+        let resolved = resolver.feed(key_input(&time, Key::KEY_B.code(), 0), &mut ctx).unwrap();
+        assert_eq!(resolved.decision, Decision::Hold);
+        assert_eq!(resolved.buffer.len(), 3);
+    }
+
+    #[test]
+    fn late_press() {
+        let mut timers = Timers::default();
+        let mut ctx = Context::new(0, Instant::now(), SystemTime::now(), &[], &mut timers);
+        let time = TimeVal::try_from(SystemTime::now() - Duration::from_secs(1)).unwrap();
+        let mut resolver = Resolver::default();
+
+        let resolved = resolver.start(&key_input(&time, Key::KEY_A.code(), 1), (), TapHoldConfig::default(), &mut ctx);
+        assert!(resolved.is_some_and(|resolved| resolved.decision == Decision::Hold));
+        assert!(!resolver.is_pending());
+    }
+
+    #[test]
+    fn mock_tap() {
+        let mut mock = Mock::new(TapHold::new(Passthrough).key(Key::KEY_A, Key::KEY_A, Key::KEY_LEFTCTRL));
+        mock.press(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.advance(Duration::from_millis(100));
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0)]);
+
+        mock.advance(Duration::from_secs(1));
+        assert_eq!(mock.keys(), vec![]);
+    }
+
+    #[test]
+    fn mock_hold() {
+        let mut mock = Mock::new(TapHold::new(Passthrough).key(Key::KEY_A, Key::KEY_A, Key::KEY_LEFTCTRL));
+        mock.press(Key::KEY_A);
+        mock.advance(Duration::from_millis(199));
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.advance(Duration::from_millis(1));
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1)]);
+
+        mock.tap(Key::KEY_B);
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_B, 1), (Key::KEY_B, 0), (Key::KEY_LEFTCTRL, 0)]);
+    }
+
+    #[test]
+    fn mock_replays_in_order() {
+        let mut mock = Mock::new(TapHold::new(Passthrough).key(Key::KEY_A, Key::KEY_A, Key::KEY_LEFTCTRL));
+        mock.press(Key::KEY_A);
+        mock.tap(Key::KEY_B);
+        assert_eq!(mock.keys(), vec![]);
+
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_B, 1), (Key::KEY_B, 0)]);
+    }
+
+    #[test]
+    fn mock_permissive_hold() {
+        let config = TapHoldConfig { mode: TapHoldMode::PermissiveHold, ..TapHoldConfig::default() };
+        let mut mock = Mock::new(TapHold::new(Passthrough).key_with(Key::KEY_A, Key::KEY_A, Key::KEY_LEFTCTRL, config));
+        mock.press(Key::KEY_A);
+        mock.press(Key::KEY_B);
+        mock.release(Key::KEY_B);
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1), (Key::KEY_B, 1), (Key::KEY_B, 0), (Key::KEY_LEFTCTRL, 0)]);
+
+        mock.press(Key::KEY_A);
+        mock.press(Key::KEY_B);
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_A, 1), (Key::KEY_A, 0), (Key::KEY_B, 1)]);
+    }
+
+    #[test]
+    fn mock_retro_tap() {
+        let config = TapHoldConfig { retro_tap: true, ..TapHoldConfig::default() };
+        let mut mock = Mock::new(TapHold::new(Passthrough).key_with(Key::KEY_A, Key::KEY_A, Key::KEY_LEFTCTRL, config));
+        mock.press(Key::KEY_A);
+        mock.advance(Duration::from_millis(300));
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1), (Key::KEY_LEFTCTRL, 0), (Key::KEY_A, 1), (Key::KEY_A, 0)]);
+
+        // Not once another key was used with it.
+        mock.press(Key::KEY_A);
+        mock.advance(Duration::from_millis(300));
+        mock.tap(Key::KEY_B);
+        mock.release(Key::KEY_A);
+        assert_eq!(mock.keys(), vec![(Key::KEY_LEFTCTRL, 1), (Key::KEY_B, 1), (Key::KEY_B, 0), (Key::KEY_LEFTCTRL, 0)]);
+    }
+}
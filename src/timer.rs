@@ -0,0 +1,59 @@
+use std::time::Instant;
+
+use evdev::InputEvent as OutInputEvent;
+
+use crate::SourceId;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+pub(crate) enum Action {
+    Emit(Vec<OutInputEvent>),
+    Call,
+}
+
+struct Timer {
+    deadline: Instant,
+    id: TimerId,
+    source: SourceId,
+    action: Action,
+}
+
+#[derive(Default)]
+pub(crate) struct Timers {
+    next_id: u64,
+    pending: Vec<Timer>,
+}
+
+impl Timers {
+    pub(crate) fn schedule(&mut self, deadline: Instant, source: SourceId, action: Action) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        // Kept sorted by deadline; equal deadlines fire in scheduling order.
+        let index = self.pending.partition_point(|timer| timer.deadline <= deadline);
+        self.pending.insert(index, Timer { deadline, id, source, action });
+
+        return id;
+    }
+
+    pub(crate) fn cancel(&mut self, id: TimerId) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|timer| timer.id != id);
+
+        return self.pending.len() != before;
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        return self.pending.first().map(|timer| timer.deadline);
+    }
+
+    pub(crate) fn pop_due(&mut self, now: Instant) -> Option<(TimerId, SourceId, Action)> {
+        if self.pending.first()?.deadline > now {
+            return None;
+        }
+
+        let timer = self.pending.remove(0);
+        return Some((timer.id, timer.source, timer.action));
+    }
+}
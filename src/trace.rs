@@ -0,0 +1,66 @@
+use evdev_rs::InputEvent as InInputEvent;
+
+use evdev::{ EventType, InputEvent as OutInputEvent, InputEventKind, Key };
+
+use crate::SourceId;
+
+// Event traces go to their own log target, so they can be routed apart
+// from lifecycle logging and are never on just because the level is.
+pub const TARGET: &str = "nhk::trace";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Trace {
+    Off,
+    On,
+    // Letters and digits show up as KEY_? so a trace can be shared without
+    // giving away what was typed.
+    Redacted,
+}
+
+impl Default for Trace {
+    fn default() -> Trace {
+        return Trace::Off;
+    }
+}
+
+fn is_alphanumeric(key: Key) -> bool {
+    return matches!(key,
+        Key::KEY_A | Key::KEY_B | Key::KEY_C | Key::KEY_D | Key::KEY_E | Key::KEY_F | Key::KEY_G |
+        Key::KEY_H | Key::KEY_I | Key::KEY_J | Key::KEY_K | Key::KEY_L | Key::KEY_M | Key::KEY_N |
+        Key::KEY_O | Key::KEY_P | Key::KEY_Q | Key::KEY_R | Key::KEY_S | Key::KEY_T | Key::KEY_U |
+        Key::KEY_V | Key::KEY_W | Key::KEY_X | Key::KEY_Y | Key::KEY_Z |
+        Key::KEY_1 | Key::KEY_2 | Key::KEY_3 | Key::KEY_4 | Key::KEY_5 |
+        Key::KEY_6 | Key::KEY_7 | Key::KEY_8 | Key::KEY_9 | Key::KEY_0 |
+        Key::KEY_KP1 | Key::KEY_KP2 | Key::KEY_KP3 | Key::KEY_KP4 | Key::KEY_KP5 |
+        Key::KEY_KP6 | Key::KEY_KP7 | Key::KEY_KP8 | Key::KEY_KP9 | Key::KEY_KP0);
+}
+
+fn describe(trace: Trace, ev_type: EventType, code: u16, value: i32, name: impl FnOnce() -> String) -> String {
+    if trace == Trace::Redacted && ev_type == EventType::KEY && is_alphanumeric(Key::new(code)) {
+        return format!("KEY_? {}", value);
+    }
+
+    return format!("{} {}", name(), value);
+}
+
+pub(crate) fn input(trace: Trace, source: SourceId, ev: &InInputEvent) {
+    if trace == Trace::Off {
+        return;
+    }
+
+    let raw = ev.as_raw();
+    log::trace!(target: TARGET, "in source={} {}", source, describe(trace, EventType(raw.type_), raw.code, ev.value, || ev.event_code.to_string()));
+}
+
+pub(crate) fn output(trace: Trace, ev: &OutInputEvent) {
+    if trace == Trace::Off {
+        return;
+    }
+
+    let name = || match ev.kind() {
+        InputEventKind::Key(key) => format!("{:?}", key),
+        kind => format!("{:?}", kind),
+    };
+
+    log::trace!(target: TARGET, "out {}", describe(trace, ev.event_type(), ev.code(), ev.value(), name));
+}
@@ -0,0 +1,180 @@
+use std::io;
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::time::{ Duration, Instant };
+
+use nix::errno::Errno;
+use nix::poll::{ poll, PollFd, PollFlags };
+use nix::sys::epoll::{ epoll_create1, epoll_ctl, epoll_wait, EpollCreateFlags, EpollEvent, EpollFlags, EpollOp };
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags };
+use nix::unistd::close;
+
+use crate::SourceId;
+use crate::error::nix_error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventLoop {
+    Threaded,
+    Single,
+}
+
+impl Default for EventLoop {
+    fn default() -> EventLoop {
+        return EventLoop::Threaded;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Token {
+    Source(SourceId),
+    Signals,
+    Watcher,
+    Control,
+    Client(usize),
+    Timer,
+}
+
+pub(crate) struct Ready {
+    pub(crate) token: Token,
+    pub(crate) hangup: bool,
+}
+
+struct Epoll {
+    fd: RawFd,
+    timer: TimerFd,
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        close(self.fd).ok();
+    }
+}
+
+enum Backend {
+    Poll,
+    Epoll(Epoll),
+}
+
+// What the read loop sleeps on: either poll over the whole fd set with the
+// next deadline as its timeout, or an epoll set that is only updated when
+// devices come and go, with deadlines on a timerfd.
+pub(crate) struct Waiter {
+    backend: Backend,
+    fds: Vec<(RawFd, Token)>,
+}
+
+fn poll_timeout(deadline: Option<Instant>) -> i32 {
+    return match deadline {
+        Some(deadline) => {
+            // Rounded up, poll would otherwise wake just short of the deadline.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            remaining.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+        },
+        None => -1,
+    };
+}
+
+fn hangup(flags: PollFlags) -> bool {
+    return flags.intersects(PollFlags::POLLHUP | PollFlags::POLLERR | PollFlags::POLLNVAL);
+}
+
+impl Waiter {
+    pub(crate) fn new(event_loop: EventLoop) -> io::Result<Waiter> {
+        let backend = match event_loop {
+            EventLoop::Threaded => Backend::Poll,
+            EventLoop::Single => {
+                let fd = epoll_create1(EpollCreateFlags::EPOLL_CLOEXEC).map_err(nix_error)?;
+                let epoll = Epoll { fd, timer: TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC).map_err(nix_error)? };
+                let mut event = EpollEvent::new(EpollFlags::EPOLLIN, epoll.timer.as_raw_fd() as u64);
+                epoll_ctl(fd, EpollOp::EpollCtlAdd, epoll.timer.as_raw_fd(), &mut event).map_err(nix_error)?;
+                Backend::Epoll(epoll)
+            },
+        };
+
+        return Ok(Waiter { backend, fds: Vec::new() });
+    }
+
+    pub(crate) fn add(&mut self, fd: RawFd, token: Token) -> io::Result<()> {
+        if let Backend::Epoll(epoll) = &self.backend {
+            let mut event = EpollEvent::new(EpollFlags::EPOLLIN, fd as u64);
+            epoll_ctl(epoll.fd, EpollOp::EpollCtlAdd, fd, &mut event).map_err(nix_error)?;
+        }
+
+        self.fds.push((fd, token));
+        return Ok(());
+    }
+
+    // Must be called before the fd is closed.
+    pub(crate) fn remove(&mut self, token: Token) {
+        let fd = match self.fds.iter().position(|&(_, t)| t == token) {
+            Some(i) => self.fds.remove(i).0,
+            None => return,
+        };
+
+        if let Backend::Epoll(epoll) = &self.backend {
+            epoll_ctl(epoll.fd, EpollOp::EpollCtlDel, fd, None).ok();
+        }
+    }
+
+    pub(crate) fn wait(&mut self, deadline: Option<Instant>) -> io::Result<Vec<Ready>> {
+        return match &self.backend {
+            Backend::Poll => self.wait_poll(deadline),
+            Backend::Epoll(epoll) => {
+                let epoll_fd = epoll.fd;
+                let expiration = deadline.map(|deadline| {
+                    // A zero expiration would disarm the timer instead.
+                    let remaining = deadline.saturating_duration_since(Instant::now()).max(Duration::from_nanos(1));
+                    Expiration::OneShot(TimeSpec::from(remaining))
+                });
+                match expiration {
+                    Some(expiration) => epoll.timer.set(expiration, TimerSetTimeFlags::empty()),
+                    None => epoll.timer.unset(),
+                }.map_err(nix_error)?;
+
+                self.wait_epoll(epoll_fd)
+            },
+        };
+    }
+
+    fn wait_poll(&self, deadline: Option<Instant>) -> io::Result<Vec<Ready>> {
+        let mut fds: Vec<PollFd> = self.fds.iter().map(|&(fd, _)| PollFd::new(fd, PollFlags::POLLIN)).collect();
+
+        match poll(&mut fds, poll_timeout(deadline)) {
+            Ok(_) => (),
+            Err(nix::Error::Sys(Errno::EINTR)) => return Ok(Vec::new()),
+            Err(e) => return Err(nix_error(e)),
+        }
+
+        let mut ready = Vec::new();
+        for (fd, &(_, token)) in fds.iter().zip(self.fds.iter()) {
+            if let Some(revents) = fd.revents().filter(|revents| !revents.is_empty()) {
+                ready.push(Ready { token, hangup: hangup(revents) });
+            }
+        }
+
+        return Ok(ready);
+    }
+
+    fn wait_epoll(&self, epoll_fd: RawFd) -> io::Result<Vec<Ready>> {
+        let mut events = vec![EpollEvent::empty(); self.fds.len() + 1];
+
+        let count = match epoll_wait(epoll_fd, &mut events, -1) {
+            Ok(count) => count,
+            Err(nix::Error::Sys(Errno::EINTR)) => return Ok(Vec::new()),
+            Err(e) => return Err(nix_error(e)),
+        };
+
+        let mut ready = Vec::new();
+        for event in &events[..count] {
+            let fd = event.data() as RawFd;
+            let token = match self.fds.iter().find(|&&(f, _)| f == fd) {
+                Some(&(_, token)) => token,
+                None => Token::Timer,
+            };
+            let hangup = event.events().intersects(EpollFlags::EPOLLHUP | EpollFlags::EPOLLERR);
+            ready.push(Ready { token, hangup });
+        }
+
+        return Ok(ready);
+    }
+}